@@ -1,4 +1,4 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
@@ -14,4 +14,112 @@ pub struct Args {
     /// Show output in the serial register. Useful for blarrgs's test rom
     #[arg(short, long, required = false, default_value_t = false)]
     pub serial: bool,
+
+    /// Load a save state on startup. Accepts native and BESS save states
+    #[arg(long, required = false)]
+    pub load_state: Option<String>,
+
+    /// Write a save state when the window is closed. Carries a BESS section for other emulators
+    #[arg(long, required = false)]
+    pub save_state: Option<String>,
+
+    /// Capture a rewind snapshot every N frames
+    #[arg(long, required = false, default_value_t = 5)]
+    pub rewind_interval: u32,
+
+    /// Memory limit of the rewind buffer in MiB
+    #[arg(long, required = false, default_value_t = 32)]
+    pub rewind_memory: usize,
+
+    /// Record the joypad input into a movie file, written when the window is closed
+    #[arg(long, required = false, conflicts_with = "play_movie")]
+    pub record_movie: Option<String>,
+
+    /// Play back a movie file recorded with --record-movie
+    #[arg(long, required = false)]
+    pub play_movie: Option<String>,
+
+    /// Run without opening a window, for machines without a display. Needs --frames
+    #[arg(long, required = false, default_value_t = false, requires = "frames")]
+    pub headless: bool,
+
+    /// Number of frames to run in headless mode
+    #[arg(long, required = false)]
+    pub frames: Option<u32>,
+
+    /// Write the screen as a PNG when the emulator exits
+    #[arg(long, required = false)]
+    pub screenshot: Option<String>,
+
+    /// With --screenshot, also write a PNG every K frames. The frame number is appended to the file name
+    #[arg(long, required = false, requires = "screenshot")]
+    pub screenshot_every: Option<u32>,
+
+    /// Dump everything sent over the serial port into a file
+    #[arg(long, required = false)]
+    pub serial_out: Option<String>,
+
+    /// Speed multiplier of the fast-forward toggle ( F )
+    #[arg(long, required = false, default_value_t = 3.0)]
+    pub fast_forward_speed: f64,
+
+    /// Speed multiplier of the slow motion toggle ( G )
+    #[arg(long, required = false, default_value_t = 0.5)]
+    pub slow_motion_speed: f64,
+
+    /// Run a Rhai script alongside the game, see the README for the functions it can call
+    #[cfg(feature = "scripting")]
+    #[arg(long, required = false)]
+    pub script: Option<String>,
+
+    /// Wait for a GDB connection on localhost:PORT and let it control the emulator
+    #[arg(long, required = false, conflicts_with = "headless")]
+    pub gdb: Option<u16>,
+
+    /// RGBDS symbol file for traces and the debugger. A .sym file next to the ROM is loaded when missing
+    #[arg(long, required = false)]
+    pub symbols: Option<String>,
+
+    /// Enable a Game Genie ( ABC-DEF-GHI ) or GameShark ( 01FF1ACF ) code, can be repeated.
+    /// Several codes of one cheat are joined with +
+    #[arg(long, required = false)]
+    pub cheat: Vec<String>,
+
+    /// Load a RetroArch style .cht cheat list
+    #[arg(long, required = false)]
+    pub cheats: Option<String>,
+
+    /// Profile the game, write the call stacks for flamegraph tools to FILE on exit
+    /// and print the hottest routines
+    #[arg(long, required = false)]
+    pub profile: Option<String>,
+
+    /// Log which ROM bytes are code and which are data to a CDL file, written on exit.
+    /// An existing file is added to, so sessions build on each other
+    #[arg(long, required = false)]
+    pub cdl: Option<String>,
+
+    /// Start stopped in the command line debugger. F12 in the window breaks back into it
+    #[arg(long, required = false, default_value_t = false, conflicts_with_all = ["headless", "gdb"])]
+    pub debugger: bool,
+
+    /// Contents of WRAM, HRAM, VRAM and OAM at power on
+    #[arg(long, required = false, value_enum, default_value_t = RamInitArg::Zero)]
+    pub ram_init: RamInitArg,
+
+    /// Seed of the random RAM patterns. A random seed is picked and printed when missing
+    #[arg(long, required = false)]
+    pub seed: Option<u64>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum RamInitArg {
+    /// Everything zeroed
+    Zero,
+    /// Every byte 0xFF
+    Ff,
+    /// Uniformly random bytes
+    Random,
+    /// Random, close to what a DMG powers on with
+    Dmg,
 }
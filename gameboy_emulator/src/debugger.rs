@@ -0,0 +1,836 @@
+use std::{
+    io::{BufRead, Write},
+    ops::RangeInclusive,
+    str::FromStr,
+    sync::mpsc::{self, Receiver, TryRecvError},
+};
+
+use gameboy_emulator_lib::{
+    bus::Memory,
+    cheats::Cheat,
+    cpu::registers::{Reg16, Reg8},
+    debug::{
+        disasm::{disassemble_around, disassemble_with_labels, Instruction},
+        search::{Compare, Operand, RamSearch, ValueKind, WatchList},
+        symbols::Symbols,
+        BankedAddress, Breakpoint, Debugger, Resume, StopReason, WatchKind,
+    },
+    emu::EmuContext,
+    hooks::AccessKind,
+    io::ppu::registers::{Lcdc, Stat},
+};
+
+const PROMPT: &str = "(gbdb) ";
+
+const HELP: &str = "\
+c, continue             run until a breakpoint, or F12 in the window
+s, step [n]             run n instructions
+n, next                 run one instruction, CALL and RST run until they return
+finish                  run until the current subroutine returns
+b, break ADDR           break at an address, ADDR is 4123 or BB:4123 for a ROM bank
+b, break op XX          break before any instruction starting with byte XX
+watch ADDR[-END]        stop after a write, rwatch on reads, awatch on both
+d, delete ID            remove a breakpoint or watchpoint
+i, info                 list breakpoints and watchpoints
+r, regs                 show registers
+set REG VALUE           change a register: a f b c d e h l af bc de hl sp pc
+x ADDR [LEN]            hex dump LEN bytes
+dis [ADDR] [N]          disassemble N instructions, around PC without ADDR
+io                      show the LCD, timer and interrupt registers
+search new [KIND]       start a RAM search, KIND is u8 (default), u16, bcd8 or bcd16
+search changed          keep values that changed since the last filter, also
+                        unchanged, inc and dec
+search eq VALUE         keep values equal to VALUE, also ne, gt and lt
+search list [N]         show the first N results
+search cheat IDX VALUE  add a cheat holding result IDX at VALUE
+search watch IDX [NAME] add result IDX to the watch list
+cheat [add CODE]        list cheats or add a Game Genie or GameShark code
+cheat toggle|delete ID  switch a cheat on or off, or remove it
+watchlist               show the watch list, it is also shown on every stop
+watchlist add ADDR [KIND] [NAME], watchlist del IDX
+profile start|stop      start profiling, or stop and drop the profile
+profile report [N]      show the N hottest routines and instructions
+profile save FILE       write the call stacks for flamegraph tools
+q, quit                 close the emulator
+Addresses are hex, counts are decimal. Search values are decimal, or hex with $.
+An empty line repeats the last command.
+Labels from a symbol file work wherever an address does.";
+
+/// # Debugger REPL
+/// Reads commands from stdin while the window keeps drawing.
+///
+/// Lines are read on a separate thread and handed over through a channel,
+/// so a stopped game still polls the window and F12 can break into a
+/// running one.
+pub struct Repl {
+    debugger: Debugger,
+    lines: Receiver<String>,
+    stopped: bool,
+    steps: u32,
+    last: String,
+    quit: bool,
+    search: Option<RamSearch>,
+    watches: WatchList,
+}
+
+impl Repl {
+    /// Starts stopped at the first instruction
+    pub fn new(ctx: &EmuContext) -> Self {
+        let (sender, lines) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                let Ok(line) = line else { break };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        println!("Type help for a list of commands");
+        let repl = Repl {
+            debugger: Debugger::default(),
+            lines,
+            stopped: true,
+            steps: 0,
+            last: String::new(),
+            quit: false,
+            search: None,
+            watches: WatchList::default(),
+        };
+        repl.stop_message("Stopped", ctx);
+        repl
+    }
+
+    pub fn quit(&self) -> bool {
+        self.quit
+    }
+
+    /// Stops a running game, bound to F12
+    pub fn break_in(&mut self, ctx: &EmuContext) {
+        if !self.stopped {
+            self.debugger.resume(ctx, Resume::Continue);
+            self.stopped = true;
+            println!();
+            self.stop_message("Interrupted", ctx);
+        }
+    }
+
+    /// Runs the commands typed so far, or a frame of the game while it runs
+    pub fn run_frame(&mut self, ctx: &mut EmuContext) {
+        if self.stopped {
+            self.run_commands(ctx);
+        }
+
+        while !self.stopped {
+            match self.debugger.run_frame(ctx) {
+                None => return,
+                Some(StopReason::Done) if self.steps > 1 => {
+                    self.steps -= 1;
+                    self.debugger.resume(ctx, Resume::Step);
+                }
+                Some(reason) => {
+                    self.stopped = true;
+                    self.report(reason, ctx);
+                }
+            }
+        }
+    }
+
+    fn run_commands(&mut self, ctx: &mut EmuContext) {
+        while self.stopped && !self.quit {
+            let line = match self.lines.try_recv() {
+                Ok(line) => line,
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {
+                    // nobody left to type commands
+                    self.stopped = false;
+                    return;
+                }
+            };
+
+            let line = if line.trim().is_empty() {
+                self.last.clone()
+            } else {
+                line.trim().to_string()
+            };
+            self.last = line.clone();
+
+            if let Err(e) = self.command(&line, ctx) {
+                println!("{}", e);
+            }
+
+            if self.stopped && !self.quit {
+                prompt();
+            }
+        }
+    }
+
+    fn command(&mut self, line: &str, ctx: &mut EmuContext) -> Result<(), String> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(());
+        };
+        let args = words.collect::<Vec<_>>();
+
+        match command {
+            "help" | "h" => println!("{}", HELP),
+            "c" | "continue" => self.resume(ctx, Resume::Continue, 1),
+            "s" | "step" => {
+                let count = match args.first() {
+                    Some(count) => count
+                        .parse()
+                        .map_err(|_| format!("invalid count {:?}", count))?,
+                    None => 1,
+                };
+                if count > 0 {
+                    self.resume(ctx, Resume::Step, count);
+                }
+            }
+            "n" | "next" => self.resume(ctx, Resume::Next, 1),
+            "finish" => self.resume(ctx, Resume::Finish, 1),
+            "b" | "break" => {
+                let breakpoint = match args.as_slice() {
+                    ["op", opcode] => Breakpoint::Opcode(parse_hex(opcode)? as u8),
+                    [address] => Breakpoint::Address(parse_address(ctx, address)?),
+                    _ => return Err("usage: break ADDR | break op XX".to_string()),
+                };
+                let id = self.debugger.add_breakpoint(breakpoint);
+                println!("Breakpoint {} {}", id, breakpoint);
+            }
+            "watch" | "rwatch" | "awatch" => {
+                let kind = match command {
+                    "watch" => WatchKind::Write,
+                    "rwatch" => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                let [range] = args.as_slice() else {
+                    return Err(format!("usage: {} ADDR[-END]", command));
+                };
+                let range = parse_range(ctx, range)?;
+                let id = self.debugger.add_watchpoint(ctx, kind, range.clone());
+                let description = describe_watch(&ctx.symbols, kind, &range);
+                println!("Watchpoint {} {}", id, description);
+            }
+            "d" | "delete" => {
+                let [id] = args.as_slice() else {
+                    return Err("usage: delete ID".to_string());
+                };
+                let id = id.parse().map_err(|_| format!("invalid id {:?}", id))?;
+                if !self.debugger.remove(ctx, id) {
+                    return Err(format!("no breakpoint or watchpoint {}", id));
+                }
+            }
+            "i" | "info" => self.info(ctx),
+            "r" | "regs" => println!("{}", ctx.cpu.registers),
+            "set" => {
+                let [register, value] = args.as_slice() else {
+                    return Err("usage: set REG VALUE".to_string());
+                };
+                set_register(ctx, register, parse_hex(value)?)?;
+                println!("{}", ctx.cpu.registers);
+            }
+            "x" => {
+                let (address, len) = match args.as_slice() {
+                    [address] => (parse_address(ctx, address)?, 64),
+                    [address, len] => (
+                        parse_address(ctx, address)?,
+                        len.parse()
+                            .map_err(|_| format!("invalid length {:?}", len))?,
+                    ),
+                    _ => return Err("usage: x ADDR [LEN]".to_string()),
+                };
+                dump(ctx, address, len);
+            }
+            "dis" => {
+                let pc = ctx.cpu.registers.pc;
+                let bus = ctx.bus.borrow();
+                let instructions = match args.as_slice() {
+                    [] => disassemble_around(
+                        |address| bus.read(address),
+                        |address| ctx.symbols.label(BankedAddress::mapped(&bus, address)),
+                        pc,
+                        4,
+                        8,
+                    ),
+                    [address] | [address, _] => {
+                        let count = match args.get(1) {
+                            Some(count) => count
+                                .parse()
+                                .map_err(|_| format!("invalid count {:?}", count))?,
+                            None => 12,
+                        };
+                        let start = parse_address(ctx, address)?;
+                        let read = |address| BankedAddress { address, ..start }.read(&bus);
+                        let label = |address| ctx.symbols.label(BankedAddress { address, ..start });
+                        let mut instructions: Vec<Instruction> = vec![];
+                        let mut cursor = start.address;
+                        for _ in 0..count {
+                            let instruction = disassemble_with_labels(read, cursor, label);
+                            cursor = cursor.wrapping_add(instruction.len());
+                            instructions.push(instruction);
+                        }
+                        instructions
+                    }
+                    _ => return Err("usage: dis [ADDR] [N]".to_string()),
+                };
+                let bank = match args.first() {
+                    Some(address) => parse_address(ctx, address)?.bank,
+                    None => None,
+                };
+                drop(bus);
+
+                for instruction in instructions {
+                    let marker = if instruction.address == pc {
+                        "=>"
+                    } else {
+                        "  "
+                    };
+                    let location = match bank {
+                        Some(bank) if instruction.address < 0x8000 => {
+                            BankedAddress::with_bank(bank, instruction.address)
+                        }
+                        _ => BankedAddress::mapped(&ctx.bus.borrow(), instruction.address),
+                    };
+                    println!(
+                        "{} {}",
+                        marker,
+                        format_instruction(ctx, location, &instruction)
+                    );
+                }
+            }
+            "io" => print_io(ctx),
+            "search" => self.search(ctx, &args)?,
+            "cheat" => cheat(ctx, &args)?,
+            "watchlist" => match args.as_slice() {
+                [] => self.print_watches(ctx),
+                ["add", address, rest @ ..] => {
+                    let address = parse_address(ctx, address)?;
+                    let kind = match rest.first() {
+                        Some(kind) => kind.parse()?,
+                        None => ValueKind::U8,
+                    };
+                    let name = match rest.get(1..) {
+                        Some(name) if !name.is_empty() => name.join(" "),
+                        _ => ctx.symbols.format(address),
+                    };
+                    self.watches.add(address.address, kind, &name);
+                }
+                ["del", idx] => {
+                    let idx = idx
+                        .parse()
+                        .map_err(|_| format!("invalid index {:?}", idx))?;
+                    self.watches
+                        .remove(idx)
+                        .ok_or_else(|| format!("no watch list entry {}", idx))?;
+                }
+                _ => return Err("usage: watchlist [add ADDR [KIND] [NAME] | del IDX]".to_string()),
+            },
+            "profile" => profile(ctx, &args)?,
+            "q" | "quit" => self.quit = true,
+            _ => return Err(format!("unknown command {:?}, try help", command)),
+        }
+
+        Ok(())
+    }
+
+    fn search(&mut self, ctx: &mut EmuContext, args: &[&str]) -> Result<(), String> {
+        if let ["new", rest @ ..] = args {
+            let kind = match rest.first() {
+                Some(kind) => kind.parse()?,
+                None => ValueKind::U8,
+            };
+            let search = RamSearch::new(&ctx.bus.borrow(), kind);
+            println!("{} {} values", search.candidates().len(), kind);
+            self.search = Some(search);
+            return Ok(());
+        }
+
+        let search = self
+            .search
+            .as_mut()
+            .ok_or("no search running, start one with search new")?;
+
+        let filter = match args {
+            ["changed"] => Some((Compare::NotEqual, Operand::Previous)),
+            ["unchanged"] => Some((Compare::Equal, Operand::Previous)),
+            ["inc"] => Some((Compare::Greater, Operand::Previous)),
+            ["dec"] => Some((Compare::Less, Operand::Previous)),
+            [compare @ ("eq" | "ne" | "gt" | "lt"), value] => {
+                let compare = match *compare {
+                    "eq" => Compare::Equal,
+                    "ne" => Compare::NotEqual,
+                    "gt" => Compare::Greater,
+                    _ => Compare::Less,
+                };
+                Some((compare, Operand::Value(parse_value(value)?)))
+            }
+            _ => None,
+        };
+
+        if let Some((compare, operand)) = filter {
+            let left = search.filter(&ctx.bus.borrow(), compare, operand);
+            println!("{} left", left);
+            return Ok(());
+        }
+
+        let candidate = |idx: &str| {
+            let idx = idx
+                .parse::<usize>()
+                .map_err(|_| format!("invalid index {:?}", idx))?;
+            search
+                .candidates()
+                .get(idx)
+                .copied()
+                .ok_or_else(|| format!("no result {}", idx))
+        };
+
+        match args {
+            ["list"] | ["list", _] => {
+                let count = match args.get(1) {
+                    Some(count) => count
+                        .parse()
+                        .map_err(|_| format!("invalid count {:?}", count))?,
+                    None => 20,
+                };
+                for (idx, candidate) in search.candidates().iter().take(count).enumerate() {
+                    let address = ctx.symbols.format(BankedAddress::new(candidate.address));
+                    println!(
+                        "{:>4}  {:<16}  {} (was {})",
+                        idx, address, candidate.value, candidate.previous
+                    );
+                }
+                if search.candidates().len() > count {
+                    println!("{} more", search.candidates().len() - count);
+                }
+            }
+            ["cheat", idx, value] => {
+                let cheat = search.cheat(candidate(idx)?.address, parse_value(value)?);
+                let code = cheat.code.clone();
+                let id = ctx.add_cheat(cheat);
+                println!("Cheat {}: {}", id, code);
+            }
+            ["watch", idx, name @ ..] => {
+                let address = candidate(idx)?.address;
+                let name = match name {
+                    [] => ctx.symbols.format(BankedAddress::new(address)),
+                    name => name.join(" "),
+                };
+                self.watches.add(address, search.kind(), &name);
+            }
+            _ => return Err("usage: search new|changed|unchanged|inc|dec|eq|ne|gt|lt|list|cheat|watch, see help".to_string()),
+        }
+
+        Ok(())
+    }
+
+    fn print_watches(&self, ctx: &EmuContext) {
+        let lines = self.watches.format(&ctx.bus.borrow());
+        for (idx, line) in lines.iter().enumerate() {
+            println!("{:>4}  {}", idx, line);
+        }
+    }
+
+    fn resume(&mut self, ctx: &EmuContext, resume: Resume, steps: u32) {
+        self.debugger.resume(ctx, resume);
+        self.steps = steps;
+        self.stopped = false;
+    }
+
+    fn info(&self, ctx: &EmuContext) {
+        if self.debugger.breakpoints().is_empty() && self.debugger.watchpoints().is_empty() {
+            println!("No breakpoints or watchpoints");
+        }
+
+        for (id, breakpoint) in self.debugger.breakpoints() {
+            match breakpoint {
+                Breakpoint::Address(address) => match ctx.symbols.label(*address) {
+                    Some(label) => println!("{:>3}  breakpoint {} <{}>", id, breakpoint, label),
+                    None => println!("{:>3}  breakpoint {}", id, breakpoint),
+                },
+                Breakpoint::Opcode(_) => println!("{:>3}  breakpoint {}", id, breakpoint),
+            }
+        }
+
+        for watchpoint in self.debugger.watchpoints() {
+            println!(
+                "{:>3}  watchpoint {}",
+                watchpoint.id,
+                describe_watch(&ctx.symbols, watchpoint.kind, &watchpoint.range)
+            );
+        }
+    }
+
+    fn report(&self, reason: StopReason, ctx: &EmuContext) {
+        match reason {
+            StopReason::Breakpoint(id) => self.stop_message(&format!("Breakpoint {}", id), ctx),
+            StopReason::Watchpoint(id, access) => {
+                let kind = match access.kind {
+                    AccessKind::Read => "read",
+                    AccessKind::Write => "write",
+                };
+                let address = BankedAddress::mapped(&ctx.bus.borrow(), access.address);
+                let message = format!(
+                    "Watchpoint {}: {} {} = {:02X}",
+                    id,
+                    kind,
+                    ctx.symbols.format(address),
+                    access.value
+                );
+                self.stop_message(&message, ctx);
+            }
+            StopReason::Done => self.stop_message("", ctx),
+            StopReason::Error(e) => self.stop_message(&format!("Emulation error: {}", e), ctx),
+        }
+    }
+
+    fn stop_message(&self, message: &str, ctx: &EmuContext) {
+        if !message.is_empty() {
+            println!("{}", message);
+        }
+        self.print_watches(ctx);
+
+        let pc = BankedAddress::mapped(&ctx.bus.borrow(), ctx.cpu.registers.pc);
+        let instruction = {
+            let bus = ctx.bus.borrow();
+            disassemble_with_labels(
+                |address| bus.read(address),
+                pc.address,
+                |address| ctx.symbols.label(BankedAddress::mapped(&bus, address)),
+            )
+        };
+        println!("=> {}", format_instruction(ctx, pc, &instruction));
+        prompt();
+    }
+}
+
+fn prompt() {
+    print!("{}", PROMPT);
+    std::io::stdout().flush().ok();
+}
+
+/// `00:0150  3E 01     LD A, $01  <Main+$04>`, the bank is left out outside of ROM
+fn format_instruction(
+    ctx: &EmuContext,
+    location: BankedAddress,
+    instruction: &Instruction,
+) -> String {
+    let bytes = instruction
+        .bytes
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let line = format!(
+        "{:>7}  {:<8}  {}",
+        location.to_string(),
+        bytes,
+        instruction.text
+    );
+
+    match ctx.symbols.label(location) {
+        Some(label) => format!("{:<40}  <{}>", line, label),
+        None => line,
+    }
+}
+
+/// A label, or an address in hex
+fn parse_address(ctx: &EmuContext, address: &str) -> Result<BankedAddress, String> {
+    match ctx.symbols.resolve(address) {
+        Some(address) => Ok(address),
+        None => BankedAddress::from_str(address),
+    }
+}
+
+fn describe_watch(symbols: &Symbols, kind: WatchKind, range: &RangeInclusive<u16>) -> String {
+    let kind = match kind {
+        WatchKind::Read => "on reads of",
+        WatchKind::Write => "on writes to",
+        WatchKind::Access => "on accesses to",
+    };
+
+    let format = |address: u16| symbols.format(BankedAddress::new(address));
+
+    if range.start() == range.end() {
+        format!("{} {}", kind, format(*range.start()))
+    } else {
+        format!(
+            "{} {}-{}",
+            kind,
+            format(*range.start()),
+            format(*range.end())
+        )
+    }
+}
+
+fn parse_hex(value: &str) -> Result<u16, String> {
+    let hex = value
+        .trim_start_matches("0x")
+        .trim_start_matches("0X")
+        .trim_start_matches('$');
+    u16::from_str_radix(hex, 16).map_err(|_| format!("invalid value {:?}", value))
+}
+
+/// Decimal, or hex with a `$` or `0x` prefix
+fn parse_value(value: &str) -> Result<u32, String> {
+    let parsed = match value.strip_prefix('$').or_else(|| value.strip_prefix("0x")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|_| format!("invalid value {:?}", value))
+}
+
+fn profile(ctx: &mut EmuContext, args: &[&str]) -> Result<(), String> {
+    if let ["start"] = args {
+        ctx.start_profiler();
+        return Ok(());
+    }
+
+    if let ["stop"] = args {
+        ctx.take_profiler();
+        return Ok(());
+    }
+
+    let profiler = ctx
+        .profiler()
+        .ok_or("not profiling, start with profile start")?;
+
+    match args {
+        ["report"] => print!("{}", profiler.report(&ctx.symbols, 20)),
+        ["report", count] => {
+            let count = count
+                .parse()
+                .map_err(|_| format!("invalid count {:?}", count))?;
+            print!("{}", profiler.report(&ctx.symbols, count));
+        }
+        ["save", path] => std::fs::write(path, profiler.collapsed(&ctx.symbols))
+            .map_err(|e| format!("Error in writing profile: {}", e))?,
+        _ => return Err("usage: profile start|stop|report [N]|save FILE".to_string()),
+    }
+
+    Ok(())
+}
+
+fn cheat(ctx: &mut EmuContext, args: &[&str]) -> Result<(), String> {
+    match args {
+        [] => {
+            for (id, cheat) in ctx.cheats().iter() {
+                let state = if cheat.enabled { "on" } else { "off" };
+                println!("{:>3}  [{}] {}: {}", id, state, cheat.name, cheat.code);
+            }
+        }
+        ["add", code, name @ ..] => {
+            let name = if name.is_empty() {
+                code.to_string()
+            } else {
+                name.join(" ")
+            };
+            let id = ctx.add_cheat(Cheat::new(&name, code)?);
+            println!("Cheat {}: {}", id, code);
+        }
+        ["toggle", id] => {
+            let id = id.parse().map_err(|_| format!("invalid id {:?}", id))?;
+            let enabled = ctx
+                .toggle_cheat(id)
+                .ok_or_else(|| format!("no cheat {}", id))?;
+            println!("Cheat {} {}", id, if enabled { "on" } else { "off" });
+        }
+        ["delete", id] => {
+            let id = id.parse().map_err(|_| format!("invalid id {:?}", id))?;
+            if !ctx.remove_cheat(id) {
+                return Err(format!("no cheat {}", id));
+            }
+        }
+        _ => return Err("usage: cheat [add CODE [NAME] | toggle ID | delete ID]".to_string()),
+    }
+
+    Ok(())
+}
+
+fn parse_range(ctx: &EmuContext, range: &str) -> Result<RangeInclusive<u16>, String> {
+    let address = |address| parse_address(ctx, address).map(|address| address.address);
+
+    match range.split_once('-') {
+        Some((start, end)) => {
+            let (start, end) = (address(start)?, address(end)?);
+            if start > end {
+                return Err(format!("invalid range {:?}", range));
+            }
+            Ok(start..=end)
+        }
+        None => {
+            let address = address(range)?;
+            Ok(address..=address)
+        }
+    }
+}
+
+fn set_register(ctx: &mut EmuContext, register: &str, value: u16) -> Result<(), String> {
+    let registers = &mut ctx.cpu.registers;
+    let byte = || u8::try_from(value).map_err(|_| format!("{:X} does not fit in a byte", value));
+
+    match register.to_lowercase().as_str() {
+        "a" => registers.set_reg(byte()?, Reg8::A),
+        "f" => registers.set_reg(byte()?, Reg8::F),
+        "b" => registers.set_reg(byte()?, Reg8::B),
+        "c" => registers.set_reg(byte()?, Reg8::C),
+        "d" => registers.set_reg(byte()?, Reg8::D),
+        "e" => registers.set_reg(byte()?, Reg8::E),
+        "h" => registers.set_reg(byte()?, Reg8::H),
+        "l" => registers.set_reg(byte()?, Reg8::L),
+        "af" => registers.set_reg_pair(value, Reg16::AF),
+        "bc" => registers.set_reg_pair(value, Reg16::BC),
+        "de" => registers.set_reg_pair(value, Reg16::DE),
+        "hl" => registers.set_reg_pair(value, Reg16::HL),
+        "sp" => registers.sp = value,
+        "pc" => registers.pc = value,
+        _ => return Err(format!("unknown register {:?}", register)),
+    }
+
+    Ok(())
+}
+
+/// 16 bytes per line with their ASCII next to them
+fn dump(ctx: &EmuContext, start: BankedAddress, len: usize) {
+    let bus = ctx.bus.borrow();
+
+    for line in (0..len).step_by(16) {
+        let address = start.address.wrapping_add(line as u16);
+        let bytes = (line..len.min(line + 16))
+            .map(|offset| {
+                BankedAddress {
+                    address: start.address.wrapping_add(offset as u16),
+                    ..start
+                }
+                .read(&bus)
+            })
+            .collect::<Vec<_>>();
+
+        let hex = bytes
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let ascii = bytes
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect::<String>();
+
+        println!(
+            "{:>7}  {:<47}  {}",
+            BankedAddress { address, ..start }.to_string(),
+            hex,
+            ascii
+        );
+    }
+}
+
+fn print_io(ctx: &EmuContext) {
+    let bus = ctx.bus.borrow();
+    let on_off = |flag: bool| if flag { "on" } else { "off" };
+    let map = |flag: bool| if flag { "9C00" } else { "9800" };
+
+    let lcdc_byte = bus.read(0xFF40);
+    let lcdc = Lcdc::new(lcdc_byte);
+    println!(
+        "LCDC FF40 {:02X}  LCD {}, window {} map {}, BG {} map {}, tiles {}, OBJ {} {}",
+        lcdc_byte,
+        on_off(lcdc.enable_lcd),
+        on_off(lcdc.window_enable),
+        map(lcdc.window_tile_map_area),
+        on_off(lcdc.bg_priority),
+        map(lcdc.bg_tile_map_area),
+        if lcdc.bg_tile_data_area {
+            "8000"
+        } else {
+            "8800"
+        },
+        on_off(lcdc.obj_enable),
+        if lcdc.obj_size { "8x16" } else { "8x8" },
+    );
+
+    let stat_byte = bus.read(0xFF41);
+    let stat = Stat::new(stat_byte);
+    let sources = [
+        (stat.lyc_ly_eq_interrupt, "LYC"),
+        (stat.oam_interrupt, "OAM"),
+        (stat.vblank_interrupt, "VBlank"),
+        (stat.hblank_interrupt, "HBlank"),
+    ];
+    println!(
+        "STAT FF41 {:02X}  mode {:?}, LY=LYC {}, interrupts on {}",
+        stat_byte,
+        stat.get_mode(),
+        stat.lyc_ly_eq_flag,
+        names(&sources),
+    );
+
+    println!(
+        "LY {:02X}  LYC {:02X}  SCY {:02X}  SCX {:02X}  WY {:02X}  WX {:02X}  BGP {:02X}  OBP0 {:02X}  OBP1 {:02X}",
+        bus.read(0xFF44),
+        bus.read(0xFF45),
+        bus.read(0xFF42),
+        bus.read(0xFF43),
+        bus.read(0xFF4A),
+        bus.read(0xFF4B),
+        bus.read(0xFF47),
+        bus.read(0xFF48),
+        bus.read(0xFF49),
+    );
+
+    let tac = bus.read(0xFF07);
+    let clock = match tac & 0b11 {
+        0b00 => 4096,
+        0b01 => 262144,
+        0b10 => 65536,
+        _ => 16384,
+    };
+    println!(
+        "TAC  FF07 {:02X}  timer {}, {} Hz",
+        tac,
+        on_off(tac & 0b100 != 0),
+        clock
+    );
+    println!(
+        "DIV {:02X}  TIMA {:02X}  TMA {:02X}",
+        bus.read(0xFF04),
+        bus.read(0xFF05),
+        bus.read(0xFF06),
+    );
+
+    for (name, address) in [("IF", 0xFF0F), ("IE", 0xFFFF)] {
+        let byte = bus.read(address);
+        let interrupts = ["VBlank", "STAT", "Timer", "Serial", "Joypad"]
+            .iter()
+            .enumerate()
+            .map(|(bit, name)| (byte & (1 << bit) != 0, *name))
+            .collect::<Vec<_>>();
+        println!(
+            "{:<4} {:04X} {:02X}  {}",
+            name,
+            address,
+            byte,
+            names(&interrupts)
+        );
+    }
+}
+
+fn names(flags: &[(bool, &str)]) -> String {
+    let set = flags
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>();
+
+    if set.is_empty() {
+        "none".to_string()
+    } else {
+        set.join(" ")
+    }
+}
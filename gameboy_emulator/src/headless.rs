@@ -0,0 +1,128 @@
+use gameboy_emulator_lib::emu::{EmuContext, Lockup};
+#[cfg(feature = "scripting")]
+use gameboy_emulator_lib::script::Script;
+use serde_json::{json, Value};
+
+#[cfg(feature = "scripting")]
+use crate::scripting;
+use crate::{args::Args, screenshot, MovieMode};
+
+/// Runs `--frames` frames without a window and returns a JSON summary
+///
+/// Stops early once the cpu is locked up, nothing would change after that,
+/// or when emulation fails, the error ends up in the summary.
+pub fn run(
+    args: &Args,
+    ctx: &mut EmuContext,
+    movie: &mut MovieMode,
+    #[cfg(feature = "scripting")] script: &mut Option<Script>,
+) -> Value {
+    let frames = args.frames.unwrap_or_default();
+    let mut frames_run = 0;
+    let mut cycles = 0;
+    let mut error = None;
+
+    while frames_run < frames {
+        #[cfg(feature = "scripting")]
+        movie.begin_frame(ctx, scripting::buttons(script));
+        #[cfg(not(feature = "scripting"))]
+        movie.begin_frame(ctx, 0);
+        match ctx.run_frame() {
+            Ok(result) => cycles += result.cycles,
+            Err(e) => {
+                error = Some(e.to_string());
+                break;
+            }
+        }
+        movie.end_frame(ctx);
+
+        frames_run += 1;
+        screenshot::on_frame(args, ctx, frames_run);
+        #[cfg(feature = "scripting")]
+        scripting::on_frame(script, ctx);
+
+        if ctx.lockup().is_some() {
+            break;
+        }
+    }
+
+    let lockup = match ctx.lockup() {
+        Some(Lockup::HaltForever) => Some("halt_forever"),
+        Some(Lockup::JumpToSelf) => Some("jump_to_self"),
+        None => None,
+    };
+
+    json!({
+        "frames_run": frames_run,
+        "cycles": cycles,
+        "final_pc": format!("0x{:04X}", ctx.cpu.registers.pc),
+        "locked_up": lockup.is_some(),
+        "lockup": lockup,
+        "serial_bytes": ctx.bus.borrow().serial.output().len(),
+        "error": error,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use gameboy_emulator_lib::{
+        cartridge::Cartridge,
+        emu::EmuContext,
+        utils::{Opts, CYCLES_1_FRAME},
+    };
+    use serde_json::Value;
+
+    use crate::{args::Args, MovieMode};
+
+    /// Runs `program` from 0x100 for `frames` frames
+    fn run_program(program: &[u8], frames: u32) -> Value {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        let mut ctx = EmuContext::new(Cartridge::new(rom).unwrap(), Opts::new(false, false));
+
+        let frames = frames.to_string();
+        let args = Args::parse_from(["gb", "-p", "test.gb", "--headless", "--frames", &frames]);
+
+        #[cfg(feature = "scripting")]
+        return super::run(&args, &mut ctx, &mut MovieMode::Off, &mut None);
+        #[cfg(not(feature = "scripting"))]
+        super::run(&args, &mut ctx, &mut MovieMode::Off)
+    }
+
+    #[test]
+    fn test_runs_all_frames() {
+        // nop ; jr -3
+        let summary = run_program(&[0x00, 0x18, 0xFD], 5);
+
+        assert_eq!(summary["frames_run"], 5);
+        let cycles = summary["cycles"].as_u64().unwrap();
+        assert!(cycles > 4 * CYCLES_1_FRAME && cycles <= 5 * CYCLES_1_FRAME);
+        assert_eq!(summary["locked_up"], false);
+        assert_eq!(summary["lockup"], Value::Null);
+        assert_eq!(summary["error"], Value::Null);
+    }
+
+    #[test]
+    fn test_stops_on_lockup() {
+        // jr -2
+        let summary = run_program(&[0x18, 0xFE], 5);
+        assert_eq!(summary["frames_run"], 1);
+        assert_eq!(summary["lockup"], "jump_to_self");
+        assert_eq!(summary["final_pc"], "0x0100");
+
+        // halt, nothing can wake the cpu up
+        let summary = run_program(&[0x76], 5);
+        assert_eq!(summary["frames_run"], 1);
+        assert_eq!(summary["lockup"], "halt_forever");
+    }
+
+    #[test]
+    fn test_stops_on_error() {
+        // 0xD3 doesn't exist
+        let summary = run_program(&[0x00, 0xD3], 5);
+        assert_eq!(summary["frames_run"], 0);
+        assert_eq!(summary["locked_up"], false);
+        assert!(summary["error"].as_str().unwrap().contains("D3"));
+    }
+}
@@ -1,13 +1,33 @@
+use std::path::{Path, PathBuf};
+
+use args::{Args, RamInitArg};
 use clap::Parser;
 use gameboy_emulator_lib::{
-    cartridge::Cartridge,
+    bus::ram_init::RamInit,
+    cartridge::{
+        cdl::{CodeDataLog, ReadKind},
+        Cartridge,
+    },
+    cheats::{self, Cheat},
+    debug::symbols::Symbols,
     emu::EmuContext,
+    gdb::GdbStub,
     io::ppu::registers::Color,
+    movie::{Movie, MoviePlayer, MovieRecorder, DEFAULT_HASH_INTERVAL},
+    rewind::Rewind,
     rom::Rom,
     utils::{BitPosCheck, Opts},
 };
 
 mod args;
+mod debugger;
+mod headless;
+#[cfg(feature = "scripting")]
+mod overlay;
+mod pacing;
+mod screenshot;
+#[cfg(feature = "scripting")]
+mod scripting;
 
 const SCREEN_WIDTH: usize = 160;
 const SCREEN_HEIGHT: usize = 144;
@@ -16,9 +36,70 @@ const DEBUG_WINDOW_HEIGHT: usize = 240;
 
 #[cfg(not(target_arch = "wasm32"))]
 fn main() {
-    use args::Args;
-    use gameboy_emulator_lib::{io::joypad::JoypadInput, utils::CYCLES_1_FRAME};
-    use minifb::{Key, Scale, ScaleMode, Window, WindowOptions};
+    use minifb::{Key, KeyRepeat, Scale, ScaleMode, Window, WindowOptions};
+    use pacing::{FramePacer, SpeedControl};
+
+    let args = Args::parse();
+
+    let rom =
+        Rom::new(args.path.to_string()).unwrap_or_else(|e| panic!("Error in reading ROM {:?}", e));
+
+    let cart = Cartridge::new(rom.data).unwrap_or_else(|e| panic!("Error in loading ROM: {}", e));
+
+    let mut opts = Opts::new(args.debug, args.serial);
+    opts.ram_init = ram_init(&args);
+
+    let mut ctx = EmuContext::new(cart, opts);
+    ctx.symbols = load_symbols(&args);
+    load_cheats(&args, &mut ctx);
+    load_cdl(&args, &mut ctx);
+
+    if args.profile.is_some() {
+        ctx.start_profiler();
+    }
+
+    if let Some(path) = &args.load_state {
+        let data =
+            std::fs::read(path).unwrap_or_else(|e| panic!("Error in reading save state {:?}", e));
+        ctx.load_state(&data)
+            .unwrap_or_else(|e| panic!("Error in loading save state: {}", e));
+    }
+
+    let mut movie = if let Some(path) = &args.play_movie {
+        let data = std::fs::read(path).unwrap_or_else(|e| panic!("Error in reading movie {:?}", e));
+        let recorded =
+            Movie::deserialize(&data).unwrap_or_else(|e| panic!("Error in loading movie: {}", e));
+
+        MovieMode::Play(
+            MoviePlayer::new(recorded, &mut ctx)
+                .unwrap_or_else(|e| panic!("Error in starting movie: {}", e)),
+        )
+    } else if args.record_movie.is_some() {
+        // a loaded state becomes the movie's starting point
+        if args.load_state.is_some() {
+            MovieMode::Record(MovieRecorder::from_current_state(
+                &ctx,
+                DEFAULT_HASH_INTERVAL,
+            ))
+        } else {
+            MovieMode::Record(MovieRecorder::power_on(&mut ctx, DEFAULT_HASH_INTERVAL))
+        }
+    } else {
+        MovieMode::Off
+    };
+
+    #[cfg(feature = "scripting")]
+    let mut script = scripting::load(&args, &mut ctx);
+
+    if args.headless {
+        #[cfg(feature = "scripting")]
+        let summary = headless::run(&args, &mut ctx, &mut movie, &mut script);
+        #[cfg(not(feature = "scripting"))]
+        let summary = headless::run(&args, &mut ctx, &mut movie);
+        println!("{}", summary);
+        write_outputs(&args, &ctx, movie);
+        return;
+    }
 
     let mut main_buffer: Vec<u32> = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT];
     let mut debug_buffer: Vec<u32> = vec![0; DEBUG_WINDOW_WIDTH * DEBUG_WINDOW_HEIGHT];
@@ -49,77 +130,130 @@ fn main() {
     //     panic!("{}", e);
     // });
 
-    let args = Args::parse();
+    let mut gdb = args.gdb.map(|port| {
+        println!("Waiting for GDB on localhost:{}", port);
+        GdbStub::listen(port).unwrap_or_else(|e| panic!("Error in starting GDB stub {:?}", e))
+    });
 
-    let rom = Rom::new(args.path.to_string());
+    let mut repl = args.debugger.then(|| debugger::Repl::new(&ctx));
 
-    let cart = Cartridge::new(rom.data);
+    let mut rewind = Rewind::new(args.rewind_interval, args.rewind_memory * 1024 * 1024);
 
-    let opts = Opts::new(args.debug, args.serial);
+    let mut frame = 0;
 
-    let mut ctx = EmuContext::new(cart, opts);
+    // pacing is done by FramePacer
+    window.limit_update_rate(None);
+    let mut pacer = FramePacer::new();
+    let mut speed = SpeedControl::new(args.fast_forward_speed, args.slow_motion_speed);
 
     // while window.is_open() && !window.is_key_down(Key::Escape) && debug_window.is_open() {
     while window.is_open() && !window.is_key_down(Key::Escape) {
-        let mut cycles_elapsed = 0;
+        if window.is_key_pressed(Key::P, KeyRepeat::No) {
+            speed.paused = !speed.paused;
+        }
 
-        loop {
-            cycles_elapsed += ctx.step();
+        if window.is_key_pressed(Key::F, KeyRepeat::No) {
+            speed.toggle_fast_forward();
+        }
 
-            if window.is_key_pressed(Key::W, minifb::KeyRepeat::Yes) {
-                ctx.bus.borrow_mut().joypad.key_down(JoypadInput::Up);
-            } else {
-                ctx.bus.borrow_mut().joypad.key_up(JoypadInput::Up);
-            }
+        if window.is_key_pressed(Key::G, KeyRepeat::No) {
+            speed.toggle_slow_motion();
+        }
 
-            if window.is_key_pressed(Key::A, minifb::KeyRepeat::Yes) {
-                ctx.bus.borrow_mut().joypad.key_down(JoypadInput::Left);
-            } else {
-                ctx.bus.borrow_mut().joypad.key_up(JoypadInput::Left);
-            }
+        let frame_advance = window.is_key_pressed(Key::N, KeyRepeat::Yes);
 
-            if window.is_key_pressed(Key::S, minifb::KeyRepeat::Yes) {
-                ctx.bus.borrow_mut().joypad.key_down(JoypadInput::Down);
-            } else {
-                ctx.bus.borrow_mut().joypad.key_up(JoypadInput::Down);
-            }
+        pacer.wait(speed.speed(window.is_key_down(Key::Tab)));
 
-            if window.is_key_pressed(Key::D, minifb::KeyRepeat::Yes) {
-                ctx.bus.borrow_mut().joypad.key_down(JoypadInput::Right);
-            } else {
-                ctx.bus.borrow_mut().joypad.key_up(JoypadInput::Right);
-            }
+        // a movie's inputs and hashes only go forward, rewinding would desync it
+        if window.is_key_down(Key::Backspace) && !movie.is_active() {
+            // every displayed frame goes back one snapshot
+            rewind.rewind(&mut ctx);
 
-            if window.is_key_pressed(Key::J, minifb::KeyRepeat::Yes) {
-                ctx.bus.borrow_mut().joypad.key_down(JoypadInput::A);
-            } else {
-                ctx.bus.borrow_mut().joypad.key_up(JoypadInput::A);
-            }
+            update_screen(&mut main_buffer, &mut ctx);
+            window
+                .update_with_buffer(&main_buffer, SCREEN_WIDTH, SCREEN_HEIGHT)
+                .unwrap();
+            continue;
+        }
 
-            if window.is_key_pressed(Key::K, minifb::KeyRepeat::Yes) {
-                ctx.bus.borrow_mut().joypad.key_down(JoypadInput::B);
-            } else {
-                ctx.bus.borrow_mut().joypad.key_up(JoypadInput::B);
+        if let Some(stub) = &mut gdb {
+            // GDB decides when the game runs, movies, scripts and rewind wait
+            ctx.bus
+                .borrow_mut()
+                .joypad
+                .set_buttons(read_buttons(&window));
+
+            match stub.run_frame(&mut ctx) {
+                Ok(true) => {}
+                Ok(false) => {
+                    println!("GDB detached");
+                    gdb = None;
+                }
+                Err(e) => {
+                    eprintln!("GDB connection lost: {}", e);
+                    gdb = None;
+                }
             }
 
-            if window.is_key_pressed(Key::U, minifb::KeyRepeat::Yes) {
-                ctx.bus.borrow_mut().joypad.key_down(JoypadInput::Select);
-            } else {
-                ctx.bus.borrow_mut().joypad.key_up(JoypadInput::Select);
-            }
+            update_screen(&mut main_buffer, &mut ctx);
+            window
+                .update_with_buffer(&main_buffer, SCREEN_WIDTH, SCREEN_HEIGHT)
+                .unwrap();
+            continue;
+        }
 
-            if window.is_key_pressed(Key::I, minifb::KeyRepeat::Yes) {
-                ctx.bus.borrow_mut().joypad.key_down(JoypadInput::Start);
-            } else {
-                ctx.bus.borrow_mut().joypad.key_up(JoypadInput::Start);
+        if let Some(repl) = &mut repl {
+            // the debugger runs the game, movies, scripts and rewind wait
+            if window.is_key_pressed(Key::F12, KeyRepeat::No) {
+                repl.break_in(&ctx);
             }
 
-            if cycles_elapsed > CYCLES_1_FRAME {
+            ctx.bus
+                .borrow_mut()
+                .joypad
+                .set_buttons(read_buttons(&window));
+
+            repl.run_frame(&mut ctx);
+            if repl.quit() {
                 break;
             }
+
+            update_screen(&mut main_buffer, &mut ctx);
+            window
+                .update_with_buffer(&main_buffer, SCREEN_WIDTH, SCREEN_HEIGHT)
+                .unwrap();
+            continue;
         }
 
+        if speed.paused && !frame_advance {
+            // keeps polling the keyboard
+            window.update();
+            continue;
+        }
+
+        #[cfg(feature = "scripting")]
+        let buttons = read_buttons(&window) | scripting::buttons(&script);
+        #[cfg(not(feature = "scripting"))]
+        let buttons = read_buttons(&window);
+        movie.begin_frame(&mut ctx, buttons);
+        if let Err(e) = ctx.run_frame() {
+            eprintln!("Emulation stopped: {}", e);
+            break;
+        }
+        movie.end_frame(&ctx);
+
+        frame += 1;
+        screenshot::on_frame(&args, &ctx, frame);
+        #[cfg(feature = "scripting")]
+        scripting::on_frame(&mut script, &mut ctx);
+
+        rewind.on_frame(&ctx);
+
         update_screen(&mut main_buffer, &mut ctx);
+        #[cfg(feature = "scripting")]
+        if let Some(script) = &script {
+            overlay::draw_text(&mut main_buffer, &script.text());
+        }
         // update_debug_buffer(&mut debug_buffer, &mut ctx);
 
         window
@@ -130,6 +264,210 @@ fn main() {
         //     .update_with_buffer(&debug_buffer, DEBUG_WINDOW_WIDTH, DEBUG_WINDOW_HEIGHT)
         //     .unwrap();
     }
+
+    write_outputs(&args, &ctx, movie);
+}
+
+/// Picks the power-on RAM pattern, the seed is printed so a run can be repeated
+fn ram_init(args: &Args) -> RamInit {
+    let seed = || {
+        let seed = args.seed.unwrap_or_else(rand::random);
+        println!("RAM init seed: {}", seed);
+        seed
+    };
+
+    match args.ram_init {
+        RamInitArg::Zero => RamInit::Zero,
+        RamInitArg::Ff => RamInit::Ones,
+        RamInitArg::Random => RamInit::Random(seed()),
+        RamInitArg::Dmg => RamInit::DmgLike(seed()),
+    }
+}
+
+/// Symbols from --symbols, or from the ROM's path with a .sym extension.
+/// A broken file next to the ROM is only reported, one asked for is fatal
+fn load_symbols(args: &Args) -> Symbols {
+    let (path, requested) = match &args.symbols {
+        Some(path) => (PathBuf::from(path), true),
+        None => (Path::new(&args.path).with_extension("sym"), false),
+    };
+
+    if !requested && !path.exists() {
+        return Symbols::default();
+    }
+
+    let symbols = std::fs::read_to_string(&path)
+        .map_err(|e| e.to_string())
+        .and_then(|text| text.parse::<Symbols>());
+
+    match symbols {
+        Ok(symbols) => {
+            println!("Loaded {} symbols from {}", symbols.len(), path.display());
+            symbols
+        }
+        Err(e) if requested => panic!("Error in loading symbols: {}", e),
+        Err(e) => {
+            eprintln!("Ignoring {}: {}", path.display(), e);
+            Symbols::default()
+        }
+    }
+}
+
+/// Cheats from --cheats, then every --cheat
+fn load_cheats(args: &Args, ctx: &mut EmuContext) {
+    if let Some(path) = &args.cheats {
+        let text = std::fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("Error in reading cheats {:?}", e));
+        let cheats =
+            cheats::parse_cht(&text).unwrap_or_else(|e| panic!("Error in loading cheats: {}", e));
+
+        for cheat in cheats {
+            ctx.add_cheat(cheat);
+        }
+    }
+
+    for code in &args.cheat {
+        let cheat =
+            Cheat::new(code, code).unwrap_or_else(|e| panic!("Error in adding cheat: {}", e));
+        ctx.add_cheat(cheat);
+    }
+
+    for (id, cheat) in ctx.cheats().iter() {
+        let state = if cheat.enabled { "on" } else { "off" };
+        println!("Cheat {} [{}] {}: {}", id, state, cheat.name, cheat.code);
+    }
+}
+
+fn load_cdl(args: &Args, ctx: &mut EmuContext) {
+    let Some(path) = &args.cdl else { return };
+
+    let mut bus = ctx.bus.borrow_mut();
+    let rom_size = bus.cartridge.data.len();
+    let cdl = match std::fs::read(path) {
+        Ok(data) => CodeDataLog::from_bytes(&data, rom_size)
+            .unwrap_or_else(|e| panic!("Error in loading CDL: {}", e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => CodeDataLog::new(rom_size),
+        Err(e) => panic!("Error in reading CDL {:?}", e),
+    };
+
+    bus.cartridge.set_cdl(Some(cdl));
+}
+
+/// Files requested on the command line, written once emulation stops
+fn write_outputs(args: &Args, ctx: &EmuContext, movie: MovieMode) {
+    if let (Some(path), Some(profiler)) = (&args.profile, ctx.profiler()) {
+        println!("{}", profiler.report(&ctx.symbols, 20));
+        std::fs::write(path, profiler.collapsed(&ctx.symbols))
+            .unwrap_or_else(|e| panic!("Error in writing profile {:?}", e));
+    }
+
+    if let (Some(path), Some(cdl)) = (&args.cdl, ctx.bus.borrow().cartridge.cdl()) {
+        println!(
+            "CDL: {} opcode, {} operand, {} data, {} DMA, {} unused of {} bytes",
+            cdl.count(ReadKind::Opcode),
+            cdl.count(ReadKind::Operand),
+            cdl.count(ReadKind::Data),
+            cdl.count(ReadKind::Dma),
+            cdl.unused(),
+            cdl.len()
+        );
+        std::fs::write(path, cdl.as_bytes())
+            .unwrap_or_else(|e| panic!("Error in writing CDL {:?}", e));
+    }
+
+    if let Some(path) = &args.save_state {
+        std::fs::write(path, ctx.save_bess())
+            .unwrap_or_else(|e| panic!("Error in writing save state {:?}", e));
+    }
+
+    if let Some(path) = &args.screenshot {
+        screenshot::save_png(Path::new(path), ctx)
+            .unwrap_or_else(|e| panic!("Error in writing screenshot {:?}", e));
+    }
+
+    if let Some(path) = &args.serial_out {
+        std::fs::write(path, ctx.bus.borrow().serial.output())
+            .unwrap_or_else(|e| panic!("Error in writing serial output {:?}", e));
+    }
+
+    if let (MovieMode::Record(recorder), Some(path)) = (movie, &args.record_movie) {
+        println!(
+            "Recorded {} frames, {} lag frames",
+            recorder.frames(),
+            recorder.lag_frames()
+        );
+        std::fs::write(path, recorder.finish().serialize())
+            .unwrap_or_else(|e| panic!("Error in writing movie {:?}", e));
+    }
+}
+
+enum MovieMode {
+    Off,
+    Record(MovieRecorder),
+    Play(MoviePlayer),
+}
+
+impl MovieMode {
+    fn is_active(&self) -> bool {
+        !matches!(self, MovieMode::Off)
+    }
+
+    /// Feeds the frame's input, `buttons` is ignored while a movie plays
+    fn begin_frame(&mut self, ctx: &mut EmuContext, buttons: u8) {
+        match self {
+            MovieMode::Record(recorder) => recorder.begin_frame(ctx, buttons),
+            MovieMode::Play(player) => {
+                if !player.begin_frame(ctx) {
+                    println!(
+                        "Movie finished after {} frames, {} lag frames",
+                        player.frame(),
+                        player.lag_frames()
+                    );
+                    *self = MovieMode::Off;
+                    self.begin_frame(ctx, buttons);
+                }
+            }
+            MovieMode::Off => ctx.bus.borrow_mut().joypad.set_buttons(buttons),
+        }
+    }
+
+    fn end_frame(&mut self, ctx: &EmuContext) {
+        match self {
+            MovieMode::Record(recorder) => {
+                recorder.end_frame(ctx);
+            }
+            MovieMode::Play(player) => {
+                if let Some(desync) = player.end_frame(ctx) {
+                    if desync.frame == player.frame() {
+                        println!("Movie {}", desync);
+                    }
+                }
+            }
+            MovieMode::Off => {}
+        }
+    }
+}
+
+/// Samples the keyboard into a joypad button mask
+#[cfg(not(target_arch = "wasm32"))]
+fn read_buttons(window: &minifb::Window) -> u8 {
+    use gameboy_emulator_lib::io::joypad::JoypadInput;
+    use minifb::Key;
+
+    let keys = [
+        (Key::W, JoypadInput::Up),
+        (Key::A, JoypadInput::Left),
+        (Key::S, JoypadInput::Down),
+        (Key::D, JoypadInput::Right),
+        (Key::J, JoypadInput::A),
+        (Key::K, JoypadInput::B),
+        (Key::U, JoypadInput::Select),
+        (Key::I, JoypadInput::Start),
+    ];
+
+    keys.iter()
+        .filter(|(key, _)| window.is_key_down(*key))
+        .fold(0, |mask, (_, input)| mask | (1 << *input as u8))
 }
 
 fn from_u8_rgb(r: u8, g: u8, b: u8) -> u32 {
@@ -139,10 +477,11 @@ fn from_u8_rgb(r: u8, g: u8, b: u8) -> u32 {
 
 fn color_to_rgb(color: Color) -> u32 {
     match color {
-        Color::C0 => from_u8_rgb(255, 255, 255),
+        Color::C0 => from_u8_rgb(240, 240, 240),
         Color::C1 => from_u8_rgb(170, 170, 170),
         Color::C2 => from_u8_rgb(85, 85, 85),
         Color::C3 => from_u8_rgb(0, 0, 0),
+        Color::Off => from_u8_rgb(255, 255, 255),
     }
 }
 
@@ -0,0 +1,211 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use gameboy_emulator_lib::utils::CYCLES_1_FRAME;
+
+/// Gameboy clock speed in Hz
+pub const CPU_FREQ: f64 = 4_194_304.0;
+/// Refresh rate of the DMG lcd, 59.7275 Hz
+pub const FRAME_RATE: f64 = CPU_FREQ / CYCLES_1_FRAME as f64;
+
+/// If emulation falls this many frames behind, e.g. after the window was
+/// dragged, the schedule restarts instead of racing to catch up
+const MAX_FRAMES_BEHIND: u32 = 4;
+
+/// Where the pacer gets the time from, tests use a fake one
+pub trait Clock {
+    fn now(&self) -> Instant;
+    fn sleep(&mut self, duration: Duration);
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// Keeps frames on a fixed schedule
+///
+/// Deadlines advance by exactly one frame time each frame, so the time spent
+/// emulating and rendering doesn't add up to drift.
+pub struct FramePacer<C: Clock = SystemClock> {
+    clock: C,
+    next_frame: Instant,
+}
+
+impl FramePacer {
+    pub fn new() -> Self {
+        Self::with_clock(SystemClock)
+    }
+}
+
+impl<C: Clock> FramePacer<C> {
+    pub fn with_clock(clock: C) -> Self {
+        FramePacer {
+            next_frame: clock.now(),
+            clock,
+        }
+    }
+
+    /// Sleeps until the next frame is due when running at `speed` times
+    /// real time. `None` runs uncapped.
+    pub fn wait(&mut self, speed: Option<f64>) {
+        let Some(speed) = speed else {
+            self.next_frame = self.clock.now();
+            return;
+        };
+
+        let frame_time = Duration::from_secs_f64(1.0 / (FRAME_RATE * speed));
+        self.next_frame += frame_time;
+
+        let now = self.clock.now();
+        if self.next_frame > now {
+            self.clock.sleep(self.next_frame - now);
+        } else if now - self.next_frame > frame_time * MAX_FRAMES_BEHIND {
+            self.next_frame = now;
+        }
+    }
+}
+
+/// Speed hotkey state
+pub struct SpeedControl {
+    pub paused: bool,
+    pub fast_forward: bool,
+    pub slow_motion: bool,
+    fast_forward_speed: f64,
+    slow_motion_speed: f64,
+}
+
+impl SpeedControl {
+    pub fn new(fast_forward_speed: f64, slow_motion_speed: f64) -> Self {
+        SpeedControl {
+            paused: false,
+            fast_forward: false,
+            slow_motion: false,
+            fast_forward_speed,
+            slow_motion_speed,
+        }
+    }
+
+    /// Multiple of real time to run at, `None` for uncapped
+    pub fn speed(&self, uncapped: bool) -> Option<f64> {
+        if uncapped {
+            return None;
+        }
+
+        match (self.fast_forward, self.slow_motion) {
+            (true, _) => Some(self.fast_forward_speed),
+            (false, true) => Some(self.slow_motion_speed),
+            (false, false) => Some(1.0),
+        }
+    }
+
+    pub fn toggle_fast_forward(&mut self) {
+        self.fast_forward = !self.fast_forward;
+        self.slow_motion = false;
+    }
+
+    pub fn toggle_slow_motion(&mut self) {
+        self.slow_motion = !self.slow_motion;
+        self.fast_forward = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Clock, FramePacer, SpeedControl, FRAME_RATE};
+
+    /// Time only moves when the pacer sleeps or the test advances it
+    struct FakeClock {
+        now: Instant,
+        slept: Vec<Duration>,
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.now
+        }
+
+        fn sleep(&mut self, duration: Duration) {
+            self.now += duration;
+            self.slept.push(duration);
+        }
+    }
+
+    fn test_pacer() -> FramePacer<FakeClock> {
+        FramePacer::with_clock(FakeClock {
+            now: Instant::now(),
+            slept: vec![],
+        })
+    }
+
+    fn frame_time(speed: f64) -> Duration {
+        Duration::from_secs_f64(1.0 / (FRAME_RATE * speed))
+    }
+
+    #[test]
+    fn test_sleeps_until_the_deadline() {
+        let mut pacer = test_pacer();
+
+        pacer.wait(Some(1.0));
+        assert_eq!(pacer.clock.slept, [frame_time(1.0)]);
+
+        // the time spent emulating comes off the next sleep
+        pacer.clock.now += frame_time(1.0) / 4;
+        pacer.wait(Some(1.0));
+        assert_eq!(pacer.clock.slept[1], frame_time(1.0) - frame_time(1.0) / 4);
+
+        pacer.wait(Some(2.0));
+        assert_eq!(pacer.clock.slept[2], frame_time(2.0));
+    }
+
+    #[test]
+    fn test_uncapped_and_falling_behind() {
+        let mut pacer = test_pacer();
+
+        pacer.clock.now += frame_time(1.0) * 10;
+        pacer.wait(None);
+        assert!(pacer.clock.slept.is_empty());
+
+        // the uncapped frame moved the schedule to now
+        pacer.wait(Some(1.0));
+        assert_eq!(pacer.clock.slept, [frame_time(1.0)]);
+
+        // too far behind, the schedule restarts instead of catching up
+        pacer.clock.now += frame_time(1.0) * 10;
+        pacer.wait(Some(1.0));
+        assert_eq!(pacer.clock.slept.len(), 1);
+        pacer.wait(Some(1.0));
+        assert_eq!(pacer.clock.slept[1], frame_time(1.0));
+    }
+
+    #[test]
+    fn test_speed() {
+        let mut speed = SpeedControl::new(3.0, 0.5);
+        assert_eq!(speed.speed(false), Some(1.0));
+        assert_eq!(speed.speed(true), None);
+
+        speed.toggle_fast_forward();
+        assert_eq!(speed.speed(false), Some(3.0));
+        // holding Tab beats the toggle
+        assert_eq!(speed.speed(true), None);
+
+        speed.toggle_slow_motion();
+        assert!(!speed.fast_forward);
+        assert_eq!(speed.speed(false), Some(0.5));
+        assert_eq!(speed.speed(true), None);
+
+        speed.toggle_slow_motion();
+        assert_eq!(speed.speed(false), Some(1.0));
+    }
+}
@@ -0,0 +1,126 @@
+use std::{fs::File, io::BufWriter, path::Path};
+
+use gameboy_emulator_lib::emu::EmuContext;
+
+use crate::{args::Args, color_to_rgb, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// Writes the PPU buffer as an 8 bit RGB PNG
+pub fn save_png(path: &Path, ctx: &EmuContext) -> Result<(), png::EncodingError> {
+    let file = BufWriter::new(File::create(path)?);
+
+    let mut encoder = png::Encoder::new(file, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let data = ctx
+        .bus
+        .borrow()
+        .ppu
+        .buffer
+        .iter()
+        .flat_map(|pixel| {
+            let rgb = color_to_rgb(pixel.get_color());
+            [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8]
+        })
+        .collect::<Vec<_>>();
+
+    encoder.write_header()?.write_image_data(&data)
+}
+
+/// Writes the periodic screenshot asked for with `--screenshot-every`
+pub fn on_frame(args: &Args, ctx: &EmuContext, frame: u32) {
+    let (Some(path), Some(every)) = (&args.screenshot, args.screenshot_every) else {
+        return;
+    };
+
+    if every == 0 || !frame.is_multiple_of(every) {
+        return;
+    }
+
+    save_png(&numbered_path(Path::new(path), frame), ctx)
+        .unwrap_or_else(|e| panic!("Error in writing screenshot {:?}", e));
+}
+
+/// `shot.png` -> `shot_000120.png`
+fn numbered_path(path: &Path, frame: u32) -> std::path::PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+
+    path.with_file_name(format!("{}_{:06}.{}", stem, frame, extension))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, path::Path};
+
+    use gameboy_emulator_lib::{
+        cartridge::Cartridge, emu::EmuContext, io::ppu::registers::Color, utils::Opts,
+    };
+
+    /// Shade 0 ( lightest ) to 3 of every pixel of a grayscale or RGB PNG
+    fn png_shades(path: &Path) -> Vec<u8> {
+        let mut decoder = png::Decoder::new(File::open(path).unwrap());
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut data).unwrap();
+        let channels = info.color_type.samples();
+
+        data[..info.buffer_size()]
+            .chunks(channels)
+            .map(|pixel| {
+                // alpha is ignored, the reference images are opaque
+                let luma = match channels {
+                    1 | 2 => pixel[0] as u32,
+                    _ => (pixel[0] as u32 + pixel[1] as u32 + pixel[2] as u32) / 3,
+                };
+                ((255 - luma + 42) / 85) as u8
+            })
+            .collect()
+    }
+
+    /// dmg-acid2 against the reference image from its repository.
+    /// The rom isn't redistributed, point `DMG_ACID2_ROM` at `dmg-acid2.gb` and
+    /// `DMG_ACID2_REFERENCE` at `reference-dmg.png` and run ignored tests
+    #[test]
+    #[ignore = "needs DMG_ACID2_ROM and DMG_ACID2_REFERENCE"]
+    fn test_dmg_acid2() {
+        let rom = std::env::var("DMG_ACID2_ROM").expect("DMG_ACID2_ROM is not set");
+        let reference =
+            std::env::var("DMG_ACID2_REFERENCE").expect("DMG_ACID2_REFERENCE is not set");
+
+        let cart = Cartridge::new(std::fs::read(rom).unwrap()).unwrap();
+        let mut ctx = EmuContext::new(cart, Opts::new(false, false));
+        for _ in 0..60 {
+            ctx.run_frame().unwrap();
+        }
+
+        let shades = ctx
+            .bus
+            .borrow()
+            .ppu
+            .buffer
+            .iter()
+            .map(|pixel| match pixel.get_color() {
+                Color::Off => 0,
+                color => color as u8,
+            })
+            .collect::<Vec<_>>();
+        let expected = png_shades(Path::new(&reference));
+        assert_eq!(shades.len(), expected.len());
+
+        let wrong = shades
+            .iter()
+            .zip(expected.iter())
+            .enumerate()
+            .filter(|(_, (found, expected))| found != expected)
+            .map(|(idx, _)| (idx % super::SCREEN_WIDTH, idx / super::SCREEN_WIDTH))
+            .collect::<Vec<_>>();
+        assert!(
+            wrong.is_empty(),
+            "{} pixels differ, first at {:?}",
+            wrong.len(),
+            wrong.first()
+        );
+    }
+}
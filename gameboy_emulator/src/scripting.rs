@@ -0,0 +1,41 @@
+use std::path::Path;
+
+use gameboy_emulator_lib::{emu::EmuContext, script::Script};
+
+use crate::{args::Args, screenshot};
+
+/// Loads the script given with `--script`
+pub fn load(args: &Args, ctx: &mut EmuContext) -> Option<Script> {
+    let path = args.script.as_ref()?;
+
+    Some(
+        Script::load(Path::new(path), ctx)
+            .unwrap_or_else(|e| panic!("Error in loading script: {}", e)),
+    )
+}
+
+/// Buttons the script holds down
+pub fn buttons(script: &Option<Script>) -> u8 {
+    script.as_ref().map_or(0, |script| script.buttons())
+}
+
+/// Runs the script's frame callback and writes the screenshots it asked for.
+/// A failing script is stopped, emulation goes on without it.
+pub fn on_frame(script: &mut Option<Script>, ctx: &mut EmuContext) {
+    let Some(running) = script else {
+        return;
+    };
+
+    let result = running.on_frame(ctx);
+
+    for path in running.take_screenshots() {
+        if let Err(e) = screenshot::save_png(Path::new(&path), ctx) {
+            eprintln!("Error in writing screenshot {:?}", e);
+        }
+    }
+
+    if let Err(e) = result {
+        eprintln!("Script stopped: {}", e);
+        *script = None;
+    }
+}
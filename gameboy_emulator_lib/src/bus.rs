@@ -1,17 +1,29 @@
 use std::{cell::RefCell, rc::Rc};
 
 use crate::{
-    cartridge::Cartridge,
+    cartridge::{cdl::ReadKind, Cartridge},
+    hooks::AccessLog,
     interrupt::Interrupts,
-    io::{joypad::Joypad, ppu::PPU, serial::Serial, timer::Timer},
+    io::{
+        joypad::Joypad,
+        ppu::{oam::OamBug, PPU},
+        serial::Serial,
+        timer::Timer,
+    },
+    savestate::{Savestate, SavestateError, StateReader, StateWriter},
 };
 
+use self::dma::Dma;
+use self::ram_init::{PowerOnRam, RamInit};
 use self::ranges::{
-    CART_END, CART_START, HRAM_END, HRAM_SIZE, HRAM_START, INTERRUPT_ENABLE, INTERRUPT_FLAG,
-    JOYPAD, LCD_END, LCD_START, OAM_END, OAM_START, SERIAL_END, SERIAL_START, TIMER_END,
-    TIMER_START, VRAM_END, VRAM_START, WRAM_END, WRAM_SIZE, WRAM_START,
+    CART_END, CART_START, DMA, ECHO_END, ECHO_START, EXTERNAL_END, EXTERNAL_SIZE, EXTERNAL_START,
+    HRAM_END, HRAM_SIZE, HRAM_START, INTERRUPT_ENABLE, INTERRUPT_FLAG, JOYPAD, LCD_END, LCD_START,
+    OAM_END, OAM_SIZE, OAM_START, PROHIBITED_END, PROHIBITED_START, SERIAL_END, SERIAL_START,
+    TIMER_END, TIMER_START, VRAM_END, VRAM_START, WRAM_END, WRAM_SIZE, WRAM_START,
 };
 
+pub mod dma;
+pub mod ram_init;
 pub mod ranges;
 
 pub struct Bus {
@@ -21,91 +33,307 @@ pub struct Bus {
     pub ppu: PPU,
     pub joypad: Joypad,
     pub interrupts: Rc<RefCell<Interrupts>>,
-    start_dma_transfer: bool,
+    /// Accesses watched by read / write hooks
+    pub access_log: AccessLog,
+    pub dma: Dma,
+    /// Cartridge RAM, not banked
+    eram: [u8; EXTERNAL_SIZE],
     wram: [u8; WRAM_SIZE],
     hram: [u8; HRAM_SIZE],
-    memory: [u8; 0x10000],
 }
 
 pub trait Memory {
     fn read(&self, address: u16) -> u8;
     fn write(&mut self, address: u16, byte: u8);
+
+    /// A read by the cpu or a DMA transfer, the bus logs ROM reads by `kind`.
+    /// Tools read with `read` so that they don't show up in the log
+    fn read_as(&self, address: u16, _kind: ReadKind) -> u8 {
+        self.read(address)
+    }
+
+    /// Runs everything on the bus for one M-cycle, the cpu calls it after
+    /// every access or internal cycle
+    fn tick_m_cycle(&mut self) {}
+
+    /// The cpu put `address` on the bus, for the DMG's OAM corruption bug
+    fn oam_bug(&mut self, _address: u16, _access: OamBug) {}
 }
 
 impl Memory for Bus {
     fn read(&self, address: u16) -> u8 {
+        let byte = self.read_mapped(address);
+        self.access_log.record_read(address, byte);
+        byte
+    }
+
+    fn read_as(&self, address: u16, kind: ReadKind) -> u8 {
+        if let Some(byte) = self.dma.conflict(address) {
+            self.access_log.record_read(address, byte);
+            return byte;
+        }
+
+        match address {
+            CART_START..=CART_END => self.cartridge.log_read(address, kind),
+            // only the game's reads count for the lag counter
+            JOYPAD if kind == ReadKind::Data => self.joypad.mark_polled(),
+            _ => {}
+        }
+        self.read(address)
+    }
+
+    fn write(&mut self, address: u16, byte: u8) {
+        self.access_log.record_write(address, byte);
+        self.write_mapped(address, byte);
+    }
+
+    fn tick_m_cycle(&mut self) {
+        for _ in 0..4 {
+            self.tick();
+        }
+        self.dma_cycle();
+    }
+
+    fn oam_bug(&mut self, address: u16, access: OamBug) {
+        self.ppu.oam_bug(address, access);
+    }
+}
+
+impl Savestate for Bus {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.interrupts.borrow().save_state(state);
+        self.timer.save_state(state);
+        self.serial.save_state(state);
+        self.ppu.save_state(state);
+        self.joypad.save_state(state);
+        self.dma.save_state(state);
+        state.write_bytes(&self.eram);
+        state.write_bytes(&self.wram);
+        state.write_bytes(&self.hram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SavestateError> {
+        self.interrupts.borrow_mut().load_state(state)?;
+        self.timer.load_state(state)?;
+        self.serial.load_state(state)?;
+        self.ppu.load_state(state)?;
+        self.joypad.load_state(state)?;
+        self.dma.load_state(state)?;
+        state.read_into(&mut self.eram)?;
+        state.read_into(&mut self.wram)?;
+        state.read_into(&mut self.hram)?;
+        Ok(())
+    }
+}
+
+impl Bus {
+    pub fn new(cartridge: Cartridge) -> Self {
+        let interrupts = Rc::new(RefCell::new(Interrupts::new()));
+
+        Bus {
+            cartridge,
+            timer: Timer::new(interrupts.clone()),
+            serial: Serial::new(interrupts.clone()),
+            ppu: PPU::new(interrupts.clone()),
+            joypad: Joypad::new(interrupts.clone()),
+            interrupts,
+            access_log: AccessLog::default(),
+            eram: [0; EXTERNAL_SIZE],
+            wram: [0; WRAM_SIZE],
+            hram: [0; HRAM_SIZE],
+            dma: Dma::new(),
+        }
+    }
+
+    /// Fills WRAM, HRAM, VRAM and OAM the way they come up at power on
+    pub fn init_ram(&mut self, init: RamInit) {
+        let mut oam = [0; OAM_SIZE];
+
+        init.fill(PowerOnRam {
+            wram: &mut self.wram,
+            hram: &mut self.hram,
+            vram: &mut self.ppu.vram,
+            oam: &mut oam,
+        });
+
+        for (idx, entry) in self.ppu.oam.iter_mut().enumerate() {
+            for field in 0..4 {
+                entry.set_field(oam[idx * 4 + field], field);
+            }
+        }
+    }
+
+    /// Sets an IO register ( 0xFF00 - 0xFF7F ) or IE without the side effects
+    /// a regular write has, e.g. DIV reset, starting a DMA transfer or the
+    /// STAT write interrupt
+    pub fn restore_io(&mut self, address: u16, byte: u8) {
+        match address {
+            0xFF04 => self.timer.set_div_counter((byte as u16) << 8),
+            0xFF40 => self.ppu.restore_lcdc(byte),
+            0xFF41 => self.ppu.restore_stat(byte),
+            0xFF46 => self.dma.restore(byte),
+            _ => self.write_mapped(address, byte),
+        }
+    }
+
+    /// Cartridge RAM, 0xA000 - 0xBFFF
+    pub fn eram(&self) -> &[u8] {
+        &self.eram
+    }
+
+    pub fn wram(&self) -> &[u8] {
+        &self.wram
+    }
+
+    pub fn hram(&self) -> &[u8] {
+        &self.hram
+    }
+
+    /// Sets cartridge RAM, WRAM and HRAM for a loaded state. Unlike writes
+    /// through the bus, hooks and DMA don't see them. Extra bytes are ignored
+    pub fn restore_ram(&mut self, eram: &[u8], wram: &[u8], hram: &[u8]) {
+        for (dest, src) in [
+            (&mut self.eram[..], eram),
+            (&mut self.wram[..], wram),
+            (&mut self.hram[..], hram),
+        ] {
+            let len = dest.len().min(src.len());
+            dest[..len].copy_from_slice(&src[..len]);
+        }
+    }
+
+    /// # Memory map
+    /// - echo RAM ( 0xE000 - 0xFDFF ) mirrors 0xC000 - 0xDDFF
+    /// - the prohibited area ( 0xFEA0 - 0xFEFF ) reads 0x00 on a DMG, 0xFF while
+    ///   OAM is blocked. Writes are ignored. CGB revisions behave differently,
+    ///   but only the DMG is emulated
+    /// - unmapped IO registers read 0xFF and ignore writes. Mapped registers set
+    ///   their unused bits themselves
+    fn read_mapped(&self, address: u16) -> u8 {
         match address {
             CART_START..=CART_END => self.cartridge.read(address),
+            EXTERNAL_START..=EXTERNAL_END => self.eram[(address - EXTERNAL_START) as usize],
             WRAM_START..=WRAM_END => self.wram[(address - WRAM_START) as usize],
+            ECHO_START..=ECHO_END => self.wram[(address - ECHO_START) as usize],
+            PROHIBITED_START..=PROHIBITED_END => {
+                if self.ppu.oam_blocked() || self.dma.is_active() {
+                    0xFF
+                } else {
+                    0x00
+                }
+            }
             JOYPAD => self.joypad.read(address),
             SERIAL_START..=SERIAL_END => self.serial.read(address),
             TIMER_START..=TIMER_END => self.timer.read(address),
+            DMA => self.dma.read(),
+            OAM_START..=OAM_END if self.dma.is_active() => 0xFF,
             VRAM_START..=VRAM_END | LCD_START..=LCD_END | OAM_START..=OAM_END => {
                 self.ppu.read(address)
             }
             HRAM_START..=HRAM_END => self.hram[(address - HRAM_START) as usize],
             INTERRUPT_ENABLE | INTERRUPT_FLAG => self.interrupts.borrow().read(address),
-            _ => self.memory[address as usize],
+            // unmapped IO registers
+            _ => 0xFF,
         }
     }
 
-    fn write(&mut self, address: u16, byte: u8) {
+    pub(crate) fn write_mapped(&mut self, address: u16, byte: u8) {
         match address {
             CART_START..=CART_END => self.cartridge.write(address, byte),
+            EXTERNAL_START..=EXTERNAL_END => self.eram[(address - EXTERNAL_START) as usize] = byte,
             WRAM_START..=WRAM_END => self.wram[(address - WRAM_START) as usize] = byte,
+            ECHO_START..=ECHO_END => self.wram[(address - ECHO_START) as usize] = byte,
+            PROHIBITED_START..=PROHIBITED_END => {}
             JOYPAD => self.joypad.write(address, byte),
             SERIAL_START..=SERIAL_END => self.serial.write(address, byte),
             TIMER_START..=TIMER_END => self.timer.write(address, byte),
+            DMA => self.dma.write(byte),
+            OAM_START..=OAM_END if self.dma.is_active() => {}
             VRAM_START..=VRAM_END | LCD_START..=LCD_END | OAM_START..=OAM_END => {
                 self.ppu.write(address, byte)
             }
             HRAM_START..=HRAM_END => self.hram[(address - HRAM_START) as usize] = byte,
             INTERRUPT_ENABLE | INTERRUPT_FLAG => self.interrupts.borrow_mut().write(address, byte),
-            _ => self.memory[address as usize] = byte,
+            _ => {}
         }
     }
-}
 
-impl Bus {
-    pub fn new(cartridge: Cartridge) -> Self {
-        let memory = [0x00; 0x10000];
-        let interrupts = Rc::new(RefCell::new(Interrupts::new()));
+    pub fn tick(&mut self) {
+        self.timer.tick();
+        self.ppu.tick();
+    }
 
-        Bus {
-            cartridge,
-            timer: Timer::new(interrupts.clone()),
-            serial: Serial::new(interrupts.clone()),
-            ppu: PPU::new(interrupts.clone()),
-            joypad: Joypad::new(interrupts.clone()),
-            interrupts,
-            wram: [0; WRAM_SIZE],
-            hram: [0; HRAM_SIZE],
-            memory,
-            start_dma_transfer: false,
+    fn dma_cycle(&mut self) {
+        let Some((source, dest)) = self.dma.m_cycle() else {
+            return;
+        };
+
+        if let CART_START..=CART_END = source {
+            self.cartridge.log_read(source, ReadKind::Dma);
         }
+        let byte = self.read_mapped(source);
+        self.dma.set_byte(byte);
+        self.ppu.write_oam(dest, byte);
     }
+}
 
-    pub fn tick(&mut self) {
-        self.timer.tick();
-        self.ppu.tick();
+#[cfg(test)]
+mod tests {
+    use super::{Bus, Memory};
+    use crate::cartridge::{cdl::ReadKind, Cartridge};
 
-        self.dma_transfer();
+    fn test_bus() -> Bus {
+        Bus::new(Cartridge::new(vec![0; 0x8000]).unwrap())
     }
 
-    fn dma_transfer(&mut self) {
-        // dma started inside PPU
-        if self.ppu.dma_mode && !self.start_dma_transfer {
-            self.start_dma_transfer = true;
-            let mut src = self.ppu.dma as u16 * 0x100;
-            let mut dest = 0xFE00;
+    #[test]
+    fn test_echo_ram_mirrors_wram() {
+        let mut bus = test_bus();
 
-            for _ in 0..160 {
-                self.write(dest, self.read(src));
-                dest += 1;
-                src += 1;
-            }
-        } else if !self.ppu.dma_mode {
-            self.start_dma_transfer = false;
+        bus.write(0xC123, 0x42);
+        assert_eq!(bus.read(0xE123), 0x42);
+
+        bus.write(0xFDFF, 0x24);
+        assert_eq!(bus.read(0xDDFF), 0x24);
+    }
+
+    #[test]
+    fn test_prohibited_area_and_unmapped_io() {
+        let mut bus = test_bus();
+
+        bus.write(0xFEA0, 0x42);
+        assert_eq!(bus.read(0xFEA0), 0x00);
+
+        for address in [0xFF03, 0xFF08, 0xFF4C, 0xFF7F] {
+            bus.write(address, 0x00);
+            assert_eq!(bus.read(address), 0xFF);
         }
     }
+
+    #[test]
+    fn test_unused_register_bits_read_one() {
+        let mut bus = test_bus();
+
+        // before IF, writing STAT in VBlank requests an interrupt
+        bus.write(0xFF41, 0x00);
+        bus.write(0xFF0F, 0x00);
+        bus.write(0xFF07, 0x00);
+        bus.write(0xFF00, 0x30);
+
+        assert_eq!(bus.read(0xFF0F), 0xE0);
+        assert_eq!(bus.read(0xFF07), 0xF8);
+        assert_eq!(bus.read(0xFF41) & 0x80, 0x80);
+        assert_eq!(bus.read(0xFF00), 0xFF);
+    }
+
+    #[test]
+    fn test_only_cpu_reads_poll_the_joypad() {
+        let bus = test_bus();
+
+        bus.read(0xFF00);
+        assert!(!bus.joypad.take_polled());
+
+        bus.read_as(0xFF00, ReadKind::Data);
+        assert!(bus.joypad.take_polled());
+    }
 }
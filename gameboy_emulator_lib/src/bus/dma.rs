@@ -0,0 +1,224 @@
+use crate::savestate::{Savestate, SavestateError, StateReader, StateWriter};
+
+use super::ranges::{
+    CART_END, CART_START, ECHO_END, ECHO_START, EXTERNAL_START, OAM_SIZE, OAM_START,
+    PROHIBITED_END, VRAM_END, VRAM_START, WRAM_START,
+};
+
+/// M-cycles from the write to 0xFF46 until the first byte is copied, the
+/// cycle of the write and one of startup
+const STARTUP_CYCLES: u8 = 2;
+
+/// # OAM DMA
+/// Writing a page XX to 0xFF46 copies 0xXX00 - 0xXX9F to OAM, one byte per
+/// M-cycle after a cycle of startup. Pages 0xE0 - 0xFF read WRAM, like echo
+/// RAM does.
+///
+/// While the transfer runs the DMA owns the bus it reads from. VRAM has a
+/// bus of its own, ROM, cartridge RAM and WRAM share the external one. The
+/// cpu reads the byte being copied from anywhere on the source's bus, and
+/// 0xFF from OAM. The other bus, IO registers and HRAM work normally. That's
+/// why games wait for the transfer in a routine copied to HRAM.
+///
+/// Writing 0xFF46 again restarts the transfer, the old one keeps going until
+/// the new one got through its startup.
+pub struct Dma {
+    /// Page of the last transfer started, what 0xFF46 reads
+    register: u8,
+    /// A transfer getting started, its page and the M-cycles left until then
+    starting: Option<(u8, u8)>,
+    /// The page being copied and the next byte of it
+    active: Option<(u8, u8)>,
+    /// Last byte copied, what the cpu reads during the transfer
+    byte: u8,
+}
+
+impl Default for Dma {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for Dma {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.register);
+        save_transfer(self.starting, state);
+        save_transfer(self.active, state);
+        state.write_u8(self.byte);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SavestateError> {
+        self.register = state.read_u8()?;
+        self.starting = load_transfer(state)?;
+        self.active = load_transfer(state)?;
+        self.byte = state.read_u8()?;
+
+        if matches!(self.active, Some((_, idx)) if idx as usize >= OAM_SIZE) {
+            return Err(SavestateError::InvalidValue("dma byte index"));
+        }
+        Ok(())
+    }
+}
+
+fn save_transfer(transfer: Option<(u8, u8)>, state: &mut StateWriter) {
+    state.write_bool(transfer.is_some());
+    let (page, count) = transfer.unwrap_or_default();
+    state.write_u8(page);
+    state.write_u8(count);
+}
+
+fn load_transfer(state: &mut StateReader) -> Result<Option<(u8, u8)>, SavestateError> {
+    let is_some = state.read_bool()?;
+    let transfer = (state.read_u8()?, state.read_u8()?);
+    Ok(is_some.then_some(transfer))
+}
+
+impl Dma {
+    pub fn new() -> Self {
+        Dma {
+            register: 0xFF,
+            starting: None,
+            active: None,
+            byte: 0xFF,
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        self.register
+    }
+
+    /// Starts a transfer from `page`
+    pub fn write(&mut self, page: u8) {
+        self.register = page;
+        self.starting = Some((page, STARTUP_CYCLES));
+    }
+
+    /// Sets 0xFF46 without starting a transfer
+    pub fn restore(&mut self, page: u8) {
+        self.register = page;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.is_some()
+    }
+
+    /// Runs an M-cycle, returns the source and OAM address of the byte to
+    /// copy on it
+    pub fn m_cycle(&mut self) -> Option<(u16, u16)> {
+        let transfer = self.active.map(|(page, idx)| {
+            let mut source = u16::from_be_bytes([page, idx]);
+            if source >= ECHO_START {
+                source -= ECHO_START - WRAM_START;
+            }
+            (source, OAM_START + idx as u16)
+        });
+
+        self.active = match self.active {
+            Some((page, idx)) if (idx as usize) < OAM_SIZE - 1 => Some((page, idx + 1)),
+            _ => None,
+        };
+
+        if let Some((page, cycles)) = self.starting {
+            if cycles > 1 {
+                self.starting = Some((page, cycles - 1));
+            } else {
+                self.starting = None;
+                self.active = Some((page, 0));
+            }
+        }
+
+        transfer
+    }
+
+    /// The byte copied on this M-cycle
+    pub fn set_byte(&mut self, byte: u8) {
+        self.byte = byte;
+    }
+
+    /// What the cpu reads from `address` instead of memory, while the
+    /// transfer has the bus
+    pub fn conflict(&self, address: u16) -> Option<u8> {
+        let (page, _) = self.active?;
+        let from_vram = (VRAM_START..=VRAM_END).contains(&u16::from_be_bytes([page, 0]));
+
+        match address {
+            VRAM_START..=VRAM_END if from_vram => Some(self.byte),
+            CART_START..=CART_END | EXTERNAL_START..=ECHO_END if !from_vram => Some(self.byte),
+            OAM_START..=PROHIBITED_END => Some(0xFF),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bus::{Bus, Memory},
+        cartridge::{cdl::ReadKind, Cartridge},
+    };
+
+    fn run(bus: &mut Bus, cycles: usize) {
+        for _ in 0..cycles {
+            bus.tick_m_cycle();
+        }
+    }
+
+    #[test]
+    fn test_dma_timing_and_conflicts() {
+        let mut bus = Bus::new(Cartridge::new(vec![0; 0x8000]).unwrap());
+        bus.write(0xFF40, 0x00);
+        for idx in 0..0x100 {
+            bus.write(0xC000 + idx, (idx as u8).wrapping_add(1));
+            bus.write(0xC100 + idx, 0xFF - idx as u8);
+        }
+        bus.write(0xFF80, 0x42);
+
+        bus.write(0xFF46, 0xC0);
+        run(&mut bus, 1);
+        assert!(!bus.dma.is_active());
+        assert_eq!(bus.read_as(0xC005, ReadKind::Data), 0x06);
+
+        // startup done, the first byte is copied on the next M-cycle
+        run(&mut bus, 1);
+        assert!(bus.dma.is_active());
+        run(&mut bus, 1);
+        assert_eq!(bus.ppu.oam[0].y_pos, 0x01);
+        assert_eq!(bus.read_as(0x0150, ReadKind::Opcode), 0x01);
+        assert_eq!(bus.read_as(0xC005, ReadKind::Data), 0x01);
+        assert_eq!(bus.read_as(0xFE00, ReadKind::Data), 0xFF);
+        assert_eq!(bus.read_as(0xFF80, ReadKind::Data), 0x42);
+        assert_eq!(bus.read(0xFF46), 0xC0);
+
+        // restart from 0xE1, echo of 0xC1, the old transfer runs on meanwhile
+        run(&mut bus, 49);
+        bus.write(0xFF46, 0xE1);
+        run(&mut bus, 2);
+        assert_eq!(bus.ppu.oam[12].flags, 52);
+        assert_eq!(bus.ppu.oam[0].y_pos, 0x01);
+        run(&mut bus, 1);
+        assert_eq!(bus.ppu.oam[0].y_pos, 0xFF);
+        assert_eq!(bus.ppu.oam[13].y_pos, 0x00);
+
+        run(&mut bus, 159);
+        assert!(!bus.dma.is_active());
+        assert_eq!(bus.ppu.oam[39].flags, 0xFF - 159);
+        assert_eq!(bus.read_as(0xC005, ReadKind::Data), 0x06);
+    }
+
+    #[test]
+    fn test_vram_dma_leaves_the_external_bus_alone() {
+        let mut bus = Bus::new(Cartridge::new(vec![0; 0x8000]).unwrap());
+        bus.write(0xFF40, 0x00);
+        bus.write(0x8000, 0x11);
+        bus.write(0x8005, 0x22);
+        bus.write(0xC005, 0x33);
+
+        bus.write(0xFF46, 0x80);
+        run(&mut bus, 3);
+        assert!(bus.dma.is_active());
+        assert_eq!(bus.read_as(0xC005, ReadKind::Data), 0x33);
+        assert_eq!(bus.read_as(0x0150, ReadKind::Opcode), 0x00);
+        assert_eq!(bus.read_as(0x8005, ReadKind::Data), 0x11);
+        assert_eq!(bus.read_as(0xFE00, ReadKind::Data), 0xFF);
+    }
+}
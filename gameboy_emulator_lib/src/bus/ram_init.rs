@@ -0,0 +1,105 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// # Power-on RAM contents
+/// Real hardware powers on with whatever the RAM cells settle to. Filling
+/// memory with garbage shows reads of uninitialized memory that a zeroed
+/// emulator hides.
+///
+/// Random patterns are seeded, the same seed gives the same memory
+/// for a given build.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum RamInit {
+    #[default]
+    Zero,
+    /// Every byte 0xFF
+    Ones,
+    /// Uniformly random WRAM, HRAM, VRAM and OAM
+    Random(u64),
+    /// Close to what a DMG shows
+    /// - WRAM in 16 byte rows that are mostly 0x00 or 0xFF, with some bits flipped
+    /// - HRAM and OAM random
+    /// - VRAM zero, the boot rom clears it
+    DmgLike(u64),
+}
+
+/// Memories that get filled at power on
+pub struct PowerOnRam<'a> {
+    pub wram: &'a mut [u8],
+    pub hram: &'a mut [u8],
+    pub vram: &'a mut [u8],
+    pub oam: &'a mut [u8],
+}
+
+impl RamInit {
+    pub fn fill(&self, ram: PowerOnRam) {
+        match *self {
+            RamInit::Zero => ram.fill_all(0x00),
+            RamInit::Ones => ram.fill_all(0xFF),
+            RamInit::Random(seed) => {
+                let mut rng = StdRng::seed_from_u64(seed);
+                rng.fill(ram.wram);
+                rng.fill(ram.hram);
+                rng.fill(ram.vram);
+                rng.fill(ram.oam);
+            }
+            RamInit::DmgLike(seed) => {
+                let mut rng = StdRng::seed_from_u64(seed);
+
+                for row in ram.wram.chunks_mut(16) {
+                    let base = if rng.gen::<bool>() { 0xFF } else { 0x00 };
+
+                    for byte in row.iter_mut() {
+                        // roughly one bit in 16 differs from the row
+                        let flips =
+                            rng.gen::<u8>() & rng.gen::<u8>() & rng.gen::<u8>() & rng.gen::<u8>();
+                        *byte = base ^ flips;
+                    }
+                }
+
+                rng.fill(ram.hram);
+                rng.fill(ram.oam);
+                ram.vram.fill(0x00);
+            }
+        }
+    }
+}
+
+impl PowerOnRam<'_> {
+    fn fill_all(self, byte: u8) {
+        self.wram.fill(byte);
+        self.hram.fill(byte);
+        self.vram.fill(byte);
+        self.oam.fill(byte);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PowerOnRam, RamInit};
+
+    fn filled(init: RamInit) -> Vec<u8> {
+        let (mut wram, mut hram, mut vram, mut oam) =
+            ([0; 0x2000], [0; 0x7F], [0; 0x2000], [0; 0xA0]);
+
+        init.fill(PowerOnRam {
+            wram: &mut wram,
+            hram: &mut hram,
+            vram: &mut vram,
+            oam: &mut oam,
+        });
+
+        [&wram[..], &hram, &vram, &oam].concat()
+    }
+
+    #[test]
+    fn test_seeded_patterns_are_reproducible() {
+        assert_eq!(filled(RamInit::Random(7)), filled(RamInit::Random(7)));
+        assert_ne!(filled(RamInit::Random(7)), filled(RamInit::Random(8)));
+        assert_eq!(filled(RamInit::DmgLike(7)), filled(RamInit::DmgLike(7)));
+        assert!(filled(RamInit::Ones).iter().all(|byte| *byte == 0xFF));
+
+        let dmg = filled(RamInit::DmgLike(1));
+        let vram = &dmg[0x2000 + 0x7F..0x4000 + 0x7F];
+        assert!(vram.iter().all(|byte| *byte == 0));
+    }
+}
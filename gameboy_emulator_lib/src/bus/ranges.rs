@@ -22,6 +22,12 @@ pub const OAM_END: u16 = 0xFE9F;
 pub const OAM_SIZE: usize = (OAM_END - OAM_START + 1) as usize;
 pub const OAM_COUNT: usize = 40;
 
+pub const PROHIBITED_START: u16 = 0xFEA0;
+pub const PROHIBITED_END: u16 = 0xFEFF;
+
+pub const IO_START: u16 = 0xFF00;
+pub const IO_END: u16 = 0xFF7F;
+
 pub const JOYPAD: u16 = 0xFF00;
 
 pub const SERIAL_START: u16 = 0xFF01;
@@ -30,6 +36,8 @@ pub const SERIAL_END: u16 = 0xFF02;
 pub const TIMER_START: u16 = 0xFF04;
 pub const TIMER_END: u16 = 0xFF07;
 
+pub const DMA: u16 = 0xFF46;
+
 pub const LCD_START: u16 = 0xFF40;
 pub const LCD_END: u16 = 0xFF4B;
 pub const LCD_SIZE: usize = (LCD_END - LCD_START + 1) as usize;
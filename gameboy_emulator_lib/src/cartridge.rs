@@ -1,23 +1,39 @@
-use crate::bus::Memory;
+use std::cell::{Ref, RefCell};
 
-use self::header::CartridgeHeader;
+use crate::{bus::Memory, cheats::RomPatch, error::EmuError};
 
+use self::{
+    cdl::{CodeDataLog, ReadKind},
+    header::CartridgeHeader,
+};
+
+pub mod cdl;
 mod header;
 
+#[derive(Clone)]
 pub struct Cartridge {
     pub header: CartridgeHeader,
     pub data: Vec<u8>,
     pub bank0: Vec<u8>,
     pub bankn: Vec<u8>,
+    /// Game Genie codes, applied on every read
+    patches: Vec<RomPatch>,
+    /// Filled by cpu and DMA reads while a log is set
+    cdl: Option<RefCell<CodeDataLog>>,
 }
 
 impl Memory for Cartridge {
     fn read(&self, address: u16) -> u8 {
-        match address {
+        let byte = match address {
             0x0000..=0x3FFF => self.bank0[address as usize],
             0x4000..=0x7FFF => self.bankn[(address - 0x4000) as usize],
-            _ => panic!("invalid cart memory accessed"),
-        }
+            _ => unreachable!("cartridge rom is only mapped at 0x0000 - 0x7FFF"),
+        };
+
+        self.patches
+            .iter()
+            .filter(|patch| patch.address == address)
+            .fold(byte, |byte, patch| patch.apply(byte))
     }
 
     fn write(&mut self, _address: u16, _byte: u8) {}
@@ -26,12 +42,71 @@ impl Memory for Cartridge {
 impl Cartridge {
     pub const BANK_N_START: u16 = 0x4000;
 
-    pub fn new(data: Vec<u8>) -> Self {
-        Cartridge {
-            header: CartridgeHeader::new(&data[0x0100..=0x14F0]),
+    /// Smallest rom, two 16 KiB banks
+    pub const MIN_ROM_SIZE: usize = 0x8000;
+
+    pub fn new(data: Vec<u8>) -> Result<Self, EmuError> {
+        if data.len() < Self::MIN_ROM_SIZE {
+            return Err(EmuError::InvalidRom(format!(
+                "rom is {} bytes, expected at least {}",
+                data.len(),
+                Self::MIN_ROM_SIZE
+            )));
+        }
+
+        Ok(Cartridge {
+            header: CartridgeHeader::new(&data[0x0100..=0x014F]),
             bankn: data[0x4000..=0x7FFF].to_vec(),
             bank0: data[0x0000..=0x3FFF].to_vec(),
             data,
-        }
+            patches: vec![],
+            cdl: None,
+        })
+    }
+
+    /// ROM bank mapped at 0x4000 - 0x7FFF.
+    /// Without MBC support this is always bank 1.
+    pub fn rom_bank(&self) -> usize {
+        1
+    }
+
+    /// Cartridge RAM bank mapped at 0xA000 - 0xBFFF, always 0 for now
+    pub fn ram_bank(&self) -> usize {
+        0
+    }
+
+    pub fn set_patches(&mut self, patches: Vec<RomPatch>) {
+        self.patches = patches;
+    }
+
+    /// Starts logging how ROM is read, or stops with `None`
+    pub fn set_cdl(&mut self, cdl: Option<CodeDataLog>) {
+        self.cdl = cdl.map(RefCell::new);
+    }
+
+    pub fn cdl(&self) -> Option<Ref<'_, CodeDataLog>> {
+        self.cdl.as_ref().map(|cdl| cdl.borrow())
+    }
+
+    pub fn take_cdl(&mut self) -> Option<CodeDataLog> {
+        self.cdl.take().map(RefCell::into_inner)
+    }
+
+    /// Logs a read of the mapped ROM byte at `address` in the CDL
+    pub fn log_read(&self, address: u16, kind: ReadKind) {
+        let Some(cdl) = &self.cdl else { return };
+
+        let bank = match address {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank(),
+        };
+        cdl.borrow_mut()
+            .mark(bank * 0x4000 + (address as usize & 0x3FFF), kind);
+    }
+
+    /// Reads a byte of any ROM bank, mapped or not
+    pub fn read_banked(&self, bank: usize, address: u16) -> Option<u8> {
+        let offset = bank * 0x4000 + (address as usize & 0x3FFF);
+        self.data.get(offset).copied()
     }
 }
@@ -0,0 +1,165 @@
+/// What the cpu or a DMA transfer reads a byte for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadKind {
+    /// First byte of an instruction, or the byte after a 0xCB prefix
+    Opcode,
+    /// Immediate value or address of an instruction
+    Operand,
+    /// Read by an instruction, e.g. `LD A, (HL)` or `POP`
+    Data,
+    /// Source of a DMA transfer. The DMG only has OAM DMA, CGB VRAM DMA
+    /// would be logged the same way
+    Dma,
+}
+
+impl ReadKind {
+    pub fn flag(&self) -> u8 {
+        match self {
+            ReadKind::Opcode => 0x01,
+            ReadKind::Operand => 0x02,
+            ReadKind::Data => 0x04,
+            ReadKind::Dma => 0x08,
+        }
+    }
+}
+
+/// # Code/Data Logger
+/// One byte of flags per ROM byte, `ReadKind::flag` ORed together for every
+/// way the byte was read. Indexed by ROM offset, `bank * 0x4000 + address`,
+/// so the same address in two banks is logged separately.
+///
+/// The file format is the flags as they are, as long as the ROM. Logs of
+/// several play sessions combine with `merge`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CodeDataLog {
+    flags: Vec<u8>,
+}
+
+impl CodeDataLog {
+    /// An empty log for a ROM of `rom_size` bytes
+    pub fn new(rom_size: usize) -> Self {
+        CodeDataLog {
+            flags: vec![0; rom_size],
+        }
+    }
+
+    /// Reads a CDL file written for a ROM of `rom_size` bytes
+    pub fn from_bytes(bytes: &[u8], rom_size: usize) -> Result<Self, String> {
+        if bytes.len() != rom_size {
+            return Err(format!(
+                "CDL file is {} bytes, the rom {} bytes",
+                bytes.len(),
+                rom_size
+            ));
+        }
+
+        Ok(CodeDataLog {
+            flags: bytes.to_vec(),
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.flags
+    }
+
+    pub fn len(&self) -> usize {
+        self.flags.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flags.is_empty()
+    }
+
+    /// Adds the flags of another session with the same ROM
+    pub fn merge(&mut self, other: &CodeDataLog) -> Result<(), String> {
+        if other.len() != self.len() {
+            return Err(format!(
+                "can't merge a CDL of {} bytes into one of {} bytes",
+                other.len(),
+                self.len()
+            ));
+        }
+
+        for (flags, other) in self.flags.iter_mut().zip(&other.flags) {
+            *flags |= other;
+        }
+        Ok(())
+    }
+
+    pub fn mark(&mut self, offset: usize, kind: ReadKind) {
+        if let Some(flags) = self.flags.get_mut(offset) {
+            *flags |= kind.flag();
+        }
+    }
+
+    /// Flags of the byte at `offset`, 0 when it was never read
+    pub fn flags(&self, offset: usize) -> u8 {
+        self.flags.get(offset).copied().unwrap_or_default()
+    }
+
+    /// How many bytes were read as `kind`
+    pub fn count(&self, kind: ReadKind) -> usize {
+        self.flags
+            .iter()
+            .filter(|flags| *flags & kind.flag() != 0)
+            .count()
+    }
+
+    /// How many bytes were never read
+    pub fn unused(&self) -> usize {
+        self.flags.iter().filter(|flags| **flags == 0).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CodeDataLog, ReadKind};
+    use crate::{bus::Memory, cartridge::Cartridge, emu::EmuContext, utils::Opts};
+
+    #[test]
+    fn test_cdl() {
+        let mut rom = vec![0; 0x8000];
+        // 0x100: ld a, ($4123) ; jr -5
+        rom[0x100..0x105].copy_from_slice(&[0xFA, 0x23, 0x41, 0x18, 0xFB]);
+        let mut ctx = EmuContext::new(Cartridge::new(rom).unwrap(), Opts::new(false, false));
+        ctx.bus
+            .borrow_mut()
+            .cartridge
+            .set_cdl(Some(CodeDataLog::new(0x8000)));
+
+        ctx.step().unwrap();
+        ctx.step().unwrap();
+        // OAM DMA from 0x4500, the write's M-cycle, startup and 160 bytes
+        ctx.bus.borrow_mut().write(0xFF46, 0x45);
+        for _ in 0..162 {
+            ctx.bus.borrow_mut().tick_m_cycle();
+        }
+        // tools don't leave a trace
+        ctx.bus.borrow().read(0x0200);
+
+        let cdl = ctx.bus.borrow_mut().cartridge.take_cdl().unwrap();
+        let (opcode, operand) = (ReadKind::Opcode.flag(), ReadKind::Operand.flag());
+        assert_eq!(cdl.flags(0x100), opcode);
+        assert_eq!(cdl.flags(0x102), operand);
+        assert_eq!(cdl.flags(0x103), opcode);
+        assert_eq!(cdl.flags(0x104), operand);
+        // bank 1 is mapped at 0x4000
+        assert_eq!(cdl.flags(0x4123), ReadKind::Data.flag());
+        assert_eq!(cdl.flags(0x4500), ReadKind::Dma.flag());
+        assert_eq!(cdl.flags(0x459F), ReadKind::Dma.flag());
+        assert_eq!(cdl.flags(0x0200), 0);
+        assert_eq!(cdl.count(ReadKind::Opcode), 2);
+        assert_eq!(cdl.count(ReadKind::Dma), 160);
+
+        let mut other = CodeDataLog::new(0x8000);
+        other.mark(0x4123, ReadKind::Operand);
+        other.merge(&cdl).unwrap();
+        assert_eq!(other.flags(0x4123), 0x06);
+        assert_eq!(other.unused(), 0x8000 - 166);
+
+        let bytes = other.as_bytes().to_vec();
+        assert_eq!(CodeDataLog::from_bytes(&bytes, 0x8000), Ok(other));
+        assert!(CodeDataLog::from_bytes(&bytes, 0x10000).is_err());
+        assert!(CodeDataLog::new(0x10000).merge(&cdl).is_err());
+    }
+}
@@ -1,3 +1,4 @@
+#[derive(Clone)]
 pub struct CartridgeHeader {
     data: Vec<u8>,
     title: String,
@@ -21,6 +22,20 @@ impl CartridgeHeader {
         }
     }
 
+    /// Raw title bytes ( 0x134 - 0x143 )
+    pub fn title_bytes(&self) -> [u8; 16] {
+        let start = (0x134 - HEADER_START) as usize;
+        let mut title = [0; 16];
+        title.copy_from_slice(&self.data[start..start + 16]);
+        title
+    }
+
+    /// Checksum of the whole rom, stored big endian at 0x14E - 0x14F
+    pub fn global_checksum(&self) -> u16 {
+        let start = (0x14E - HEADER_START) as usize;
+        u16::from_be_bytes([self.data[start], self.data[start + 1]])
+    }
+
     pub fn print(&self) {
         println!("Title: {}", self.title);
     }
@@ -0,0 +1,340 @@
+use std::{collections::BTreeMap, str::FromStr};
+
+use crate::bus::{
+    ranges::{EXTERNAL_END, EXTERNAL_START},
+    Bus,
+};
+
+/// GameShark codes with this bank write to whatever cartridge RAM bank is mapped
+const GAMESHARK_ANY_BANK: u8 = 0x01;
+
+/// # Cheat codes
+/// - Game Genie, `ABC-DEF` or `ABC-DEF-GHI`: replaces the ROM byte at an
+///   address. With the third group the byte is only replaced while ROM holds
+///   the compare byte, which keeps the code to a single bank.
+/// - GameShark, `ttvvaaaa`: writes `vv` to RAM at `aaaa` ( low byte first )
+///   once every frame. `tt` is the cartridge RAM bank, `01` meaning any.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CheatCode {
+    GameGenie {
+        address: u16,
+        value: u8,
+        compare: Option<u8>,
+    },
+    GameShark {
+        bank: u8,
+        address: u16,
+        value: u8,
+    },
+}
+
+impl FromStr for CheatCode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits = s
+            .trim()
+            .chars()
+            .filter(|c| *c != '-')
+            .map(|c| c.to_digit(16).map(|digit| digit as u16))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| format!("invalid cheat code {:?}", s))?;
+
+        match digits.as_slice() {
+            [a, b, c, d, e, f, rest @ ..] if rest.is_empty() || rest.len() == 3 => {
+                // ABC-DEF-GHI: AB value, FCDE address with F inverted,
+                // GI compare rotated right by 2 and xored with 0xBA. H is not used
+                let compare = match rest {
+                    [g, _, i] => Some(((g << 4 | i) as u8).rotate_right(2) ^ 0xBA),
+                    _ => None,
+                };
+
+                Ok(CheatCode::GameGenie {
+                    address: (f ^ 0xF) << 12 | c << 8 | d << 4 | e,
+                    value: (a << 4 | b) as u8,
+                    compare,
+                })
+            }
+            [t1, t2, v1, v2, a1, a2, a3, a4] => Ok(CheatCode::GameShark {
+                bank: (t1 << 4 | t2) as u8,
+                value: (v1 << 4 | v2) as u8,
+                address: a3 << 12 | a4 << 8 | a1 << 4 | a2,
+            }),
+            _ => Err(format!("invalid cheat code {:?}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cheat {
+    pub name: String,
+    /// The codes as they were entered, `+` separated
+    pub code: String,
+    pub codes: Vec<CheatCode>,
+    pub enabled: bool,
+}
+
+impl Cheat {
+    /// Parses one or more codes joined with `+`
+    pub fn new(name: &str, code: &str) -> Result<Self, String> {
+        let codes = code
+            .split('+')
+            .map(CheatCode::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Cheat {
+            name: name.to_string(),
+            code: code.to_string(),
+            codes,
+            enabled: true,
+        })
+    }
+}
+
+/// A Game Genie code as the cartridge applies it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RomPatch {
+    pub address: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+}
+
+impl RomPatch {
+    /// The byte the CPU sees in place of `byte`
+    pub fn apply(&self, byte: u8) -> u8 {
+        match self.compare {
+            Some(compare) if compare != byte => byte,
+            _ => self.value,
+        }
+    }
+}
+
+/// # Cheats
+/// The cheat list of an `EmuContext`, ids count up from 1.
+///
+/// Changes go through `EmuContext` so the cartridge picks up Game Genie codes
+/// right away. GameShark codes are written when the PPU enters VBlank.
+#[derive(Clone, Debug, Default)]
+pub struct Cheats {
+    next_id: u32,
+    cheats: BTreeMap<u32, Cheat>,
+}
+
+impl Cheats {
+    pub fn add(&mut self, cheat: Cheat) -> u32 {
+        self.next_id += 1;
+        self.cheats.insert(self.next_id, cheat);
+        self.next_id
+    }
+
+    pub fn remove(&mut self, id: u32) -> Option<Cheat> {
+        self.cheats.remove(&id)
+    }
+
+    /// The new state, `None` for an unknown id
+    pub fn toggle(&mut self, id: u32) -> Option<bool> {
+        let cheat = self.cheats.get_mut(&id)?;
+        cheat.enabled = !cheat.enabled;
+        Some(cheat.enabled)
+    }
+
+    pub fn set_enabled(&mut self, id: u32, enabled: bool) -> bool {
+        match self.cheats.get_mut(&id) {
+            Some(cheat) => {
+                cheat.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Cheat> {
+        self.cheats.get(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &Cheat)> {
+        self.cheats.iter().map(|(id, cheat)| (*id, cheat))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cheats.is_empty()
+    }
+
+    fn enabled_codes(&self) -> impl Iterator<Item = &CheatCode> {
+        self.cheats
+            .values()
+            .filter(|cheat| cheat.enabled)
+            .flat_map(|cheat| cheat.codes.iter())
+    }
+
+    /// Game Genie codes of enabled cheats
+    pub fn rom_patches(&self) -> Vec<RomPatch> {
+        self.enabled_codes()
+            .filter_map(|code| match *code {
+                CheatCode::GameGenie {
+                    address,
+                    value,
+                    compare,
+                } => Some(RomPatch {
+                    address,
+                    value,
+                    compare,
+                }),
+                CheatCode::GameShark { .. } => None,
+            })
+            .collect()
+    }
+
+    /// Writes the GameShark codes of enabled cheats
+    pub(crate) fn apply_ram(&self, bus: &mut Bus) {
+        for code in self.enabled_codes() {
+            let CheatCode::GameShark {
+                bank,
+                address,
+                value,
+            } = *code
+            else {
+                continue;
+            };
+
+            let other_bank = (EXTERNAL_START..=EXTERNAL_END).contains(&address)
+                && bank != GAMESHARK_ANY_BANK
+                && (bank & 0x0F) as usize != bus.cartridge.ram_bank();
+
+            if !other_bank {
+                bus.write_mapped(address, value);
+            }
+        }
+    }
+}
+
+/// Reads a RetroArch style `.cht` file
+///
+/// ```text
+/// cheats = 1
+/// cheat0_desc = "Infinite lives"
+/// cheat0_code = "00A-17B-C49+01FF1ACF"
+/// cheat0_enable = true
+/// ```
+pub fn parse_cht(text: &str) -> Result<Vec<Cheat>, String> {
+    let mut entries: BTreeMap<usize, (Option<String>, Option<String>, bool)> = BTreeMap::new();
+
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected `key = value`", idx + 1))?;
+        let value = value.trim().trim_matches('"').to_string();
+
+        let Some((number, field)) = key.trim().strip_prefix("cheat").and_then(|key| {
+            let (number, field) = key.split_once('_')?;
+            Some((number.parse::<usize>().ok()?, field))
+        }) else {
+            // `cheats = N` and unknown keys
+            continue;
+        };
+
+        let entry = entries.entry(number).or_insert((None, None, false));
+        match field {
+            "desc" => entry.0 = Some(value),
+            "code" => entry.1 = Some(value),
+            "enable" => entry.2 = value == "true",
+            _ => {}
+        }
+    }
+
+    entries
+        .into_iter()
+        .map(|(number, (name, code, enabled))| {
+            let code = code.ok_or_else(|| format!("cheat{} has no code", number))?;
+            let name = name.unwrap_or_else(|| format!("cheat{}", number));
+            let mut cheat =
+                Cheat::new(&name, &code).map_err(|e| format!("cheat{}: {}", number, e))?;
+            cheat.enabled = enabled;
+            Ok(cheat)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_cht, CheatCode};
+    use crate::{bus::Memory, cartridge::Cartridge, emu::EmuContext, utils::Opts};
+
+    #[test]
+    fn test_parse_codes() {
+        assert_eq!(
+            "3EA-17B".parse::<CheatCode>(),
+            Ok(CheatCode::GameGenie {
+                address: 0x4A17,
+                value: 0x3E,
+                compare: None
+            })
+        );
+        // GI = 0xC9 -> rotated right 0x72 -> xor 0xBA = 0xC8
+        assert_eq!(
+            "00A-17B-C49".parse::<CheatCode>(),
+            Ok(CheatCode::GameGenie {
+                address: 0x4A17,
+                value: 0x00,
+                compare: Some(0xC8)
+            })
+        );
+        assert_eq!(
+            "01FF1ACF".parse::<CheatCode>(),
+            Ok(CheatCode::GameShark {
+                bank: 0x01,
+                address: 0xCF1A,
+                value: 0xFF
+            })
+        );
+        assert!("01FF1ACG".parse::<CheatCode>().is_err());
+        assert!("3EA-17".parse::<CheatCode>().is_err());
+
+        let cheats = parse_cht(
+            "cheats = 2\n\
+             cheat0_desc = \"Lives\"\n\
+             cheat0_code = \"01091ACF+3EA-17B\"\n\
+             cheat0_enable = true\n\
+             cheat1_code = \"00A-17B-C49\"\n",
+        )
+        .unwrap();
+        assert_eq!(cheats.len(), 2);
+        assert_eq!(cheats[0].name, "Lives");
+        assert_eq!(cheats[0].codes.len(), 2);
+        assert!(cheats[0].enabled);
+        assert!(!cheats[1].enabled);
+    }
+
+    #[test]
+    fn test_cheats_apply() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x4A17] = 0xC8;
+        rom[0x4A18] = 0x12;
+        // a loop, so the PPU reaches VBlank
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+        let mut ctx = EmuContext::new(Cartridge::new(rom).unwrap(), Opts::new(false, false));
+
+        let genie = ctx.add_cheat(super::Cheat::new("genie", "00A-17B-C49+3EA-18B-C49").unwrap());
+        let shark = ctx.add_cheat(super::Cheat::new("shark", "01091ACF").unwrap());
+
+        // the second code's compare byte doesn't match
+        assert_eq!(ctx.bus.borrow().read(0x4A17), 0x00);
+        assert_eq!(ctx.bus.borrow().read(0x4A18), 0x12);
+
+        ctx.run_frame().unwrap();
+        assert_eq!(ctx.bus.borrow().read(0xCF1A), 0x09);
+
+        assert_eq!(ctx.toggle_cheat(genie), Some(false));
+        assert_eq!(ctx.bus.borrow().read(0x4A17), 0xC8);
+
+        assert!(ctx.remove_cheat(shark));
+        ctx.bus.borrow_mut().write(0xCF1A, 0x01);
+        ctx.run_frame().unwrap();
+        assert_eq!(ctx.bus.borrow().read(0xCF1A), 0x01);
+    }
+}
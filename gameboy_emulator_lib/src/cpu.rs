@@ -2,14 +2,18 @@ use std::{cell::RefCell, rc::Rc};
 
 use crate::{
     bus::Memory,
-    interrupt::Interrupts,
+    cartridge::cdl::ReadKind,
+    error::EmuError,
+    interrupt::{InterruptType, Interrupts},
+    io::ppu::oam::OamBug,
+    savestate::{Savestate, SavestateError, StateReader, StateWriter},
     utils::{reset_bit, word_to_bytes},
 };
 
 use self::{operation::Operation, registers::Registers};
 
-mod operation;
-mod registers;
+pub mod operation;
+pub mod registers;
 
 pub struct CPU {
     pub registers: Registers,
@@ -18,6 +22,8 @@ pub struct CPU {
     pub ime: bool,
     pub halted: bool,
     pub enable_ime_next_cycle: bool,
+    /// Interrupt dispatched by the last `step`
+    pub last_interrupt: Option<InterruptType>,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -25,6 +31,25 @@ pub enum Cycles {
     N4 = 4,
 }
 
+impl Savestate for CPU {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.registers.save_state(state);
+        state.write_u64(self.cycles);
+        state.write_bool(self.ime);
+        state.write_bool(self.halted);
+        state.write_bool(self.enable_ime_next_cycle);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SavestateError> {
+        self.registers.load_state(state)?;
+        self.cycles = state.read_u64()?;
+        self.ime = state.read_bool()?;
+        self.halted = state.read_bool()?;
+        self.enable_ime_next_cycle = state.read_bool()?;
+        Ok(())
+    }
+}
+
 impl CPU {
     pub fn new(bus: Rc<RefCell<dyn Memory>>) -> Self {
         CPU {
@@ -34,6 +59,7 @@ impl CPU {
             ime: false,
             halted: false,
             enable_ime_next_cycle: false,
+            last_interrupt: None,
         }
     }
 
@@ -43,12 +69,13 @@ impl CPU {
         (flag & enable) > 0
     }
 
-    pub fn step(&mut self) -> u64 {
+    pub fn step(&mut self) -> Result<u64, EmuError> {
         let cur_cycles = self.cycles;
+        self.last_interrupt = None;
 
         if self.halted && !self.has_interrupt() {
             self.tick();
-            return self.cycles - cur_cycles;
+            return Ok(self.cycles - cur_cycles);
         }
 
         if self.has_interrupt() {
@@ -60,10 +87,10 @@ impl CPU {
             self.ime = false;
             self.halted = false;
         } else {
-            self.execute();
+            self.execute()?;
         }
 
-        self.cycles - cur_cycles
+        Ok(self.cycles - cur_cycles)
     }
 
     fn handle_interrupt(&mut self) {
@@ -75,7 +102,10 @@ impl CPU {
         let flag = self.bus.borrow().read(0xFF0F);
         let enable = self.bus.borrow().read(0xFFFF);
 
-        let it_type = Interrupts::interrupt_type(enable, flag);
+        // only called with an interrupt pending
+        let Some(it_type) = Interrupts::interrupt_type(enable, flag) else {
+            return;
+        };
         let address = Interrupts::interrupt_addr(it_type);
 
         self.registers.sp = self.registers.sp.wrapping_sub(1);
@@ -83,6 +113,7 @@ impl CPU {
         self.registers.sp = self.registers.sp.wrapping_sub(1);
         self.write_byte(self.registers.sp, pc_low);
         self.registers.pc = address;
+        self.last_interrupt = Some(it_type);
         self.tick();
 
         let flag_resetted = reset_bit(flag, it_type as usize);
@@ -91,6 +122,7 @@ impl CPU {
 
     pub fn tick(&mut self) {
         self.add_cycles(Cycles::N4);
+        self.bus.borrow_mut().tick_m_cycle();
 
         if self.enable_ime_next_cycle {
             self.ime = true;
@@ -102,21 +134,52 @@ impl CPU {
         self.cycles += n_cycles as u64;
     }
 
+    fn fetch_opcode(&mut self) -> u8 {
+        let byte = self
+            .bus
+            .borrow()
+            .read_as(self.registers.pc, ReadKind::Opcode);
+        self.registers.pc = self.registers.pc.wrapping_add(1);
+        self.tick();
+        byte
+    }
+
     fn fetch_byte(&mut self) -> u8 {
-        let byte = self.bus.borrow().read(self.registers.pc);
+        let byte = self
+            .bus
+            .borrow()
+            .read_as(self.registers.pc, ReadKind::Operand);
         self.registers.pc = self.registers.pc.wrapping_add(1);
         self.tick();
         byte
     }
 
     fn read_byte_bus(&mut self, addr: u16) -> u8 {
-        let byte = self.bus.borrow().read(addr);
+        let byte = self.bus.borrow().read_as(addr, ReadKind::Data);
+        self.bus.borrow_mut().oam_bug(addr, OamBug::Read);
+        self.tick();
+        byte
+    }
+
+    /// A read that also increments or decrements the pointer, POP and
+    /// LD A, (HL+) / LD A, (HL-)
+    fn read_byte_inc_dec(&mut self, addr: u16) -> u8 {
+        let byte = self.bus.borrow().read_as(addr, ReadKind::Data);
+        self.bus.borrow_mut().oam_bug(addr, OamBug::ReadIncDec);
         self.tick();
         byte
     }
 
     pub fn write_byte(&mut self, addr: u16, byte: u8) {
         self.bus.borrow_mut().write(addr, byte);
+        self.bus.borrow_mut().oam_bug(addr, OamBug::Write);
+        self.tick();
+    }
+
+    /// An internal cycle incrementing or decrementing `word`, which is put on
+    /// the address bus as well
+    fn tick_inc_dec(&mut self, word: u16) {
+        self.bus.borrow_mut().oam_bug(word, OamBug::Write);
         self.tick();
     }
 
@@ -126,21 +189,31 @@ impl CPU {
         self.write_byte(addr + 1, hi);
     }
 
-    fn execute(&mut self) {
-        let mut opcode = self.fetch_byte();
+    fn execute(&mut self) -> Result<(), EmuError> {
+        let pc = self.registers.pc;
+        let mut opcode = self.fetch_opcode();
         let prefixed = Operation::is_prefix(opcode);
 
         if prefixed {
-            opcode = self.fetch_byte();
+            opcode = self.fetch_opcode();
         }
 
         let op = Operation::get_operation(opcode, prefixed);
 
         let inst = match op {
             Some(o) => o,
-            None => panic!("Unknown opcode {}, prefixed {}", opcode, prefixed),
+            None => {
+                // stays on the opcode, stepping again reports it again
+                self.registers.pc = pc;
+                return Err(EmuError::UnknownOpcode {
+                    opcode,
+                    prefixed,
+                    pc,
+                });
+            }
         };
 
         Operation::execute(self, inst);
+        Ok(())
     }
 }
@@ -25,7 +25,7 @@ macro_rules! get_pair_src {
             ALU16Src::HL => $cpu.registers.get_reg_pair(Reg16::HL),
             ALU16Src::DE => $cpu.registers.get_reg_pair(Reg16::DE),
             ALU16Src::SP => $cpu.registers.get_reg_pair(Reg16::SP),
-            _ => panic!("invalid enum variant passesd"),
+            _ => unreachable!("invalid enum variant passesd"),
         }
     };
 }
@@ -43,14 +43,14 @@ macro_rules! set_pair {
 pub fn inc(cpu: &mut CPU, dest: ALU16Dest) {
     let word = get_pair_dest!(cpu, dest);
     let res = word.wrapping_add(1);
-    cpu.tick();
+    cpu.tick_inc_dec(word);
     set_pair!(cpu, dest, res);
 }
 
 pub fn dec(cpu: &mut CPU, dest: ALU16Dest) {
     let word = get_pair_dest!(cpu, dest);
     let res = word.wrapping_sub(1);
-    cpu.tick();
+    cpu.tick_inc_dec(word);
     set_pair!(cpu, dest, res);
 }
 
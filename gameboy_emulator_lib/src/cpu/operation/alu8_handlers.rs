@@ -74,7 +74,7 @@ macro_rules! set_reg {
                 let addr = $cpu.registers.get_reg_pair(Reg16::HL);
                 $cpu.write_byte(addr, $value)
             }
-            _ => panic!("invalid enum choice"),
+            _ => unreachable!("invalid enum choice"),
         }
     };
 }
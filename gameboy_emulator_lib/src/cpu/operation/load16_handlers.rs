@@ -1,5 +1,3 @@
-use core::panic;
-
 use crate::{
     cpu::{
         registers::{flags::FlagType, Reg16}, CPU,
@@ -68,14 +66,14 @@ pub fn ld(cpu: &mut CPU, dest: Load16Dest, src: Load16Src) {
             cpu.registers.set_reg_pair(res, Reg16::SP);
             cpu.tick();
         }
-        _ => panic!("Invalid choice of enum variant"),
+        _ => unreachable!("Invalid choice of enum variant"),
     };
 }
 
 pub fn pop(cpu: &mut CPU, dest: Load16Dest) {
-    let lo = cpu.read_byte_bus(cpu.registers.sp);
+    let lo = cpu.read_byte_inc_dec(cpu.registers.sp);
     cpu.registers.sp = cpu.registers.sp.wrapping_add(1);
-    let hi = cpu.read_byte_bus(cpu.registers.sp);
+    let hi = cpu.read_byte_inc_dec(cpu.registers.sp);
     cpu.registers.sp = cpu.registers.sp.wrapping_add(1);
 
     let word = le_bytes_to_word(lo, hi);
@@ -85,7 +83,7 @@ pub fn pop(cpu: &mut CPU, dest: Load16Dest) {
         Load16Dest::DE => cpu.registers.set_reg_pair(word, Reg16::DE),
         Load16Dest::HL => cpu.registers.set_reg_pair(word, Reg16::HL),
         Load16Dest::AF => cpu.registers.set_reg_pair(word, Reg16::AF),
-        _ => panic!("invalid choice"),
+        _ => unreachable!("invalid choice"),
     };
 }
 
@@ -95,12 +93,12 @@ pub fn push(cpu: &mut CPU, dest: Load16Dest) {
         Load16Dest::DE => cpu.registers.get_reg_pair(Reg16::DE),
         Load16Dest::HL => cpu.registers.get_reg_pair(Reg16::HL),
         Load16Dest::AF => cpu.registers.get_reg_pair(Reg16::AF),
-        _ => panic!("invalid choice"),
+        _ => unreachable!("invalid choice"),
     };
 
     let (hi, lo) = word_to_bytes(word);
 
-    cpu.tick();
+    cpu.tick_inc_dec(cpu.registers.sp);
     cpu.registers.sp = cpu.registers.sp.wrapping_sub(1);
     cpu.write_byte(cpu.registers.sp, hi);
     cpu.registers.sp = cpu.registers.sp.wrapping_sub(1);
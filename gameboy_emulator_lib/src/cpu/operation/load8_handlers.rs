@@ -29,13 +29,13 @@ macro_rules! reg_src {
             }
             Load8Src::HLI => {
                 let addr = $cpu.registers.get_reg_pair(Reg16::HL);
-                let value = $cpu.read_byte_bus(addr);
+                let value = $cpu.read_byte_inc_dec(addr);
                 $cpu.registers.set_reg_pair(addr + 1, Reg16::HL);
                 value
             }
             Load8Src::HLD => {
                 let addr = $cpu.registers.get_reg_pair(Reg16::HL);
-                let value = $cpu.read_byte_bus(addr);
+                let value = $cpu.read_byte_inc_dec(addr);
                 $cpu.registers.set_reg_pair(addr - 1, Reg16::HL);
                 value
             }
@@ -50,7 +50,7 @@ macro_rules! reg_src {
                 let addr = le_bytes_to_word(lo, hi);
                 $cpu.read_byte_bus(addr)
             }
-            _ => panic!("invalid enum variants"),
+            _ => unreachable!("invalid enum variants"),
         }
     };
 }
@@ -117,7 +117,7 @@ pub fn ld(cpu: &mut CPU, dest: Load8Dest, src: Load8Src) {
         Load8Dest::L => {
             cpu.registers.l = reg_src!(cpu, src);
         }
-        _ => panic!("Invalid enum variants passed"),
+        _ => unreachable!("Invalid enum variants passed"),
     };
 }
 
@@ -141,6 +141,6 @@ pub fn ldh(cpu: &mut CPU, dest: Load8Dest, _src: Load8Src) {
 
             cpu.registers.a = byte;
         }
-        _ => panic!("Invalid enum variant"),
+        _ => unreachable!("Invalid enum variant"),
     };
 }
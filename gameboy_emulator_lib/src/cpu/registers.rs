@@ -1,6 +1,9 @@
 use std::fmt;
 
-use crate::utils::{bytes_to_word, word_to_bytes};
+use crate::{
+    savestate::{Savestate, SavestateError, StateReader, StateWriter},
+    utils::{bytes_to_word, word_to_bytes},
+};
 
 use self::flags::Flags;
 
@@ -39,6 +42,12 @@ pub enum Reg8 {
     L,
 }
 
+impl Default for Registers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Registers {
     pub fn new() -> Registers {
         Registers {
@@ -122,6 +131,27 @@ impl Registers {
     }
 }
 
+impl Savestate for Registers {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.get_reg_pair(Reg16::AF));
+        state.write_u16(self.get_reg_pair(Reg16::BC));
+        state.write_u16(self.get_reg_pair(Reg16::DE));
+        state.write_u16(self.get_reg_pair(Reg16::HL));
+        state.write_u16(self.sp);
+        state.write_u16(self.pc);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SavestateError> {
+        self.set_reg_pair(state.read_u16()?, Reg16::AF);
+        self.set_reg_pair(state.read_u16()?, Reg16::BC);
+        self.set_reg_pair(state.read_u16()?, Reg16::DE);
+        self.set_reg_pair(state.read_u16()?, Reg16::HL);
+        self.sp = state.read_u16()?;
+        self.pc = state.read_u16()?;
+        Ok(())
+    }
+}
+
 impl fmt::Display for Registers {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -20,6 +20,12 @@ pub enum FlagType {
     Carry,
 }
 
+impl Default for Flags {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Flags {
     pub fn new() -> Flags {
         Flags {
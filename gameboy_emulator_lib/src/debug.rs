@@ -0,0 +1,423 @@
+use std::{cell::Cell, fmt, ops::RangeInclusive, rc::Rc, str::FromStr};
+
+use crate::{
+    bus::{Bus, Memory},
+    emu::EmuContext,
+    error::EmuError,
+    hooks::{HookId, MemoryAccess},
+    utils::CYCLES_1_FRAME,
+};
+
+use self::disasm::disassemble;
+
+pub mod disasm;
+pub mod profiler;
+pub mod search;
+pub mod symbols;
+
+/// # Bank-aware addresses
+/// Debugging tools name memory with 32 bit addresses, `0xBB_AAAA`
+/// - bits 0 - 15 are the CPU address
+/// - bits 16 - 23 are a ROM bank, 0 meaning whatever bank is mapped
+///
+/// `0x03_4123` is byte 0x0123 of ROM bank 3. Reads get that byte even when
+/// another bank is mapped, breakpoints only trigger while bank 3 is mapped.
+/// The bank is ignored outside of ROM ( 0x0000 - 0x7FFF ).
+///
+/// As text the bank comes first, like in RGBDS symbol files: `03:4123`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BankedAddress {
+    pub bank: Option<usize>,
+    pub address: u16,
+}
+
+impl BankedAddress {
+    pub fn new(address: u16) -> Self {
+        BankedAddress {
+            bank: None,
+            address,
+        }
+    }
+
+    pub fn with_bank(bank: usize, address: u16) -> Self {
+        BankedAddress {
+            bank: Some(bank),
+            address,
+        }
+    }
+
+    /// `address` in the bank the CPU sees right now
+    pub fn mapped(bus: &Bus, address: u16) -> Self {
+        BankedAddress {
+            bank: mapped_bank(bus, address),
+            address,
+        }
+    }
+
+    pub fn from_u32(value: u32) -> Self {
+        match (value >> 16) as usize & 0xFF {
+            0 => BankedAddress::new(value as u16),
+            bank => BankedAddress::with_bank(bank, value as u16),
+        }
+    }
+
+    pub fn to_u32(self) -> u32 {
+        (self.bank.unwrap_or_default() as u32) << 16 | self.address as u32
+    }
+
+    /// Whether the CPU sees this address right now
+    pub fn is_mapped(&self, bus: &Bus) -> bool {
+        match (self.bank, mapped_bank(bus, self.address)) {
+            (Some(bank), Some(mapped)) => bank == mapped,
+            _ => true,
+        }
+    }
+
+    /// Reads the byte, from the given bank even if it is not mapped
+    pub fn read(&self, bus: &Bus) -> u8 {
+        match (self.bank, mapped_bank(bus, self.address)) {
+            (Some(bank), Some(_)) => bus
+                .cartridge
+                .read_banked(bank, self.address)
+                .unwrap_or(0xFF),
+            _ => bus.read(self.address),
+        }
+    }
+}
+
+impl fmt::Display for BankedAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.bank {
+            Some(bank) => write!(f, "{:02X}:{:04X}", bank, self.address),
+            None => write!(f, "{:04X}", self.address),
+        }
+    }
+}
+
+/// Parses `4123`, `0x4123`, `$4123` or `03:4123`, always in hex
+impl FromStr for BankedAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |hex: &str| {
+            let hex = hex
+                .trim_start_matches("0x")
+                .trim_start_matches("0X")
+                .trim_start_matches('$');
+            u32::from_str_radix(hex, 16).map_err(|_| format!("invalid address {:?}", s))
+        };
+
+        match s.split_once(':') {
+            Some((bank, address)) => {
+                let address = parse(address)?;
+                if address > 0xFFFF {
+                    return Err(format!("invalid address {:?}", s));
+                }
+                Ok(BankedAddress::with_bank(
+                    parse(bank)? as usize,
+                    address as u16,
+                ))
+            }
+            None => Ok(BankedAddress::from_u32(parse(s)?)),
+        }
+    }
+}
+
+/// ROM bank the CPU sees at `address`, `None` outside of ROM
+pub fn mapped_bank(bus: &Bus, address: u16) -> Option<usize> {
+    match address {
+        0x0000..=0x3FFF => Some(0),
+        0x4000..=0x7FFF => Some(bus.cartridge.rom_bank()),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Breakpoint {
+    Address(BankedAddress),
+    /// Stops before any instruction starting with this byte
+    Opcode(u8),
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Breakpoint::Address(address) => write!(f, "at {}", address),
+            Breakpoint::Opcode(opcode) => write!(f, "on opcode {:02X}", opcode),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+pub struct Watchpoint {
+    pub id: u32,
+    pub kind: WatchKind,
+    pub range: RangeInclusive<u16>,
+    hooks: Vec<HookId>,
+}
+
+/// How the game continues after `Debugger::resume`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resume {
+    Continue,
+    /// One instruction
+    Step,
+    /// One instruction, a CALL or RST runs until it returns
+    Next,
+    /// Until the current subroutine returns
+    Finish,
+}
+
+#[derive(Debug)]
+pub enum StopReason {
+    Breakpoint(u32),
+    Watchpoint(u32, MemoryAccess),
+    /// A step, next or finish completed
+    Done,
+    Error(EmuError),
+}
+
+#[derive(Clone, Copy)]
+enum Target {
+    Step,
+    /// Returned to `pc` with the stack back at `sp`
+    Until {
+        pc: u16,
+        sp: u16,
+    },
+    /// A return popped the stack above `sp`
+    Finish {
+        sp: u16,
+    },
+}
+
+/// # Debugger
+/// Breakpoints, watchpoints and stepping on top of `EmuContext`.
+///
+/// The game runs in frame sized chunks through `run_frame`, so a frontend
+/// keeps drawing while it waits for a stop. Watchpoints are memory hooks
+/// and stop after the instruction that made the access.
+#[derive(Default)]
+pub struct Debugger {
+    next_id: u32,
+    breakpoints: Vec<(u32, Breakpoint)>,
+    watchpoints: Vec<Watchpoint>,
+    watch_hit: Rc<Cell<Option<(u32, MemoryAccess)>>>,
+    target: Option<Target>,
+}
+
+impl Debugger {
+    fn next_id(&mut self) -> u32 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> u32 {
+        let id = self.next_id();
+        self.breakpoints.push((id, breakpoint));
+        id
+    }
+
+    pub fn add_watchpoint(
+        &mut self,
+        ctx: &mut EmuContext,
+        kind: WatchKind,
+        range: RangeInclusive<u16>,
+    ) -> u32 {
+        let id = self.next_id();
+        let mut hooks = vec![];
+
+        if kind != WatchKind::Write {
+            let hit = self.watch_hit.clone();
+            hooks.push(
+                ctx.add_read_hook(range.clone(), move |_, access| hit.set(Some((id, access)))),
+            );
+        }
+
+        if kind != WatchKind::Read {
+            let hit = self.watch_hit.clone();
+            hooks.push(
+                ctx.add_write_hook(range.clone(), move |_, access| hit.set(Some((id, access)))),
+            );
+        }
+
+        self.watchpoints.push(Watchpoint {
+            id,
+            kind,
+            range,
+            hooks,
+        });
+        id
+    }
+
+    /// Removes a breakpoint or watchpoint, false if the id is unknown
+    pub fn remove(&mut self, ctx: &mut EmuContext, id: u32) -> bool {
+        let before = self.breakpoints.len() + self.watchpoints.len();
+
+        self.breakpoints.retain(|(bp_id, _)| *bp_id != id);
+        self.watchpoints.retain(|watchpoint| {
+            if watchpoint.id != id {
+                return true;
+            }
+            for hook in &watchpoint.hooks {
+                ctx.remove_hook(*hook);
+            }
+            false
+        });
+
+        before != self.breakpoints.len() + self.watchpoints.len()
+    }
+
+    pub fn breakpoints(&self) -> &[(u32, Breakpoint)] {
+        &self.breakpoints
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn resume(&mut self, ctx: &EmuContext, resume: Resume) {
+        let registers = &ctx.cpu.registers;
+
+        self.target = match resume {
+            Resume::Continue => None,
+            Resume::Step => Some(Target::Step),
+            Resume::Next => {
+                let bus = ctx.bus.borrow();
+                let instruction = disassemble(|address| bus.read(address), registers.pc);
+
+                if instruction.is_call() {
+                    Some(Target::Until {
+                        pc: registers.pc.wrapping_add(instruction.len()),
+                        sp: registers.sp,
+                    })
+                } else {
+                    Some(Target::Step)
+                }
+            }
+            Resume::Finish => Some(Target::Finish { sp: registers.sp }),
+        };
+    }
+
+    /// Runs until a stop or the end of the frame, `None` means the frame ended
+    pub fn run_frame(&mut self, ctx: &mut EmuContext) -> Option<StopReason> {
+        let mut cycles = 0;
+
+        loop {
+            let returning = matches!(self.target, Some(Target::Finish { .. })) && {
+                let bus = ctx.bus.borrow();
+                disassemble(|address| bus.read(address), ctx.cpu.registers.pc).is_return()
+            };
+
+            match ctx.step() {
+                Ok(n) => cycles += n,
+                Err(e) => return self.stop(StopReason::Error(e)),
+            }
+
+            if let Some((id, access)) = self.watch_hit.take() {
+                return self.stop(StopReason::Watchpoint(id, access));
+            }
+
+            let registers = &ctx.cpu.registers;
+            let done = match self.target {
+                Some(Target::Step) => true,
+                Some(Target::Until { pc, sp }) => registers.pc == pc && registers.sp >= sp,
+                Some(Target::Finish { sp }) => returning && registers.sp > sp,
+                None => false,
+            };
+            if done {
+                return self.stop(StopReason::Done);
+            }
+
+            if let Some(id) = self.breakpoint_hit(ctx) {
+                return self.stop(StopReason::Breakpoint(id));
+            }
+
+            if ctx.bus.borrow_mut().ppu.take_frame_ready() || cycles >= CYCLES_1_FRAME {
+                return None;
+            }
+        }
+    }
+
+    fn stop(&mut self, reason: StopReason) -> Option<StopReason> {
+        self.target = None;
+        Some(reason)
+    }
+
+    fn breakpoint_hit(&self, ctx: &EmuContext) -> Option<u32> {
+        let pc = ctx.cpu.registers.pc;
+        let bus = ctx.bus.borrow();
+
+        self.breakpoints
+            .iter()
+            .find(|(_, breakpoint)| match breakpoint {
+                Breakpoint::Address(address) => address.address == pc && address.is_mapped(&bus),
+                Breakpoint::Opcode(opcode) => bus.read(pc) == *opcode,
+            })
+            .map(|(id, _)| *id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BankedAddress, Breakpoint, Debugger, Resume, StopReason, WatchKind};
+    use crate::{cartridge::Cartridge, emu::EmuContext, utils::Opts};
+
+    #[test]
+    fn test_next_finish_and_stops() {
+        let mut rom = vec![0; 0x8000];
+        // 0x100: call 0x200 ; nop ; ld (0xC000), a ; jr -2
+        rom[0x100..0x108].copy_from_slice(&[0xCD, 0x00, 0x02, 0x00, 0xEA, 0x00, 0xC0, 0x18]);
+        rom[0x108] = 0xFE;
+        // 0x200: nop ; nop ; ret
+        rom[0x200..0x203].copy_from_slice(&[0x00, 0x00, 0xC9]);
+        let mut ctx = EmuContext::new(Cartridge::new(rom).unwrap(), Opts::new(false, false));
+        let mut debugger = Debugger::default();
+
+        debugger.resume(&ctx, Resume::Next);
+        assert!(matches!(
+            debugger.run_frame(&mut ctx),
+            Some(StopReason::Done)
+        ));
+        assert_eq!(ctx.cpu.registers.pc, 0x103);
+
+        ctx.cpu.registers.pc = 0x100;
+        debugger.resume(&ctx, Resume::Step);
+        debugger.run_frame(&mut ctx);
+        assert_eq!(ctx.cpu.registers.pc, 0x200);
+
+        debugger.resume(&ctx, Resume::Finish);
+        assert!(matches!(
+            debugger.run_frame(&mut ctx),
+            Some(StopReason::Done)
+        ));
+        assert_eq!(ctx.cpu.registers.pc, 0x103);
+
+        let watch = debugger.add_watchpoint(&mut ctx, WatchKind::Write, 0xC000..=0xC000);
+        debugger.resume(&ctx, Resume::Continue);
+        assert!(matches!(
+            debugger.run_frame(&mut ctx),
+            Some(StopReason::Watchpoint(id, access)) if id == watch && access.address == 0xC000
+        ));
+        assert!(debugger.remove(&mut ctx, watch));
+
+        let jr = debugger.add_breakpoint(Breakpoint::Opcode(0x18));
+        assert!(
+            matches!(debugger.run_frame(&mut ctx), Some(StopReason::Breakpoint(id)) if id == jr)
+        );
+        debugger.remove(&mut ctx, jr);
+
+        // bank 2 is never mapped without an MBC
+        debugger.add_breakpoint(Breakpoint::Address(BankedAddress::with_bank(2, 0x4000)));
+        let unbanked = debugger.add_breakpoint(Breakpoint::Address(BankedAddress::new(0x107)));
+        assert!(
+            matches!(debugger.run_frame(&mut ctx), Some(StopReason::Breakpoint(id)) if id == unbanked)
+        );
+    }
+}
@@ -0,0 +1,355 @@
+use crate::cpu::operation::{opcodes::*, Operation};
+
+const PREFIX_INST: u8 = 0xCB;
+
+/// A decoded instruction
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instruction {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub operation: Option<Operation>,
+    pub text: String,
+}
+
+impl Instruction {
+    pub fn len(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// CALL and RST push a return address, `next` steps over them
+    pub fn is_call(&self) -> bool {
+        matches!(
+            self.operation,
+            Some(Operation::Jump(JumpOp::CALL(_) | JumpOp::RST(_)))
+        )
+    }
+
+    pub fn is_return(&self) -> bool {
+        matches!(
+            self.operation,
+            Some(Operation::Jump(JumpOp::RET(_) | JumpOp::RETI))
+        )
+    }
+}
+
+/// Decodes the instruction at `address` with the same opcode table the CPU
+/// uses. Immediates are shown in hex, relative jumps as their target.
+/// Unknown opcodes come out as `DB $xx`.
+pub fn disassemble<F>(read: F, address: u16) -> Instruction
+where
+    F: Fn(u16) -> u8,
+{
+    disassemble_with_labels(read, address, |_| None)
+}
+
+/// Like `disassemble`, jump targets and memory operands are shown as the
+/// name `label` returns for them
+pub fn disassemble_with_labels<F, L>(read: F, address: u16, label: L) -> Instruction
+where
+    F: Fn(u16) -> u8,
+    L: Fn(u16) -> Option<String>,
+{
+    let opcode = read(address);
+    let prefixed = opcode == PREFIX_INST;
+    let (opcode, start) = if prefixed {
+        (read(address.wrapping_add(1)), 2)
+    } else {
+        (opcode, 1)
+    };
+
+    let Some(operation) = Operation::get_operation(opcode, prefixed) else {
+        return Instruction {
+            address,
+            bytes: vec![read(address)],
+            operation: None,
+            text: format!("DB ${:02X}", read(address)),
+        };
+    };
+
+    let length = start + immediate_len(operation);
+    let bytes = (0..length)
+        .map(|offset| read(address.wrapping_add(offset)))
+        .collect::<Vec<_>>();
+
+    let imm8 = bytes.get(start as usize).copied().unwrap_or_default();
+    let imm16 = u16::from_le_bytes([
+        imm8,
+        bytes.get(start as usize + 1).copied().unwrap_or_default(),
+    ]);
+    let next = address.wrapping_add(length);
+
+    Instruction {
+        address,
+        text: format_operation(operation, imm8, imm16, next, &label),
+        bytes,
+        operation: Some(operation),
+    }
+}
+
+/// Disassembles `before` instructions in front of `address`, the one at
+/// `address` and `after` following ones.
+///
+/// Instructions have different lengths, so the start is guessed: the furthest
+/// start whose instructions line up with `address` wins.
+pub fn disassemble_around<F, L>(
+    read: F,
+    label: L,
+    address: u16,
+    before: usize,
+    after: usize,
+) -> Vec<Instruction>
+where
+    F: Fn(u16) -> u8,
+    L: Fn(u16) -> Option<String>,
+{
+    let mut start = address;
+
+    for distance in (1..=before as u16 * 3).rev() {
+        let candidate = address.wrapping_sub(distance);
+        let mut cursor = candidate;
+        let mut count = 0;
+
+        while cursor != address && count <= before {
+            let len = disassemble(&read, cursor).len();
+            if cursor.wrapping_add(len).wrapping_sub(candidate) > distance {
+                break;
+            }
+            cursor = cursor.wrapping_add(len);
+            count += 1;
+        }
+
+        if cursor == address && count <= before {
+            start = candidate;
+            break;
+        }
+    }
+
+    let mut instructions = vec![];
+    let mut cursor = start;
+
+    while cursor != address {
+        let instruction = disassemble_with_labels(&read, cursor, &label);
+        cursor = cursor.wrapping_add(instruction.len());
+        instructions.push(instruction);
+    }
+
+    for _ in 0..=after {
+        let instruction = disassemble_with_labels(&read, cursor, &label);
+        cursor = cursor.wrapping_add(instruction.len());
+        instructions.push(instruction);
+    }
+
+    instructions
+}
+
+fn immediate_len(operation: Operation) -> u16 {
+    match operation {
+        Operation::Misc(MiscOp::STOP) => 1,
+        Operation::Load8(Load8Op::LD(dest, src) | Load8Op::LDH(dest, src)) => match (dest, src) {
+            (Load8Dest::Addr16Bit, _) | (_, Load8Src::Addr16Bit) => 2,
+            (Load8Dest::Unsigned8, _) | (_, Load8Src::Unsigned8 | Load8Src::Direct8Bit) => 1,
+            _ => 0,
+        },
+        Operation::Load16(Load16Op::LD(dest, src)) => match (dest, src) {
+            (Load16Dest::Addr16Bit, _) | (_, Load16Src::Direct16Bit) => 2,
+            (_, Load16Src::SPr8) => 1,
+            _ => 0,
+        },
+        Operation::ALU16(ALU16Op::ADD(_, ALU16Src::Signed8)) => 1,
+        Operation::ALU8(
+            ALU8Op::SUB(ALU8Dest::Direct8Bit)
+            | ALU8Op::AND(ALU8Dest::Direct8Bit)
+            | ALU8Op::XOR(ALU8Dest::Direct8Bit)
+            | ALU8Op::OR(ALU8Dest::Direct8Bit)
+            | ALU8Op::CP(ALU8Dest::Direct8Bit)
+            | ALU8Op::ADD(_, ALU8Src::Direct8Bit)
+            | ALU8Op::ADC(_, ALU8Src::Direct8Bit)
+            | ALU8Op::SBC(_, ALU8Src::Direct8Bit),
+        ) => 1,
+        Operation::Jump(JumpOp::JR(_)) => 1,
+        Operation::Jump(JumpOp::JP(_) | JumpOp::CALL(_)) => 2,
+        _ => 0,
+    }
+}
+
+fn format_operation(
+    operation: Operation,
+    imm8: u8,
+    imm16: u16,
+    next: u16,
+    label: &dyn Fn(u16) -> Option<String>,
+) -> String {
+    let address = |address: u16| label(address).unwrap_or_else(|| format!("${:04X}", address));
+
+    let signed = |byte: u8| {
+        let value = byte as i8;
+        if value < 0 {
+            format!("-${:02X}", value.unsigned_abs())
+        } else {
+            format!("+${:02X}", value)
+        }
+    };
+
+    let load8_dest = |dest: Load8Dest| match dest {
+        Load8Dest::AddrC => "($FF00+C)".to_string(),
+        Load8Dest::Unsigned8 => format!("({})", address(0xFF00 | imm8 as u16)),
+        Load8Dest::Addr16Bit => format!("({})", address(imm16)),
+        Load8Dest::BC => "(BC)".to_string(),
+        Load8Dest::DE => "(DE)".to_string(),
+        Load8Dest::HL => "(HL)".to_string(),
+        Load8Dest::HLI => "(HL+)".to_string(),
+        Load8Dest::HLD => "(HL-)".to_string(),
+        other => format!("{:?}", other),
+    };
+
+    let load8_src = |src: Load8Src| match src {
+        Load8Src::AddrC => "($FF00+C)".to_string(),
+        Load8Src::Unsigned8 => format!("({})", address(0xFF00 | imm8 as u16)),
+        Load8Src::Addr16Bit => format!("({})", address(imm16)),
+        Load8Src::Direct8Bit => format!("${:02X}", imm8),
+        Load8Src::BC => "(BC)".to_string(),
+        Load8Src::DE => "(DE)".to_string(),
+        Load8Src::HL => "(HL)".to_string(),
+        Load8Src::HLI => "(HL+)".to_string(),
+        Load8Src::HLD => "(HL-)".to_string(),
+        other => format!("{:?}", other),
+    };
+
+    let alu8_dest = |dest: ALU8Dest| match dest {
+        ALU8Dest::HL => "(HL)".to_string(),
+        ALU8Dest::Direct8Bit => format!("${:02X}", imm8),
+        other => format!("{:?}", other),
+    };
+
+    let alu8_src = |src: ALU8Src| match src {
+        ALU8Src::HL => "(HL)".to_string(),
+        ALU8Src::Direct8Bit => format!("${:02X}", imm8),
+        other => format!("{:?}", other),
+    };
+
+    let condition = |condition: JumpCondition| match condition {
+        JumpCondition::NIL => String::new(),
+        other => format!("{:?}, ", other),
+    };
+
+    match operation {
+        Operation::Misc(MiscOp::PREFIX) => "PREFIX CB".to_string(),
+        Operation::Misc(op) => format!("{:?}", op),
+        Operation::Load8(Load8Op::LD(dest, src)) => {
+            format!("LD {}, {}", load8_dest(dest), load8_src(src))
+        }
+        Operation::Load8(Load8Op::LDH(dest, src)) => {
+            format!("LDH {}, {}", load8_dest(dest), load8_src(src))
+        }
+        Operation::Load16(Load16Op::LD(dest, src)) => {
+            let dest = match dest {
+                Load16Dest::Addr16Bit => format!("({})", address(imm16)),
+                other => format!("{:?}", other),
+            };
+            let src = match src {
+                Load16Src::Direct16Bit => format!("${:04X}", imm16),
+                Load16Src::SPr8 => format!("SP{}", signed(imm8)),
+                other => format!("{:?}", other),
+            };
+            format!("LD {}, {}", dest, src)
+        }
+        Operation::Load16(Load16Op::POP(dest)) => format!("POP {:?}", dest),
+        Operation::Load16(Load16Op::PUSH(dest)) => format!("PUSH {:?}", dest),
+        Operation::ALU16(ALU16Op::INC(dest)) => format!("INC {:?}", dest),
+        Operation::ALU16(ALU16Op::DEC(dest)) => format!("DEC {:?}", dest),
+        Operation::ALU16(ALU16Op::ADD(dest, src)) => match src {
+            ALU16Src::Signed8 => format!("ADD {:?}, {}", dest, signed(imm8)),
+            other => format!("ADD {:?}, {:?}", dest, other),
+        },
+        Operation::ALU8(op) => match op {
+            ALU8Op::INC(dest) => format!("INC {}", alu8_dest(dest)),
+            ALU8Op::DEC(dest) => format!("DEC {}", alu8_dest(dest)),
+            ALU8Op::SUB(dest) => format!("SUB {}", alu8_dest(dest)),
+            ALU8Op::AND(dest) => format!("AND {}", alu8_dest(dest)),
+            ALU8Op::XOR(dest) => format!("XOR {}", alu8_dest(dest)),
+            ALU8Op::OR(dest) => format!("OR {}", alu8_dest(dest)),
+            ALU8Op::CP(dest) => format!("CP {}", alu8_dest(dest)),
+            ALU8Op::ADD(dest, src) => format!("ADD {}, {}", alu8_dest(dest), alu8_src(src)),
+            ALU8Op::ADC(dest, src) => format!("ADC {}, {}", alu8_dest(dest), alu8_src(src)),
+            ALU8Op::SBC(dest, src) => format!("SBC {}, {}", alu8_dest(dest), alu8_src(src)),
+            other => format!("{:?}", other),
+        },
+        Operation::Bit(op) => match op {
+            BitOp::RLC(dest) => format!("RLC {}", dest),
+            BitOp::RRC(dest) => format!("RRC {}", dest),
+            BitOp::RL(dest) => format!("RL {}", dest),
+            BitOp::RR(dest) => format!("RR {}", dest),
+            BitOp::SLA(dest) => format!("SLA {}", dest),
+            BitOp::SRA(dest) => format!("SRA {}", dest),
+            BitOp::SWAP(dest) => format!("SWAP {}", dest),
+            BitOp::SRL(dest) => format!("SRL {}", dest),
+            BitOp::BIT(pos, dest) => format!("BIT {}, {}", u8::from(pos), dest),
+            BitOp::RES(pos, dest) => format!("RES {}, {}", u8::from(pos), dest),
+            BitOp::SET(pos, dest) => format!("SET {}, {}", u8::from(pos), dest),
+            other => format!("{:?}", other),
+        },
+        Operation::Jump(op) => match op {
+            JumpOp::RETI => "RETI".to_string(),
+            JumpOp::JPToHL => "JP HL".to_string(),
+            JumpOp::JR(c) => {
+                let target = next.wrapping_add(imm8 as i8 as u16);
+                format!("JR {}{}", condition(c), address(target))
+            }
+            JumpOp::JP(c) => format!("JP {}{}", condition(c), address(imm16)),
+            JumpOp::CALL(c) => format!("CALL {}{}", condition(c), address(imm16)),
+            JumpOp::RET(JumpCondition::NIL) => "RET".to_string(),
+            JumpOp::RET(c) => format!("RET {:?}", c),
+            JumpOp::RST(target) => format!("RST ${:02X}", target as u8),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{disassemble, disassemble_around};
+
+    fn text_at(program: &[u8], address: u16) -> String {
+        disassemble(
+            |addr| program.get(addr as usize).copied().unwrap_or(0),
+            address,
+        )
+        .text
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let program = [
+            0x3E, 0x42, // LD A, $42
+            0xEA, 0x00, 0xC0, // LD ($C000), A
+            0xE0, 0x44, // LDH ($FF44), A
+            0x20, 0xF7, // JR NZ, $0000
+            0xCB, 0x7E, // BIT 7, (HL)
+            0xF8, 0xFE, // LD HL, SP-$02
+            0xCD, 0x34, 0x12, // CALL $1234
+            0xD3, // unknown
+        ];
+
+        assert_eq!(text_at(&program, 0), "LD A, $42");
+        assert_eq!(text_at(&program, 2), "LD ($C000), A");
+        assert_eq!(text_at(&program, 5), "LDH ($FF44), A");
+        assert_eq!(text_at(&program, 7), "JR NZ, $0000");
+        assert_eq!(text_at(&program, 9), "BIT 7, (HL)");
+        assert_eq!(text_at(&program, 11), "LD HL, SP-$02");
+        assert_eq!(text_at(&program, 13), "CALL $1234");
+        assert_eq!(text_at(&program, 16), "DB $D3");
+
+        let around = disassemble_around(
+            |addr| program.get(addr as usize).copied().unwrap_or(0),
+            |_| None,
+            7,
+            2,
+            1,
+        );
+        let addresses = around.iter().map(|i| i.address).collect::<Vec<_>>();
+        assert_eq!(addresses, vec![2, 5, 7, 9]);
+    }
+}
@@ -0,0 +1,355 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+};
+
+use crate::{
+    bus::{Bus, Memory},
+    cpu::CPU,
+};
+
+use super::{symbols::Symbols, BankedAddress};
+
+/// How an instruction changes the call stack
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Flow {
+    Call,
+    Return,
+    Other,
+}
+
+impl Flow {
+    fn of(opcode: u8) -> Self {
+        match opcode {
+            // CALL and RST
+            0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC => Flow::Call,
+            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => Flow::Call,
+            // RET and RETI
+            0xC0 | 0xC8 | 0xC9 | 0xD0 | 0xD8 | 0xD9 => Flow::Return,
+            _ => Flow::Other,
+        }
+    }
+}
+
+/// CPU state before an instruction, taken by `EmuContext::step`
+pub(crate) struct Sample {
+    pc: BankedAddress,
+    sp: u16,
+    flow: Flow,
+}
+
+impl Sample {
+    pub(crate) fn take(cpu: &CPU, bus: &Bus) -> Self {
+        let pc = cpu.registers.pc;
+        let flow = if cpu.halted {
+            Flow::Other
+        } else {
+            Flow::of(bus.read(pc))
+        };
+
+        Sample {
+            pc: BankedAddress::mapped(bus, pc),
+            sp: cpu.registers.sp,
+            flow,
+        }
+    }
+}
+
+/// A call stack, stored as a tree so every step only adds to a counter
+struct Node {
+    parent: Option<usize>,
+    /// `None` for the root, code running before the first call
+    entry: Option<BankedAddress>,
+    cycles: u64,
+}
+
+/// A subroutine and the stack pointer right after it was entered
+struct Frame {
+    node: usize,
+    sp: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Routine {
+    pub entry: BankedAddress,
+    pub calls: u64,
+    /// Cycles spent in the routine itself
+    pub self_cycles: u64,
+    /// Cycles spent in the routine and everything it called
+    pub total_cycles: u64,
+}
+
+/// # Profiler
+/// Attributes CPU cycles to the instruction ( bank and PC ) and to the
+/// call stack they were spent in.
+///
+/// Subroutines start with CALL, RST or an interrupt and end with the RET or
+/// RETI that pops their return address. Games that drop return addresses
+/// themselves are handled by unwinding every frame at or below the stack
+/// pointer of a return, or of a new call.
+pub struct Profiler {
+    hotspots: HashMap<BankedAddress, u64>,
+    nodes: Vec<Node>,
+    children: HashMap<(usize, BankedAddress), usize>,
+    stack: Vec<Frame>,
+    calls: HashMap<BankedAddress, u64>,
+    total: u64,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler {
+            hotspots: HashMap::new(),
+            nodes: vec![Node {
+                parent: None,
+                entry: None,
+                cycles: 0,
+            }],
+            children: HashMap::new(),
+            stack: vec![],
+            calls: HashMap::new(),
+            total: 0,
+        }
+    }
+}
+
+impl Profiler {
+    pub(crate) fn record(&mut self, before: Sample, cycles: u64, cpu: &CPU, bus: &Bus) {
+        self.total += cycles;
+        *self.hotspots.entry(before.pc).or_default() += cycles;
+        let current = self.current();
+        self.nodes[current].cycles += cycles;
+
+        let sp = cpu.registers.sp;
+        if cpu.last_interrupt.is_some() {
+            // the interrupt ran instead of the instruction at `before.pc`
+            self.enter(BankedAddress::mapped(bus, cpu.registers.pc), sp);
+            return;
+        }
+
+        match before.flow {
+            // a call that is not taken leaves the stack alone
+            Flow::Call if sp == before.sp.wrapping_sub(2) => {
+                self.enter(BankedAddress::mapped(bus, cpu.registers.pc), sp);
+            }
+            Flow::Return if sp == before.sp.wrapping_add(2) => self.unwind(before.sp),
+            _ => {}
+        }
+    }
+
+    fn current(&self) -> usize {
+        self.stack.last().map(|frame| frame.node).unwrap_or(0)
+    }
+
+    fn enter(&mut self, entry: BankedAddress, sp: u16) {
+        self.unwind(sp);
+
+        let parent = self.current();
+        let next = self.nodes.len();
+        let node = *self.children.entry((parent, entry)).or_insert(next);
+        if node == next {
+            self.nodes.push(Node {
+                parent: Some(parent),
+                entry: Some(entry),
+                cycles: 0,
+            });
+        }
+
+        *self.calls.entry(entry).or_default() += 1;
+        self.stack.push(Frame { node, sp });
+    }
+
+    /// Drops the frames whose return address sits at or below `sp`
+    fn unwind(&mut self, sp: u16) {
+        while self.stack.last().is_some_and(|frame| frame.sp <= sp) {
+            self.stack.pop();
+        }
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.total
+    }
+
+    /// Cycles per instruction, the most expensive first
+    pub fn hotspots(&self) -> Vec<(BankedAddress, u64)> {
+        let mut hotspots = self
+            .hotspots
+            .iter()
+            .map(|(address, cycles)| (*address, *cycles))
+            .collect::<Vec<_>>();
+        hotspots.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.to_u32().cmp(&b.0.to_u32())));
+        hotspots
+    }
+
+    /// Every subroutine seen, the highest self cycles first
+    pub fn routines(&self) -> Vec<Routine> {
+        let mut routines: HashMap<BankedAddress, Routine> = HashMap::new();
+
+        for node in &self.nodes {
+            let Some(entry) = node.entry else { continue };
+            routines
+                .entry(entry)
+                .or_insert(Routine {
+                    entry,
+                    calls: self.calls.get(&entry).copied().unwrap_or_default(),
+                    self_cycles: 0,
+                    total_cycles: 0,
+                })
+                .self_cycles += node.cycles;
+        }
+
+        for (idx, node) in self.nodes.iter().enumerate() {
+            // a recursive routine counts each cycle once
+            let mut seen = HashSet::new();
+            for entry in self.path(idx) {
+                if seen.insert(entry) {
+                    if let Some(routine) = routines.get_mut(&entry) {
+                        routine.total_cycles += node.cycles;
+                    }
+                }
+            }
+        }
+
+        let mut routines = routines.into_values().collect::<Vec<_>>();
+        routines.sort_by(|a, b| {
+            b.self_cycles
+                .cmp(&a.self_cycles)
+                .then(a.entry.to_u32().cmp(&b.entry.to_u32()))
+        });
+        routines
+    }
+
+    /// Entries from the outermost call to `node`
+    fn path(&self, mut node: usize) -> Vec<BankedAddress> {
+        let mut path = vec![];
+        loop {
+            let Node { parent, entry, .. } = &self.nodes[node];
+            path.extend(*entry);
+            match parent {
+                Some(parent) => node = *parent,
+                None => break,
+            }
+        }
+        path.reverse();
+        path
+    }
+
+    /// The `count` hottest routines and instructions as a text table
+    pub fn report(&self, symbols: &Symbols, count: usize) -> String {
+        let percent = |cycles: u64| cycles as f64 * 100.0 / self.total.max(1) as f64;
+        let mut report = format!("{} cycles\n\n", self.total);
+
+        writeln!(
+            report,
+            "{:>12} {:>6} {:>12} {:>6} {:>8}  routine",
+            "self", "%", "total", "%", "calls"
+        )
+        .ok();
+        for routine in self.routines().iter().take(count) {
+            writeln!(
+                report,
+                "{:>12} {:>6.2} {:>12} {:>6.2} {:>8}  {}",
+                routine.self_cycles,
+                percent(routine.self_cycles),
+                routine.total_cycles,
+                percent(routine.total_cycles),
+                routine.calls,
+                symbols.format(routine.entry)
+            )
+            .ok();
+        }
+
+        writeln!(report, "\n{:>12} {:>6}  instruction", "cycles", "%").ok();
+        for (address, cycles) in self.hotspots().iter().take(count) {
+            writeln!(
+                report,
+                "{:>12} {:>6.2}  {}",
+                cycles,
+                percent(*cycles),
+                symbols.format(*address)
+            )
+            .ok();
+        }
+
+        report
+    }
+
+    /// One `outer;inner cycles` line per call stack, the format flamegraph
+    /// tools read. Code outside of any call is `(root)`.
+    pub fn collapsed(&self, symbols: &Symbols) -> String {
+        let mut lines = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| node.cycles > 0)
+            .map(|(idx, node)| {
+                let names = std::iter::once("(root)".to_string())
+                    .chain(
+                        self.path(idx)
+                            .into_iter()
+                            .map(|entry| symbols.format(entry)),
+                    )
+                    .collect::<Vec<_>>();
+                format!("{} {}", names.join(";"), node.cycles)
+            })
+            .collect::<Vec<_>>();
+        lines.sort();
+
+        lines.iter().fold(String::new(), |mut output, line| {
+            writeln!(output, "{}", line).ok();
+            output
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cartridge::Cartridge,
+        debug::{symbols::Symbols, BankedAddress},
+        emu::EmuContext,
+        utils::Opts,
+    };
+
+    #[test]
+    fn test_profiler() {
+        let mut rom = vec![0; 0x8000];
+        // 0x100: call 0x200 ; jr -5
+        rom[0x100..0x105].copy_from_slice(&[0xCD, 0x00, 0x02, 0x18, 0xFB]);
+        // 0x200: call 0x300 ; ret
+        rom[0x200..0x204].copy_from_slice(&[0xCD, 0x00, 0x03, 0xC9]);
+        // 0x300: nop ; ret
+        rom[0x300..0x302].copy_from_slice(&[0x00, 0xC9]);
+        let mut ctx = EmuContext::new(Cartridge::new(rom).unwrap(), Opts::new(false, false));
+
+        ctx.start_profiler();
+        // two rounds of the loop
+        for _ in 0..12 {
+            ctx.step().unwrap();
+        }
+        let profiler = ctx.take_profiler().unwrap();
+
+        let outer = BankedAddress::with_bank(0, 0x200);
+        let inner = BankedAddress::with_bank(0, 0x300);
+        let routines = profiler.routines();
+        let routine = |entry| *routines.iter().find(|r| r.entry == entry).unwrap();
+
+        // nop 4 + ret 16
+        assert_eq!(routine(inner).self_cycles, 2 * 20);
+        assert_eq!(routine(inner).calls, 2);
+        // call 24 + ret 16
+        assert_eq!(routine(outer).self_cycles, 2 * 40);
+        assert_eq!(routine(outer).total_cycles, 2 * 60);
+        assert_eq!(profiler.total_cycles(), 2 * (24 + 12) + 2 * 60);
+
+        let symbols = "00:0200 Outer\n00:0300 Inner".parse::<Symbols>().unwrap();
+        assert_eq!(
+            profiler.collapsed(&symbols),
+            "(root) 72\n(root);Outer 80\n(root);Outer;Inner 40\n"
+        );
+        assert_eq!(
+            profiler.hotspots()[0],
+            (BankedAddress::with_bank(0, 0x100), 48)
+        );
+    }
+}
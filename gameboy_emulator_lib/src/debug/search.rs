@@ -0,0 +1,303 @@
+use std::{fmt, str::FromStr};
+
+use crate::{
+    bus::{
+        ranges::{EXTERNAL_END, EXTERNAL_START, HRAM_END, HRAM_START, WRAM_END, WRAM_START},
+        Bus, Memory,
+    },
+    cheats::Cheat,
+};
+
+/// Memory a search looks at: cartridge RAM, WRAM and HRAM
+const AREAS: [(u16, u16); 3] = [
+    (EXTERNAL_START, EXTERNAL_END),
+    (WRAM_START, WRAM_END),
+    (HRAM_START, HRAM_END),
+];
+
+/// How the bytes at an address are read, 16 bit values are little endian
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValueKind {
+    U8,
+    U16,
+    /// Two decimal digits in a byte, `0x42` is 42
+    Bcd8,
+    Bcd16,
+}
+
+impl ValueKind {
+    pub fn size(&self) -> u16 {
+        match self {
+            ValueKind::U8 | ValueKind::Bcd8 => 1,
+            ValueKind::U16 | ValueKind::Bcd16 => 2,
+        }
+    }
+
+    /// `None` when the bytes are not valid BCD
+    pub fn read(&self, bus: &Bus, address: u16) -> Option<u32> {
+        let low = bus.read(address);
+        let high = || bus.read(address.wrapping_add(1));
+
+        match self {
+            ValueKind::U8 => Some(low as u32),
+            ValueKind::U16 => Some(u16::from_le_bytes([low, high()]) as u32),
+            ValueKind::Bcd8 => from_bcd(low),
+            ValueKind::Bcd16 => Some(from_bcd(high())? * 100 + from_bcd(low)?),
+        }
+    }
+
+    /// Bytes to write for `value`, low byte first
+    pub fn bytes(&self, value: u32) -> Vec<u8> {
+        match self {
+            ValueKind::U8 => vec![value as u8],
+            ValueKind::U16 => (value as u16).to_le_bytes().to_vec(),
+            ValueKind::Bcd8 => vec![to_bcd(value % 100)],
+            ValueKind::Bcd16 => vec![to_bcd(value % 100), to_bcd(value / 100 % 100)],
+        }
+    }
+}
+
+impl FromStr for ValueKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "u8" | "8" => Ok(ValueKind::U8),
+            "u16" | "16" => Ok(ValueKind::U16),
+            "bcd" | "bcd8" => Ok(ValueKind::Bcd8),
+            "bcd16" => Ok(ValueKind::Bcd16),
+            _ => Err(format!("unknown value kind {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ValueKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValueKind::U8 => write!(f, "u8"),
+            ValueKind::U16 => write!(f, "u16"),
+            ValueKind::Bcd8 => write!(f, "bcd8"),
+            ValueKind::Bcd16 => write!(f, "bcd16"),
+        }
+    }
+}
+
+fn from_bcd(byte: u8) -> Option<u32> {
+    let (high, low) = (byte >> 4, byte & 0x0F);
+    (high < 10 && low < 10).then_some(high as u32 * 10 + low as u32)
+}
+
+fn to_bcd(value: u32) -> u8 {
+    (((value / 10) << 4) | (value % 10)) as u8
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compare {
+    Equal,
+    NotEqual,
+    Greater,
+    Less,
+}
+
+impl Compare {
+    fn matches(&self, value: u32, other: u32) -> bool {
+        match self {
+            Compare::Equal => value == other,
+            Compare::NotEqual => value != other,
+            Compare::Greater => value > other,
+            Compare::Less => value < other,
+        }
+    }
+}
+
+/// What a value is compared against
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operand {
+    /// The value seen by the previous filter, or when the search started
+    Previous,
+    Value(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Candidate {
+    pub address: u16,
+    pub value: u32,
+    pub previous: u32,
+}
+
+/// # RAM search
+/// Narrows down where a game keeps a value by filtering every address of
+/// cartridge RAM, WRAM and HRAM again and again.
+///
+/// "Changed" is `NotEqual` against `Previous`, "increased" `Greater` against
+/// `Previous`. Each filter takes a new snapshot of the remaining addresses.
+pub struct RamSearch {
+    kind: ValueKind,
+    candidates: Vec<Candidate>,
+}
+
+impl RamSearch {
+    /// Every address holding a valid value is a candidate
+    pub fn new(bus: &Bus, kind: ValueKind) -> Self {
+        let candidates = AREAS
+            .iter()
+            .flat_map(|(start, end)| *start..=end - (kind.size() - 1))
+            .filter_map(|address| {
+                let value = kind.read(bus, address)?;
+                Some(Candidate {
+                    address,
+                    value,
+                    previous: value,
+                })
+            })
+            .collect();
+
+        RamSearch { kind, candidates }
+    }
+
+    pub fn kind(&self) -> ValueKind {
+        self.kind
+    }
+
+    pub fn candidates(&self) -> &[Candidate] {
+        &self.candidates
+    }
+
+    /// Keeps the addresses whose value matches, returns how many are left
+    pub fn filter(&mut self, bus: &Bus, compare: Compare, operand: Operand) -> usize {
+        let kind = self.kind;
+
+        self.candidates.retain_mut(|candidate| {
+            let Some(value) = kind.read(bus, candidate.address) else {
+                return false;
+            };
+            let other = match operand {
+                Operand::Previous => candidate.value,
+                Operand::Value(other) => other,
+            };
+
+            candidate.previous = candidate.value;
+            candidate.value = value;
+            compare.matches(value, other)
+        });
+
+        self.candidates.len()
+    }
+
+    /// A GameShark cheat holding `value` at `address`
+    pub fn cheat(&self, address: u16, value: u32) -> Cheat {
+        let code = self
+            .kind
+            .bytes(value)
+            .iter()
+            .enumerate()
+            .map(|(offset, byte)| {
+                let [low, high] = address.wrapping_add(offset as u16).to_le_bytes();
+                format!("01{:02X}{:02X}{:02X}", byte, low, high)
+            })
+            .collect::<Vec<_>>()
+            .join("+");
+
+        let name = format!("{:04X} = {}", address, value);
+        Cheat::new(&name, &code).expect("generated codes are valid")
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WatchEntry {
+    pub address: u16,
+    pub kind: ValueKind,
+    pub name: String,
+}
+
+/// Addresses shown with their current value, e.g. whenever the debugger stops
+#[derive(Clone, Debug, Default)]
+pub struct WatchList {
+    entries: Vec<WatchEntry>,
+}
+
+impl WatchList {
+    pub fn add(&mut self, address: u16, kind: ValueKind, name: &str) {
+        self.entries.push(WatchEntry {
+            address,
+            kind,
+            name: name.to_string(),
+        });
+    }
+
+    /// Removes entry `idx`, counted from 0
+    pub fn remove(&mut self, idx: usize) -> Option<WatchEntry> {
+        (idx < self.entries.len()).then(|| self.entries.remove(idx))
+    }
+
+    pub fn entries(&self) -> &[WatchEntry] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// One `name (kind) = value` line per entry, `??` for invalid BCD
+    pub fn format(&self, bus: &Bus) -> Vec<String> {
+        self.entries
+            .iter()
+            .map(|entry| {
+                let value = entry
+                    .kind
+                    .read(bus, entry.address)
+                    .map(|value| value.to_string())
+                    .unwrap_or_else(|| "??".to_string());
+                format!("{} ({}) = {}", entry.name, entry.kind, value)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Compare, Operand, RamSearch, ValueKind, WatchList};
+    use crate::{
+        bus::{Bus, Memory},
+        cartridge::Cartridge,
+        cheats::CheatCode,
+    };
+
+    #[test]
+    fn test_search() {
+        let mut bus = Bus::new(Cartridge::new(vec![0; 0x8000]).unwrap());
+        bus.write(0xC100, 3);
+        bus.write(0xFF90, 3);
+
+        let mut search = RamSearch::new(&bus, ValueKind::U8);
+        assert_eq!(search.filter(&bus, Compare::Equal, Operand::Value(3)), 2);
+
+        bus.write(0xC100, 2);
+        assert_eq!(search.filter(&bus, Compare::Less, Operand::Previous), 1);
+        assert_eq!(search.candidates()[0].address, 0xC100);
+        assert_eq!(search.candidates()[0].previous, 3);
+        assert_eq!(search.filter(&bus, Compare::Equal, Operand::Previous), 1);
+
+        let cheat = search.cheat(0xC100, 9);
+        assert_eq!(cheat.code, "010900C1");
+        assert_eq!(
+            cheat.codes,
+            vec![CheatCode::GameShark {
+                bank: 0x01,
+                address: 0xC100,
+                value: 0x09
+            }]
+        );
+
+        // 1234 as BCD, low byte first
+        bus.write(0xD000, 0x34);
+        bus.write(0xD001, 0x12);
+        let mut search = RamSearch::new(&bus, ValueKind::Bcd16);
+        assert_eq!(search.filter(&bus, Compare::Equal, Operand::Value(1234)), 1);
+        assert_eq!(ValueKind::Bcd16.bytes(1235), vec![0x35, 0x12]);
+
+        let mut watches = WatchList::default();
+        watches.add(0xD000, ValueKind::Bcd16, "score");
+        assert_eq!(watches.format(&bus), vec!["score (bcd16) = 1234"]);
+    }
+}
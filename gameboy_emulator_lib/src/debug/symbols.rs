@@ -0,0 +1,174 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+};
+
+use super::BankedAddress;
+
+/// # Symbols
+/// Labels from an RGBDS symbol file, one `BB:AAAA Label` per line.
+///
+/// Only ROM ( 0x4000 - 0x7FFF ) is looked up by bank, labels elsewhere are
+/// matched on the address alone. An address without a label of its own is
+/// named after the closest label before it in the same memory area,
+/// `Label+$XX`.
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    labels: BTreeMap<(usize, u16), String>,
+    addresses: HashMap<String, BankedAddress>,
+}
+
+impl Symbols {
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// `Label` or `Label+$XX`
+    pub fn label(&self, address: BankedAddress) -> Option<String> {
+        let key = key(address);
+
+        self.labels
+            .range(..=key)
+            .next_back()
+            .filter(|((bank, start), _)| *bank == key.0 && area(*start) == area(key.1))
+            .map(|((_, start), name)| match key.1 - start {
+                0 => name.clone(),
+                offset => format!("{}+${:02X}", name, offset),
+            })
+    }
+
+    /// Address of a label, ROM addresses come with their bank
+    pub fn resolve(&self, name: &str) -> Option<BankedAddress> {
+        self.addresses.get(name).copied()
+    }
+
+    /// The label if there is one, the address otherwise
+    pub fn format(&self, address: BankedAddress) -> String {
+        self.label(address).unwrap_or_else(|| address.to_string())
+    }
+}
+
+/// Comments start with `;`, blank lines are skipped
+impl FromStr for Symbols {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut symbols = Symbols::default();
+
+        for (idx, line) in s.lines().enumerate() {
+            let line = match line.split_once(';') {
+                Some((line, _)) => line.trim(),
+                None => line.trim(),
+            };
+            if line.is_empty() {
+                continue;
+            }
+
+            let (address, name) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| format!("line {}: expected `BB:AAAA Label`", idx + 1))?;
+            let address =
+                BankedAddress::from_str(address).map_err(|e| format!("line {}: {}", idx + 1, e))?;
+            let address = match address.address {
+                0x0000..=0x7FFF => address,
+                _ => BankedAddress::new(address.address),
+            };
+
+            let name = name.trim().to_string();
+            // the first of several labels at one address names it
+            symbols.labels.entry(key(address)).or_insert(name.clone());
+            symbols.addresses.insert(name, address);
+        }
+
+        Ok(symbols)
+    }
+}
+
+fn key(address: BankedAddress) -> (usize, u16) {
+    match address.address {
+        // ROMs without banking list their upper half as bank 0
+        0x4000..=0x7FFF => (address.bank.unwrap_or(1).max(1), address.address),
+        _ => (0, address.address),
+    }
+}
+
+/// Offsets never reach from one memory area into the next
+fn area(address: u16) -> u8 {
+    match address {
+        0x0000..=0x3FFF => 0,
+        0x4000..=0x7FFF => 1,
+        0x8000..=0x9FFF => 2,
+        0xA000..=0xBFFF => 3,
+        0xC000..=0xDFFF => 4,
+        0xE000..=0xFDFF => 5,
+        0xFE00..=0xFE9F => 6,
+        0xFF80..=0xFFFE => 7,
+        _ => 8,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Symbols;
+    use crate::debug::{disasm::disassemble_with_labels, BankedAddress};
+
+    #[test]
+    fn test_symbols() {
+        let symbols = "\
+            ; File generated by rgblink\n\
+            00:0150 Main\n\
+            00:0158 Main.loop\n\
+            02:4000 Level2Data\n\
+            00:C000 wPlayerX ; comment\n"
+            .parse::<Symbols>()
+            .unwrap();
+
+        assert_eq!(symbols.len(), 4);
+        assert_eq!(symbols.format(BankedAddress::with_bank(0, 0x150)), "Main");
+        assert_eq!(
+            symbols.format(BankedAddress::with_bank(0, 0x15A)),
+            "Main.loop+$02"
+        );
+        assert_eq!(
+            symbols.format(BankedAddress::with_bank(2, 0x4010)),
+            "Level2Data+$10"
+        );
+        // another bank, and an address before the first label of the area
+        assert_eq!(
+            symbols.format(BankedAddress::with_bank(1, 0x4010)),
+            "01:4010"
+        );
+        assert_eq!(symbols.format(BankedAddress::new(0xC001)), "wPlayerX+$01");
+        assert_eq!(symbols.format(BankedAddress::new(0xBFFF)), "BFFF");
+
+        assert_eq!(
+            symbols.resolve("Main.loop"),
+            Some(BankedAddress::with_bank(0, 0x158))
+        );
+        assert_eq!(
+            symbols.resolve("wPlayerX"),
+            Some(BankedAddress::new(0xC000))
+        );
+        assert_eq!(symbols.resolve("Missing"), None);
+
+        assert!("00:0150".parse::<Symbols>().is_err());
+        assert!("zz:0150 Main".parse::<Symbols>().is_err());
+
+        let program = [0xCD, 0x58, 0x01, 0xEA, 0x01, 0xC0];
+        let read = |address: u16| program.get(address as usize).copied().unwrap_or(0);
+        let label = |address| symbols.label(BankedAddress::with_bank(0, address));
+        assert_eq!(
+            disassemble_with_labels(read, 0, label).text,
+            "CALL Main.loop"
+        );
+        let label = |address| symbols.label(BankedAddress::new(address));
+        assert_eq!(
+            disassemble_with_labels(read, 3, label).text,
+            "LD (wPlayerX+$01), A"
+        );
+    }
+}
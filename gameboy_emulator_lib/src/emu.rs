@@ -1,55 +1,546 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, ops::RangeInclusive, rc::Rc};
 
 use crate::{
     bus::{Bus, Memory},
     cartridge::Cartridge,
+    cheats::{Cheat, Cheats},
     cpu::CPU,
-    utils::Opts,
+    debug::{
+        profiler::{Profiler, Sample},
+        symbols::Symbols,
+        BankedAddress,
+    },
+    error::EmuError,
+    hooks::{HookId, Hooks, MemoryAccess},
+    interrupt::InterruptType,
+    io::ppu::registers::Mode,
+    savestate::{
+        bess::{self, BessState},
+        Savestate, SavestateError, StateReader, StateWriter, STATE_MAGIC, STATE_VERSION,
+    },
+    utils::{bytes_to_word, Opts, CYCLES_1_FRAME},
 };
 
+/// Ways the cpu can get stuck for good
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Lockup {
+    /// Halted with no interrupt enabled that could wake it up
+    HaltForever,
+    /// `JR -2` or `JP` onto itself with interrupts off.
+    /// Test roms park here once they are done.
+    JumpToSelf,
+}
+
+/// Outcome of `EmuContext::run_frame`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameResult {
+    /// The PPU entered VBlank and `ppu.buffer` holds a new frame.
+    /// False when the lcd was off and a frame's worth of cycles ran instead.
+    pub completed: bool,
+    pub cycles: u64,
+}
+
 pub struct EmuContext {
     pub cpu: CPU,
     pub bus: Rc<RefCell<Bus>>,
     pub opts: Opts,
+    /// Labels shown in traces and debugger output
+    pub symbols: Symbols,
+    hooks: Hooks,
+    cheats: Cheats,
+    profiler: Option<Profiler>,
 }
 
 impl EmuContext {
     pub fn new(cart: Cartridge, opts: Opts) -> Self {
         let bus = Rc::new(RefCell::new(Bus::new(cart)));
+        bus.borrow_mut().init_ram(opts.ram_init);
 
         EmuContext {
             cpu: CPU::new(bus.clone()),
             bus,
             opts,
+            symbols: Symbols::default(),
+            hooks: Hooks::default(),
+            cheats: Cheats::default(),
+            profiler: None,
         }
     }
 
+    /// Power cycles the machine, keeping the inserted cartridge and options
+    pub fn reset(&mut self) {
+        let cart = self.bus.borrow().cartridge.clone();
+        let bus = Rc::new(RefCell::new(Bus::new(cart)));
+        bus.borrow_mut().init_ram(self.opts.ram_init);
+
+        self.cpu = CPU::new(bus.clone());
+        self.bus = bus;
+        self.sync_access_log();
+    }
+
     fn print_debug(&self) {
+        // the label goes last, the rest of the line stays comparable with other emulators
+        let label = self
+            .symbols
+            .label(BankedAddress::mapped(
+                &self.bus.borrow(),
+                self.cpu.registers.pc,
+            ))
+            .map(|label| format!(" {}", label))
+            .unwrap_or_default();
+
         println!(
-            "{} ({:02X} {:02X} {:02X} {:02X})",
+            "{} ({:02X} {:02X} {:02X} {:02X}){}",
             self.cpu.registers,
             self.bus.borrow().read(self.cpu.registers.pc),
-            self.bus.borrow().read(self.cpu.registers.pc + 1),
-            self.bus.borrow().read(self.cpu.registers.pc + 2),
-            self.bus.borrow().read(self.cpu.registers.pc + 3),
+            self.bus
+                .borrow()
+                .read(self.cpu.registers.pc.wrapping_add(1)),
+            self.bus
+                .borrow()
+                .read(self.cpu.registers.pc.wrapping_add(2)),
+            self.bus
+                .borrow()
+                .read(self.cpu.registers.pc.wrapping_add(3)),
+            label,
         );
     }
 
-    pub fn step(&mut self) -> u64 {
+    pub fn step(&mut self) -> Result<u64, EmuError> {
         if self.opts.show_debug_info {
             self.print_debug();
         }
 
-        let n_cycles = self.cpu.step();
+        if self.hooks.has_exec_at(self.cpu.registers.pc) {
+            let mut hooks = self.hooks.take();
+            hooks.run_exec(self, self.cpu.registers.pc);
+            self.restore_hooks(hooks);
+        }
+
+        let sample = self
+            .profiler
+            .as_ref()
+            .map(|_| Sample::take(&self.cpu, &self.bus.borrow()));
+
+        // accesses by tools in between steps don't count. Without hooks the
+        // bus records nothing, see `sync_access_log`
+        if !self.hooks.is_empty() {
+            self.bus.borrow().access_log.clear();
+        }
+        let mode = self.bus.borrow().ppu.mode();
+
+        // the cpu runs the bus along with every M-cycle
+        let n_cycles = self.cpu.step()?;
 
-        for _ in 0..n_cycles {
-            self.bus.borrow_mut().tick();
+        if let (Some(profiler), Some(sample)) = (&mut self.profiler, sample) {
+            profiler.record(sample, n_cycles, &self.cpu, &self.bus.borrow());
         }
 
-        if self.opts.show_serial_output {
-            self.bus.borrow_mut().serial.print_serial_data();
+        let mut mode_changes = vec![];
+
+        {
+            let mut bus = self.bus.borrow_mut();
+            let new_mode = bus.ppu.mode();
+
+            // the shortest mode is longer than any instruction, so there is at
+            // most one change
+            if new_mode != mode && self.hooks.watches_ppu_mode() {
+                mode_changes.push(new_mode);
+            }
+
+            if mode != Mode::VBlank && new_mode == Mode::VBlank {
+                self.cheats.apply_ram(&mut bus);
+            }
+
+            if bus.serial.update().is_some() && self.opts.show_serial_output {
+                bus.serial.print_serial_data();
+            }
+        }
+
+        let interrupt = self.cpu.last_interrupt.take();
+
+        if !self.hooks.is_empty() {
+            self.run_hooks(interrupt, mode_changes);
+        }
+
+        Ok(n_cycles)
+    }
+
+    fn run_hooks(&mut self, interrupt: Option<InterruptType>, mode_changes: Vec<Mode>) {
+        let accesses = self.bus.borrow().access_log.take();
+        let mut hooks = self.hooks.take();
+
+        if let Some(it_type) = interrupt {
+            hooks.run_interrupt(self, it_type);
+        }
+
+        for access in accesses {
+            hooks.run_memory(self, access);
+        }
+
+        for mode in mode_changes {
+            hooks.run_ppu_mode(self, mode);
+        }
+
+        self.restore_hooks(hooks);
+    }
+
+    fn restore_hooks(&mut self, hooks: Hooks) {
+        self.hooks.restore(hooks);
+        self.sync_access_log();
+    }
+
+    /// Starts attributing cycles to code, a running profile starts over
+    pub fn start_profiler(&mut self) {
+        self.profiler = Some(Profiler::default());
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Stops profiling and hands out what was collected
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    pub fn cheats(&self) -> &Cheats {
+        &self.cheats
+    }
+
+    pub fn add_cheat(&mut self, cheat: Cheat) -> u32 {
+        let id = self.cheats.add(cheat);
+        self.sync_cheats();
+        id
+    }
+
+    pub fn remove_cheat(&mut self, id: u32) -> bool {
+        let removed = self.cheats.remove(id).is_some();
+        self.sync_cheats();
+        removed
+    }
+
+    /// The new state, `None` for an unknown id
+    pub fn toggle_cheat(&mut self, id: u32) -> Option<bool> {
+        let enabled = self.cheats.toggle(id);
+        self.sync_cheats();
+        enabled
+    }
+
+    pub fn set_cheat_enabled(&mut self, id: u32, enabled: bool) -> bool {
+        let found = self.cheats.set_enabled(id, enabled);
+        self.sync_cheats();
+        found
+    }
+
+    fn sync_cheats(&mut self) {
+        let patches = self.cheats.rom_patches();
+        self.bus.borrow_mut().cartridge.set_patches(patches);
+    }
+
+    /// Tells the bus which ranges to record for the memory hooks
+    fn sync_access_log(&mut self) {
+        self.bus
+            .borrow_mut()
+            .access_log
+            .set_ranges(self.hooks.read_ranges(), self.hooks.write_ranges());
+    }
+
+    /// Calls `hook` after every instruction that read from `range`
+    pub fn add_read_hook<F>(&mut self, range: RangeInclusive<u16>, hook: F) -> HookId
+    where
+        F: FnMut(&mut EmuContext, MemoryAccess) + 'static,
+    {
+        let id = self.hooks.add_read(range, Box::new(hook));
+        self.sync_access_log();
+        id
+    }
+
+    /// Calls `hook` after every instruction that wrote to `range`
+    pub fn add_write_hook<F>(&mut self, range: RangeInclusive<u16>, hook: F) -> HookId
+    where
+        F: FnMut(&mut EmuContext, MemoryAccess) + 'static,
+    {
+        let id = self.hooks.add_write(range, Box::new(hook));
+        self.sync_access_log();
+        id
+    }
+
+    /// Calls `hook` before the instruction at `pc` executes
+    pub fn add_exec_hook<F>(&mut self, pc: u16, hook: F) -> HookId
+    where
+        F: FnMut(&mut EmuContext, u16) + 'static,
+    {
+        self.hooks.add_exec(pc, Box::new(hook))
+    }
+
+    /// Calls `hook` whenever the cpu jumps to an interrupt vector
+    pub fn add_interrupt_hook<F>(&mut self, hook: F) -> HookId
+    where
+        F: FnMut(&mut EmuContext, InterruptType) + 'static,
+    {
+        self.hooks.add_interrupt(Box::new(hook))
+    }
+
+    /// Calls `hook` with the new mode whenever the PPU switches modes
+    pub fn add_ppu_mode_hook<F>(&mut self, hook: F) -> HookId
+    where
+        F: FnMut(&mut EmuContext, Mode) + 'static,
+    {
+        self.hooks.add_ppu_mode(Box::new(hook))
+    }
+
+    /// Returns whether the hook was removed right away
+    ///
+    /// While hooks run their callbacks are taken out of the context, a hook
+    /// removed from inside a callback is dropped once they are put back.
+    pub fn remove_hook(&mut self, id: HookId) -> bool {
+        if self.hooks.remove(id) {
+            self.sync_access_log();
+            return true;
         }
 
-        n_cycles
+        // the callbacks are taken out while hooks run
+        self.hooks.remove_later(id);
+        false
+    }
+
+    /// Runs until the PPU enters VBlank
+    ///
+    /// With the lcd off there is no VBlank, the frame ends after
+    /// `CYCLES_1_FRAME` cycles instead so that callers keep a steady pace.
+    pub fn run_frame(&mut self) -> Result<FrameResult, EmuError> {
+        let mut cycles = 0;
+
+        loop {
+            cycles += self.step()?;
+
+            let mut bus = self.bus.borrow_mut();
+            if bus.ppu.take_frame_ready() {
+                return Ok(FrameResult {
+                    completed: true,
+                    cycles,
+                });
+            }
+
+            if cycles >= CYCLES_1_FRAME && !bus.ppu.is_lcd_enabled() {
+                return Ok(FrameResult {
+                    completed: false,
+                    cycles,
+                });
+            }
+        }
+    }
+
+    /// Runs whole instructions until at least `cycles` cycles have passed
+    /// Returns the cycles actually run
+    pub fn run_cycles(&mut self, cycles: u64) -> Result<u64, EmuError> {
+        let mut elapsed = 0;
+
+        while elapsed < cycles {
+            elapsed += self.step()?;
+        }
+
+        Ok(elapsed)
+    }
+
+    /// Steps until `predicate` holds, it is checked after every instruction
+    /// Returns the cycles run
+    pub fn run_until<F>(&mut self, mut predicate: F) -> Result<u64, EmuError>
+    where
+        F: FnMut(&EmuContext) -> bool,
+    {
+        let mut elapsed = 0;
+
+        loop {
+            elapsed += self.step()?;
+
+            if predicate(self) {
+                return Ok(elapsed);
+            }
+        }
+    }
+
+    /// Detects a cpu that can never make progress again
+    pub fn lockup(&self) -> Option<Lockup> {
+        let bus = self.bus.borrow();
+        let interrupts_enabled = bus.read(0xFFFF) & 0x1F != 0;
+
+        if self.cpu.halted && !interrupts_enabled {
+            return Some(Lockup::HaltForever);
+        }
+
+        if self.cpu.ime && interrupts_enabled {
+            return None;
+        }
+
+        let pc = self.cpu.registers.pc;
+        let jumps_to_self = match bus.read(pc) {
+            0x18 => bus.read(pc.wrapping_add(1)) == 0xFE,
+            0xC3 => bytes_to_word(bus.read(pc.wrapping_add(2)), bus.read(pc.wrapping_add(1))) == pc,
+            _ => false,
+        };
+
+        jumps_to_self.then_some(Lockup::JumpToSelf)
+    }
+
+    /// Serializes the whole machine into the native save state format
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        let bus = self.bus.borrow();
+
+        state.write_bytes(STATE_MAGIC);
+        state.write_u16(STATE_VERSION);
+        state.write_bytes(&bus.cartridge.header.title_bytes());
+        state.write_u16(bus.cartridge.header.global_checksum());
+
+        self.cpu.save_state(&mut state);
+        bus.save_state(&mut state);
+
+        state.into_bytes()
+    }
+
+    /// Native save state with a BESS block section appended,
+    /// so that other emulators can load it as well
+    pub fn save_bess(&self) -> Vec<u8> {
+        BessState::from_emu(self).write(&self.save_state())
+    }
+
+    /// Loads either a native save state or a BESS save state
+    ///
+    /// Native data is preferred when a file contains both
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SavestateError> {
+        if data.starts_with(STATE_MAGIC) {
+            return self.load_native_state(data);
+        }
+
+        if bess::is_bess(data) {
+            return BessState::parse(data)?.apply(self);
+        }
+
+        Err(SavestateError::InvalidMagic)
+    }
+
+    fn load_native_state(&mut self, data: &[u8]) -> Result<(), SavestateError> {
+        let mut state = StateReader::new(data);
+
+        if state.read_bytes(STATE_MAGIC.len())? != STATE_MAGIC {
+            return Err(SavestateError::InvalidMagic);
+        }
+
+        let version = state.read_u16()?;
+        if version != STATE_VERSION {
+            return Err(SavestateError::UnsupportedVersion(version));
+        }
+
+        {
+            let bus = self.bus.borrow();
+            let title = state.read_bytes(16)?;
+            let checksum = state.read_u16()?;
+
+            if title != bus.cartridge.header.title_bytes()
+                || checksum != bus.cartridge.header.global_checksum()
+            {
+                return Err(SavestateError::RomMismatch);
+            }
+        }
+
+        // a truncated state must not leave the machine half loaded
+        let backup = self.save_state();
+
+        let result = self
+            .cpu
+            .load_state(&mut state)
+            .and_then(|_| self.bus.borrow_mut().load_state(&mut state));
+
+        if result.is_err() {
+            // only fails if saving and loading disagree, report that instead
+            self.load_native_state(&backup)?;
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EmuContext, Lockup};
+    use crate::error::EmuError;
+    use crate::{
+        bus::Memory, cartridge::Cartridge, io::ppu::registers::Mode, utils::Opts,
+        utils::CYCLES_1_FRAME,
+    };
+
+    #[test]
+    fn test_run_frame_stops_at_vblank() {
+        let mut ctx = EmuContext::new(
+            Cartridge::new(vec![0; 0x8000]).unwrap(),
+            Opts::new(false, false),
+        );
+
+        // the boot state is mid frame, sync up first
+        ctx.run_frame().unwrap();
+        let result = ctx.run_frame().unwrap();
+
+        assert!(result.completed);
+        assert!(result.cycles.abs_diff(CYCLES_1_FRAME) < 8);
+        assert_eq!(ctx.bus.borrow().ppu.mode(), Mode::VBlank);
+        assert_eq!(ctx.bus.borrow().ppu.ly(), 144);
+    }
+
+    #[test]
+    fn test_run_frame_with_lcd_off() {
+        let mut ctx = EmuContext::new(
+            Cartridge::new(vec![0; 0x8000]).unwrap(),
+            Opts::new(false, false),
+        );
+        ctx.bus.borrow_mut().write(0xFF40, 0x00);
+
+        let result = ctx.run_frame().unwrap();
+
+        assert!(!result.completed);
+        assert!(result.cycles >= CYCLES_1_FRAME);
+    }
+
+    #[test]
+    fn test_run_until() {
+        let mut ctx = EmuContext::new(
+            Cartridge::new(vec![0; 0x8000]).unwrap(),
+            Opts::new(false, false),
+        );
+
+        ctx.run_until(|ctx| ctx.cpu.registers.pc >= 0x110).unwrap();
+        assert_eq!(ctx.cpu.registers.pc, 0x110);
+        assert!(ctx.run_cycles(10).unwrap() >= 10);
+    }
+
+    #[test]
+    fn test_lockup() {
+        // di ; jr -2
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x103].copy_from_slice(&[0xF3, 0x18, 0xFE]);
+        let mut ctx = EmuContext::new(Cartridge::new(rom).unwrap(), Opts::new(false, false));
+
+        assert_eq!(ctx.lockup(), None);
+        ctx.run_cycles(16).unwrap();
+        assert_eq!(ctx.lockup(), Some(Lockup::JumpToSelf));
+    }
+
+    #[test]
+    fn test_unknown_opcode_is_an_error() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100] = 0xD3;
+        let mut ctx = EmuContext::new(Cartridge::new(rom).unwrap(), Opts::new(false, false));
+
+        assert!(matches!(
+            ctx.step(),
+            Err(EmuError::UnknownOpcode {
+                opcode: 0xD3,
+                prefixed: false,
+                pc: 0x100
+            })
+        ));
+        assert_eq!(ctx.cpu.registers.pc, 0x100);
+        assert!(Cartridge::new(vec![0; 0x100]).is_err());
     }
 }
@@ -0,0 +1,61 @@
+use std::{fmt, io};
+
+use crate::savestate::SavestateError;
+
+/// Errors surfaced by the library instead of panicking
+#[derive(Debug)]
+pub enum EmuError {
+    /// Reading a rom or other file failed
+    Io(io::Error),
+    /// Rom data that can't be inserted as a cartridge
+    InvalidRom(String),
+    /// The cpu fetched an opcode that doesn't exist.
+    /// On hardware this locks up the cpu, `pc` points at the opcode
+    UnknownOpcode {
+        opcode: u8,
+        prefixed: bool,
+        pc: u16,
+    },
+    Savestate(SavestateError),
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmuError::Io(e) => write!(f, "{}", e),
+            EmuError::InvalidRom(msg) => write!(f, "invalid rom: {}", msg),
+            EmuError::UnknownOpcode {
+                opcode,
+                prefixed,
+                pc,
+            } => write!(
+                f,
+                "unknown opcode {:02X} (prefixed {}) at {:04X}",
+                opcode, prefixed, pc
+            ),
+            EmuError::Savestate(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for EmuError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EmuError::Io(e) => Some(e),
+            EmuError::Savestate(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for EmuError {
+    fn from(e: io::Error) -> Self {
+        EmuError::Io(e)
+    }
+}
+
+impl From<SavestateError> for EmuError {
+    fn from(e: SavestateError) -> Self {
+        EmuError::Savestate(e)
+    }
+}
@@ -0,0 +1,630 @@
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    io::{self, ErrorKind, Read, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    bus::Memory,
+    cpu::registers::Reg16,
+    debug::BankedAddress,
+    emu::EmuContext,
+    hooks::HookId,
+    utils::{word_to_bytes, CYCLES_1_FRAME},
+};
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+/// Longest `m` read, the reply has two hex digits per byte and has to fit
+/// in the advertised `PacketSize` of 0x1000
+const MAX_READ_LENGTH: u32 = 0x800;
+
+/// How long a stopped stub waits for packets before giving the frontend
+/// its frame back
+const STOPPED_POLL_TIME: Duration = Duration::from_millis(10);
+
+/// # GDB remote serial protocol
+/// Lets GDB frontends debug the game over TCP on localhost.
+///
+/// Registers, in `g` / `p` order, are 16 bit little endian
+/// - 0 AF, 1 BC, 2 DE, 3 HL, 4 SP, 5 PC
+///
+/// Memory and breakpoint addresses follow the `BankedAddress` convention,
+/// `m34000,10` reads 16 bytes at the start of ROM bank 3. Breakpoints with
+/// a bank only stop while that bank is mapped, watchpoints ignore the bank.
+///
+/// Supported packets
+/// - `?`, `g`, `G`, `p`, `P`, `m`, `M`
+/// - `c`, `s`, and `0x03` to stop a running game
+/// - `Z0` - `Z4`, `z0` - `z4`, software and hardware breakpoints are the same
+/// - `D`, `k`, `qSupported`, `QStartNoAckMode` and the thread queries
+///   for a single thread
+///
+/// While running the stub is driven one frame at a time by `run_frame`,
+/// so a frontend keeps drawing the screen.
+pub struct GdbStub {
+    stream: TcpStream,
+    input: Vec<u8>,
+    last_reply: Vec<u8>,
+    no_ack: bool,
+    running: bool,
+    stop_reason: String,
+    breakpoints: Vec<BankedAddress>,
+    /// ( type, address, length ) of a `Z` packet
+    watchpoints: HashMap<(u8, u16, u16), Vec<HookId>>,
+    watch_hit: Rc<Cell<Option<WatchHit>>>,
+}
+
+#[derive(Clone, Copy)]
+struct WatchHit {
+    kind: u8,
+    address: u16,
+}
+
+enum Incoming {
+    Packet(String),
+    Interrupt,
+}
+
+impl GdbStub {
+    /// Waits on localhost for GDB to connect
+    pub fn listen(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        Self::accept(&listener)
+    }
+
+    pub fn accept(listener: &TcpListener) -> io::Result<Self> {
+        let (stream, _) = listener.accept()?;
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+
+        Ok(GdbStub {
+            stream,
+            input: vec![],
+            last_reply: vec![],
+            no_ack: false,
+            running: false,
+            stop_reason: format!("S{:02x}", SIGTRAP),
+            breakpoints: vec![],
+            watchpoints: HashMap::new(),
+            watch_hit: Rc::new(Cell::new(None)),
+        })
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Answers GDB, then runs the game for up to a frame unless it is stopped.
+    /// Returns false once GDB detached, the watchpoints are gone by then.
+    pub fn run_frame(&mut self, ctx: &mut EmuContext) -> io::Result<bool> {
+        let deadline = Instant::now() + STOPPED_POLL_TIME;
+
+        loop {
+            if !self.receive()? {
+                self.clear(ctx);
+                return Ok(false);
+            }
+
+            while let Some(incoming) = self.next_incoming()? {
+                match incoming {
+                    Incoming::Interrupt if self.running => self.stop(format!("S{:02x}", SIGINT))?,
+                    Incoming::Interrupt => {}
+                    Incoming::Packet(packet) => {
+                        if !self.handle_packet(ctx, &packet)? {
+                            self.clear(ctx);
+                            return Ok(false);
+                        }
+                    }
+                }
+            }
+
+            if self.running || Instant::now() >= deadline {
+                break;
+            }
+
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        if self.running {
+            self.run(ctx)?;
+        }
+
+        Ok(true)
+    }
+
+    fn run(&mut self, ctx: &mut EmuContext) -> io::Result<()> {
+        let mut cycles = 0;
+
+        while cycles < CYCLES_1_FRAME {
+            match ctx.step() {
+                Ok(n) => cycles += n,
+                Err(_) => return self.stop(format!("S{:02x}", SIGILL)),
+            }
+
+            if let Some(reason) = self.check_stop(ctx) {
+                return self.stop(reason);
+            }
+
+            if ctx.bus.borrow_mut().ppu.take_frame_ready() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn step(&mut self, ctx: &mut EmuContext) -> io::Result<()> {
+        if ctx.step().is_err() {
+            return self.stop(format!("S{:02x}", SIGILL));
+        }
+
+        let reason = self
+            .check_stop(ctx)
+            .unwrap_or_else(|| format!("S{:02x}", SIGTRAP));
+        self.stop(reason)
+    }
+
+    fn check_stop(&self, ctx: &EmuContext) -> Option<String> {
+        if let Some(hit) = self.watch_hit.take() {
+            let kind = match hit.kind {
+                2 => "watch",
+                3 => "rwatch",
+                _ => "awatch",
+            };
+            return Some(format!("T{:02x}{}:{:x};", SIGTRAP, kind, hit.address));
+        }
+
+        let pc = ctx.cpu.registers.pc;
+        let bus = ctx.bus.borrow();
+        self.breakpoints
+            .iter()
+            .any(|bp| bp.address == pc && bp.is_mapped(&bus))
+            .then(|| format!("S{:02x}", SIGTRAP))
+    }
+
+    fn stop(&mut self, reason: String) -> io::Result<()> {
+        self.running = false;
+        self.stop_reason = reason.clone();
+        self.reply(&reason)
+    }
+
+    /// Returns false when the connection is closed
+    fn receive(&mut self) -> io::Result<bool> {
+        let mut buffer = [0; 1024];
+
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Ok(false),
+                Ok(n) => self.input.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn next_incoming(&mut self) -> io::Result<Option<Incoming>> {
+        loop {
+            match self.input.first() {
+                None => return Ok(None),
+                Some(b'+') => {
+                    self.input.remove(0);
+                }
+                Some(b'-') => {
+                    self.input.remove(0);
+                    let reply = self.last_reply.clone();
+                    self.send(&reply)?;
+                }
+                Some(0x03) => {
+                    self.input.remove(0);
+                    return Ok(Some(Incoming::Interrupt));
+                }
+                Some(b'$') => break,
+                // garbage between packets
+                Some(_) => {
+                    self.input.remove(0);
+                }
+            }
+        }
+
+        let Some(end) = self.input.iter().position(|byte| *byte == b'#') else {
+            return Ok(None);
+        };
+        if self.input.len() < end + 3 {
+            return Ok(None);
+        }
+
+        let packet: Vec<u8> = self.input.drain(..end + 3).collect();
+        let data = &packet[1..end];
+        let checksum = std::str::from_utf8(&packet[end + 1..])
+            .ok()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        if checksum != Some(checksum_of(data)) {
+            if !self.no_ack {
+                self.send(b"-")?;
+            }
+            return self.next_incoming();
+        }
+
+        if !self.no_ack {
+            self.send(b"+")?;
+        }
+
+        Ok(Some(Incoming::Packet(
+            String::from_utf8_lossy(data).into_owned(),
+        )))
+    }
+
+    /// Returns false when the session ends
+    fn handle_packet(&mut self, ctx: &mut EmuContext, packet: &str) -> io::Result<bool> {
+        let Some(command) = packet.chars().next() else {
+            self.reply("")?;
+            return Ok(true);
+        };
+        let args = &packet[command.len_utf8()..];
+
+        match command {
+            '?' => {
+                let reason = self.stop_reason.clone();
+                self.reply(&reason)?;
+            }
+            'g' => {
+                let registers = (0..6)
+                    .filter_map(|idx| read_register(ctx, idx))
+                    .map(|value| format!("{:02x}{:02x}", value as u8, value >> 8))
+                    .collect::<String>();
+                self.reply(&registers)?;
+            }
+            'G' => {
+                let written = decode_hex(args)
+                    .filter(|bytes| bytes.len() == 12)
+                    .map(|bytes| {
+                        for (idx, pair) in bytes.chunks(2).enumerate() {
+                            write_register(ctx, idx, u16::from_le_bytes([pair[0], pair[1]]));
+                        }
+                    });
+                self.reply_ok(written.is_some())?;
+            }
+            'p' => match parse_hex(args).and_then(|idx| read_register(ctx, idx as usize)) {
+                Some(value) => self.reply(&format!("{:02x}{:02x}", value as u8, value >> 8))?,
+                None => self.reply("E01")?,
+            },
+            'P' => {
+                let written = args.split_once('=').and_then(|(idx, value)| {
+                    let bytes = decode_hex(value).filter(|bytes| bytes.len() == 2)?;
+                    let value = u16::from_le_bytes([bytes[0], bytes[1]]);
+                    write_register(ctx, parse_hex(idx)? as usize, value).then_some(())
+                });
+                self.reply_ok(written.is_some())?;
+            }
+            'm' => match parse_pair(args) {
+                Some((address, length)) if length <= MAX_READ_LENGTH => {
+                    let bus = ctx.bus.borrow();
+                    let data = (0..length)
+                        .map(|offset| {
+                            let byte =
+                                BankedAddress::from_u32(address.wrapping_add(offset)).read(&bus);
+                            format!("{:02x}", byte)
+                        })
+                        .collect::<String>();
+                    drop(bus);
+                    self.reply(&data)?;
+                }
+                _ => self.reply("E01")?,
+            },
+            'M' => {
+                let written = args.split_once(':').and_then(|(range, data)| {
+                    let (address, length) = parse_pair(range)?;
+                    let bytes = decode_hex(data).filter(|bytes| bytes.len() == length as usize)?;
+                    let mut bus = ctx.bus.borrow_mut();
+                    for (offset, byte) in bytes.into_iter().enumerate() {
+                        // ROM can't be patched, writes only go through the bus
+                        bus.write(address.wrapping_add(offset as u32) as u16, byte);
+                    }
+                    Some(())
+                });
+                self.reply_ok(written.is_some())?;
+            }
+            'c' | 's' => {
+                if let Some(address) = parse_hex(args) {
+                    ctx.cpu.registers.pc = address as u16;
+                }
+
+                if command == 'c' {
+                    self.running = true;
+                } else {
+                    self.step(ctx)?;
+                }
+            }
+            'Z' | 'z' => {
+                let done = self.change_point(ctx, command == 'Z', args);
+                match done {
+                    Some(done) => self.reply_ok(done)?,
+                    // unknown kinds are unsupported, not failed
+                    None => self.reply("")?,
+                }
+            }
+            'D' => {
+                self.reply("OK")?;
+                return Ok(false);
+            }
+            'k' => return Ok(false),
+            'H' | 'T' => self.reply("OK")?,
+            'q' => {
+                let name = packet.split([':', ',']).next().unwrap_or_default();
+                let reply = match name {
+                    "qSupported" => "PacketSize=1000;QStartNoAckMode+",
+                    "qAttached" => "1",
+                    "qC" => "QC1",
+                    "qfThreadInfo" => "m1",
+                    "qsThreadInfo" => "l",
+                    _ => "",
+                };
+                self.reply(reply)?;
+            }
+            'Q' if packet == "QStartNoAckMode" => {
+                self.reply("OK")?;
+                self.no_ack = true;
+            }
+            _ => self.reply("")?,
+        }
+
+        Ok(true)
+    }
+
+    /// Handles `Z` / `z`, returns `None` for unsupported kinds
+    fn change_point(&mut self, ctx: &mut EmuContext, insert: bool, args: &str) -> Option<bool> {
+        let mut fields = args.split(',');
+        let kind = fields.next().and_then(parse_hex)? as u8;
+        let Some(address) = fields.next().and_then(parse_hex) else {
+            return Some(false);
+        };
+        let length = fields.next().and_then(parse_hex).unwrap_or(1).max(1) as u16;
+        let address = BankedAddress::from_u32(address);
+
+        match (kind, insert) {
+            (0 | 1, true) => {
+                if !self.breakpoints.contains(&address) {
+                    self.breakpoints.push(address);
+                }
+                Some(true)
+            }
+            (0 | 1, false) => {
+                self.breakpoints.retain(|bp| *bp != address);
+                Some(true)
+            }
+            (2..=4, true) => {
+                let key = (kind, address.address, length);
+                let range = address.address..=address.address.saturating_add(length - 1);
+                let mut ids = vec![];
+
+                if kind == 2 || kind == 4 {
+                    let hit = self.watch_hit.clone();
+                    ids.push(ctx.add_write_hook(range.clone(), move |_, access| {
+                        hit.set(Some(WatchHit {
+                            kind,
+                            address: access.address,
+                        }))
+                    }));
+                }
+
+                if kind == 3 || kind == 4 {
+                    let hit = self.watch_hit.clone();
+                    ids.push(ctx.add_read_hook(range, move |_, access| {
+                        hit.set(Some(WatchHit {
+                            kind,
+                            address: access.address,
+                        }))
+                    }));
+                }
+
+                self.watchpoints.entry(key).or_default().extend(ids);
+                Some(true)
+            }
+            (2..=4, false) => {
+                let ids = self
+                    .watchpoints
+                    .remove(&(kind, address.address, length))
+                    .unwrap_or_default();
+                for id in ids {
+                    ctx.remove_hook(id);
+                }
+                Some(true)
+            }
+            _ => None,
+        }
+    }
+
+    /// Drops the hooks registered for watchpoints
+    fn clear(&mut self, ctx: &mut EmuContext) {
+        for (_, ids) in self.watchpoints.drain() {
+            for id in ids {
+                ctx.remove_hook(id);
+            }
+        }
+        self.running = false;
+    }
+
+    fn reply_ok(&mut self, ok: bool) -> io::Result<()> {
+        self.reply(if ok { "OK" } else { "E01" })
+    }
+
+    fn reply(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.last_reply = packet.clone().into_bytes();
+        self.send(packet.as_bytes())
+    }
+
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut written = 0;
+
+        while written < data.len() {
+            match self.stream.write(&data[written..]) {
+                Ok(n) => written += n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => std::thread::yield_now(),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn read_register(ctx: &EmuContext, idx: usize) -> Option<u16> {
+    let registers = &ctx.cpu.registers;
+
+    match idx {
+        0 => Some(registers.get_reg_pair(Reg16::AF)),
+        1 => Some(registers.get_reg_pair(Reg16::BC)),
+        2 => Some(registers.get_reg_pair(Reg16::DE)),
+        3 => Some(registers.get_reg_pair(Reg16::HL)),
+        4 => Some(registers.sp),
+        5 => Some(registers.pc),
+        _ => None,
+    }
+}
+
+fn write_register(ctx: &mut EmuContext, idx: usize, value: u16) -> bool {
+    let registers = &mut ctx.cpu.registers;
+
+    match idx {
+        0 => {
+            // the low nibble of F always reads 0
+            let (a, f) = word_to_bytes(value);
+            registers.a = a;
+            registers.f = (f & 0xF0).into();
+        }
+        1 => registers.set_reg_pair(value, Reg16::BC),
+        2 => registers.set_reg_pair(value, Reg16::DE),
+        3 => registers.set_reg_pair(value, Reg16::HL),
+        4 => registers.sp = value,
+        5 => registers.pc = value,
+        _ => return false,
+    }
+
+    true
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn parse_hex(hex: &str) -> Option<u32> {
+    u32::from_str_radix(hex, 16).ok()
+}
+
+/// `address,length`
+fn parse_pair(args: &str) -> Option<(u32, u32)> {
+    let (address, length) = args.split_once(',')?;
+    Some((parse_hex(address)?, parse_hex(length)?))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(hex.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::{checksum_of, GdbStub};
+    use crate::{cartridge::Cartridge, emu::EmuContext, utils::Opts};
+
+    struct Client(TcpStream);
+
+    impl Client {
+        fn request(&mut self, data: &str) -> String {
+            let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+            self.0.write_all(packet.as_bytes()).unwrap();
+            self.read_reply()
+        }
+
+        fn read_reply(&mut self) -> String {
+            let mut reply = vec![];
+            let mut byte = [0];
+
+            loop {
+                self.0.read_exact(&mut byte).unwrap();
+                match byte[0] {
+                    b'+' if reply.is_empty() => {}
+                    b'$' if reply.is_empty() => {}
+                    b'#' => break,
+                    other => reply.push(other),
+                }
+            }
+
+            let mut checksum = [0; 2];
+            self.0.read_exact(&mut checksum).unwrap();
+            String::from_utf8(reply).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_scripted_session() {
+        let mut rom = vec![0; 0x8000];
+        // ld a, 0x42 ; ld (0xC000), a ; nop ; jr -3
+        rom[0x100..0x108].copy_from_slice(&[0x3E, 0x42, 0xEA, 0x00, 0xC0, 0x00, 0x18, 0xFD]);
+        rom[0x4000] = 0xAB;
+        let mut ctx = EmuContext::new(Cartridge::new(rom).unwrap(), Opts::new(false, false));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = std::thread::spawn(move || {
+            let mut gdb = Client(TcpStream::connect(address).unwrap());
+
+            assert!(gdb.request("qSupported").starts_with("PacketSize"));
+            assert_eq!(gdb.request("?"), "S05");
+            assert_eq!(&gdb.request("g")[20..24], "0001");
+
+            assert_eq!(gdb.request("Z2,c000,1"), "OK");
+            assert_eq!(gdb.request("c"), "T05watch:c000;");
+            assert_eq!(gdb.request("p5"), "0501");
+            assert_eq!(gdb.request("z2,c000,1"), "OK");
+
+            assert_eq!(gdb.request("Z0,105,1"), "OK");
+            assert_eq!(gdb.request("c"), "S05");
+            assert_eq!(gdb.request("p5"), "0501");
+            assert_eq!(gdb.request("s"), "S05");
+            assert_eq!(gdb.request("p5"), "0601");
+
+            assert_eq!(gdb.request("P1=3412"), "OK");
+            assert_eq!(gdb.request("p1"), "3412");
+            assert_eq!(gdb.request("Mc001,1:07"), "OK");
+            assert_eq!(gdb.request("mc000,2"), "4207");
+            // ROM bank 1 through the bank-aware address
+            assert_eq!(gdb.request("m14000,1"), "ab");
+            assert_eq!(gdb.request("mffffffff,2").len(), 4);
+            assert_eq!(gdb.request("m0,ffffffff"), "E01");
+            // non-ASCII commands are unsupported
+            assert_eq!(gdb.request("\u{ff}"), "");
+
+            assert_eq!(gdb.request("D"), "OK");
+        });
+
+        let mut stub = GdbStub::accept(&listener).unwrap();
+        while stub.run_frame(&mut ctx).unwrap() {}
+
+        client.join().unwrap();
+        assert_eq!(ctx.cpu.registers.b, 0x12);
+        assert_eq!(ctx.cpu.registers.c, 0x34);
+    }
+}
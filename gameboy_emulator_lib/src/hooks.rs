@@ -0,0 +1,377 @@
+use std::{cell::RefCell, ops::RangeInclusive};
+
+use crate::{emu::EmuContext, interrupt::InterruptType, io::ppu::registers::Mode};
+
+/// Handle returned when registering a hook, used to remove it again
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct HookId(u32);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// A bus access made while executing an instruction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryAccess {
+    pub kind: AccessKind,
+    pub address: u16,
+    /// Byte read, or byte written
+    pub value: u8,
+}
+
+pub type MemoryHook = Box<dyn FnMut(&mut EmuContext, MemoryAccess)>;
+pub type ExecHook = Box<dyn FnMut(&mut EmuContext, u16)>;
+pub type InterruptHook = Box<dyn FnMut(&mut EmuContext, InterruptType)>;
+pub type PpuModeHook = Box<dyn FnMut(&mut EmuContext, Mode)>;
+
+/// # Hooks
+/// Callbacks registered through `EmuContext`.
+///
+/// Callbacks get the whole `EmuContext`, so they may not run while the bus
+/// is borrowed. Events are therefore collected during an instruction and
+/// handed out right after it, in this order
+/// - interrupt dispatch
+/// - memory accesses, in the order they happened
+/// - PPU mode changes
+///
+/// Exec hooks are the exception, they run before the instruction at their PC.
+///
+/// With nothing registered every check is an empty `Vec`, so the cost is a
+/// few length compares per instruction and per bus access.
+#[derive(Default)]
+pub struct Hooks {
+    next_id: u32,
+    reads: Vec<(HookId, RangeInclusive<u16>, MemoryHook)>,
+    writes: Vec<(HookId, RangeInclusive<u16>, MemoryHook)>,
+    execs: Vec<(HookId, u16, ExecHook)>,
+    interrupts: Vec<(HookId, InterruptHook)>,
+    ppu_modes: Vec<(HookId, PpuModeHook)>,
+    /// Removed while their callbacks were taken out for dispatch
+    removed: Vec<HookId>,
+}
+
+impl Hooks {
+    fn next_id(&mut self) -> HookId {
+        self.next_id += 1;
+        HookId(self.next_id)
+    }
+
+    pub fn add_read(&mut self, range: RangeInclusive<u16>, hook: MemoryHook) -> HookId {
+        let id = self.next_id();
+        self.reads.push((id, range, hook));
+        id
+    }
+
+    pub fn add_write(&mut self, range: RangeInclusive<u16>, hook: MemoryHook) -> HookId {
+        let id = self.next_id();
+        self.writes.push((id, range, hook));
+        id
+    }
+
+    pub fn add_exec(&mut self, pc: u16, hook: ExecHook) -> HookId {
+        let id = self.next_id();
+        self.execs.push((id, pc, hook));
+        id
+    }
+
+    pub fn add_interrupt(&mut self, hook: InterruptHook) -> HookId {
+        let id = self.next_id();
+        self.interrupts.push((id, hook));
+        id
+    }
+
+    pub fn add_ppu_mode(&mut self, hook: PpuModeHook) -> HookId {
+        let id = self.next_id();
+        self.ppu_modes.push((id, hook));
+        id
+    }
+
+    /// Returns false if no hook with that id is registered
+    pub fn remove(&mut self, id: HookId) -> bool {
+        let before = self.len();
+
+        self.reads.retain(|(hook_id, _, _)| *hook_id != id);
+        self.writes.retain(|(hook_id, _, _)| *hook_id != id);
+        self.execs.retain(|(hook_id, _, _)| *hook_id != id);
+        self.interrupts.retain(|(hook_id, _)| *hook_id != id);
+        self.ppu_modes.retain(|(hook_id, _)| *hook_id != id);
+
+        before != self.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.reads.len()
+            + self.writes.len()
+            + self.execs.len()
+            + self.interrupts.len()
+            + self.ppu_modes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn has_exec_at(&self, pc: u16) -> bool {
+        self.execs.iter().any(|(_, hook_pc, _)| *hook_pc == pc)
+    }
+
+    pub fn watches_ppu_mode(&self) -> bool {
+        !self.ppu_modes.is_empty()
+    }
+
+    pub(crate) fn read_ranges(&self) -> Vec<RangeInclusive<u16>> {
+        self.reads
+            .iter()
+            .map(|(_, range, _)| range.clone())
+            .collect()
+    }
+
+    pub(crate) fn write_ranges(&self) -> Vec<RangeInclusive<u16>> {
+        self.writes
+            .iter()
+            .map(|(_, range, _)| range.clone())
+            .collect()
+    }
+
+    /// Moves the callbacks out for dispatch, ids keep counting up
+    pub(crate) fn take(&mut self) -> Hooks {
+        Hooks {
+            next_id: 0,
+            reads: std::mem::take(&mut self.reads),
+            writes: std::mem::take(&mut self.writes),
+            execs: std::mem::take(&mut self.execs),
+            interrupts: std::mem::take(&mut self.interrupts),
+            ppu_modes: std::mem::take(&mut self.ppu_modes),
+            removed: vec![],
+        }
+    }
+
+    /// Puts the callbacks back after dispatch.
+    /// Hooks added by a callback go after the existing ones,
+    /// hooks removed by a callback are dropped now.
+    pub(crate) fn restore(&mut self, mut taken: Hooks) {
+        taken.reads.append(&mut self.reads);
+        taken.writes.append(&mut self.writes);
+        taken.execs.append(&mut self.execs);
+        taken.interrupts.append(&mut self.interrupts);
+        taken.ppu_modes.append(&mut self.ppu_modes);
+
+        self.reads = taken.reads;
+        self.writes = taken.writes;
+        self.execs = taken.execs;
+        self.interrupts = taken.interrupts;
+        self.ppu_modes = taken.ppu_modes;
+
+        for id in std::mem::take(&mut self.removed) {
+            self.remove(id);
+        }
+    }
+
+    /// Remembers a removal that happened while the callbacks were taken out
+    pub(crate) fn remove_later(&mut self, id: HookId) {
+        self.removed.push(id);
+    }
+
+    pub(crate) fn run_exec(&mut self, ctx: &mut EmuContext, pc: u16) {
+        for (_, hook_pc, hook) in self.execs.iter_mut() {
+            if *hook_pc == pc {
+                hook(ctx, pc);
+            }
+        }
+    }
+
+    pub(crate) fn run_interrupt(&mut self, ctx: &mut EmuContext, it_type: InterruptType) {
+        for (_, hook) in self.interrupts.iter_mut() {
+            hook(ctx, it_type);
+        }
+    }
+
+    pub(crate) fn run_memory(&mut self, ctx: &mut EmuContext, access: MemoryAccess) {
+        let hooks = match access.kind {
+            AccessKind::Read => &mut self.reads,
+            AccessKind::Write => &mut self.writes,
+        };
+
+        for (_, range, hook) in hooks.iter_mut() {
+            if range.contains(&access.address) {
+                hook(ctx, access);
+            }
+        }
+    }
+
+    pub(crate) fn run_ppu_mode(&mut self, ctx: &mut EmuContext, mode: Mode) {
+        for (_, hook) in self.ppu_modes.iter_mut() {
+            hook(ctx, mode);
+        }
+    }
+}
+
+/// Records bus accesses inside the watched ranges.
+/// Lives in the bus, which only has `&self` on reads.
+#[derive(Default)]
+pub struct AccessLog {
+    read_ranges: Vec<RangeInclusive<u16>>,
+    write_ranges: Vec<RangeInclusive<u16>>,
+    accesses: RefCell<Vec<MemoryAccess>>,
+}
+
+impl AccessLog {
+    pub fn set_ranges(
+        &mut self,
+        read_ranges: Vec<RangeInclusive<u16>>,
+        write_ranges: Vec<RangeInclusive<u16>>,
+    ) {
+        self.read_ranges = read_ranges;
+        self.write_ranges = write_ranges;
+    }
+
+    #[inline(always)]
+    pub fn record_read(&self, address: u16, value: u8) {
+        if self.read_ranges.is_empty() {
+            return;
+        }
+
+        if self
+            .read_ranges
+            .iter()
+            .any(|range| range.contains(&address))
+        {
+            self.accesses.borrow_mut().push(MemoryAccess {
+                kind: AccessKind::Read,
+                address,
+                value,
+            });
+        }
+    }
+
+    #[inline(always)]
+    pub fn record_write(&self, address: u16, value: u8) {
+        if self.write_ranges.is_empty() {
+            return;
+        }
+
+        if self
+            .write_ranges
+            .iter()
+            .any(|range| range.contains(&address))
+        {
+            self.accesses.borrow_mut().push(MemoryAccess {
+                kind: AccessKind::Write,
+                address,
+                value,
+            });
+        }
+    }
+
+    pub fn clear(&self) {
+        self.accesses.borrow_mut().clear();
+    }
+
+    pub fn take(&self) -> Vec<MemoryAccess> {
+        std::mem::take(&mut *self.accesses.borrow_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        rc::Rc,
+    };
+
+    use super::{AccessKind, MemoryAccess};
+    use crate::{
+        cartridge::Cartridge, emu::EmuContext, interrupt::InterruptType, io::ppu::registers::Mode,
+        utils::Opts,
+    };
+
+    fn test_context(program: &[u8]) -> EmuContext {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        EmuContext::new(Cartridge::new(rom).unwrap(), Opts::new(false, false))
+    }
+
+    #[test]
+    fn test_memory_and_exec_hooks() {
+        // ld a, 0x42 ; ld (0xC000), a ; ld a, (0xC000)
+        let mut ctx = test_context(&[0x3E, 0x42, 0xEA, 0x00, 0xC0, 0xFA, 0x00, 0xC0]);
+        let accesses = Rc::new(RefCell::new(vec![]));
+        let execs = Rc::new(RefCell::new(vec![]));
+
+        let log = accesses.clone();
+        ctx.add_write_hook(0xC000..=0xC0FF, move |_, access| {
+            log.borrow_mut().push(access)
+        });
+        let log = accesses.clone();
+        ctx.add_read_hook(0xC000..=0xC000, move |_, access| {
+            log.borrow_mut().push(access)
+        });
+        let log = execs.clone();
+        let exec = ctx.add_exec_hook(0x102, move |ctx, pc| {
+            assert_eq!(ctx.cpu.registers.a, 0x42);
+            log.borrow_mut().push(pc)
+        });
+
+        ctx.run_until(|ctx| ctx.cpu.registers.pc == 0x108).unwrap();
+
+        assert_eq!(*execs.borrow(), vec![0x102]);
+        assert_eq!(
+            *accesses.borrow(),
+            vec![
+                MemoryAccess {
+                    kind: AccessKind::Write,
+                    address: 0xC000,
+                    value: 0x42
+                },
+                MemoryAccess {
+                    kind: AccessKind::Read,
+                    address: 0xC000,
+                    value: 0x42
+                },
+            ]
+        );
+
+        assert!(ctx.remove_hook(exec));
+        assert!(!ctx.remove_hook(exec));
+    }
+
+    #[test]
+    fn test_interrupt_and_ppu_mode_hooks() {
+        // ei ; halt ; jr -3
+        let mut ctx = test_context(&[0xFB, 0x76, 0x18, 0xFD]);
+        ctx.bus.borrow_mut().interrupts.borrow_mut().enable = 0x01;
+
+        let interrupts = Rc::new(RefCell::new(vec![]));
+        let modes = Rc::new(RefCell::new(vec![]));
+
+        let log = interrupts.clone();
+        ctx.add_interrupt_hook(move |_, it_type| log.borrow_mut().push(it_type));
+        let log = modes.clone();
+        let own_id = Rc::new(Cell::new(None));
+        let id = own_id.clone();
+        let mode_hook = ctx.add_ppu_mode_hook(move |ctx, mode| {
+            log.borrow_mut().push(mode);
+            // removing itself from inside the callback
+            if mode == Mode::VBlank {
+                ctx.remove_hook(id.get().unwrap());
+            }
+        });
+        own_id.set(Some(mode_hook));
+
+        ctx.run_frame().unwrap();
+        ctx.run_frame().unwrap();
+
+        assert!(interrupts.borrow().contains(&InterruptType::VBLANK));
+        assert_eq!(modes.borrow().last(), Some(&Mode::VBlank));
+        assert_eq!(
+            modes
+                .borrow()
+                .iter()
+                .filter(|m| **m == Mode::VBlank)
+                .count(),
+            1
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use crate::{
     bus::Memory,
+    savestate::{Savestate, SavestateError, StateReader, StateWriter},
     utils::{reset_bit, set_bit, BitPosCheck},
 };
 
@@ -16,21 +17,35 @@ pub trait Interruptable {
 impl Memory for Interrupts {
     fn read(&self, address: u16) -> u8 {
         match address {
-            0xFF0F => self.flag,
+            // only 5 interrupts, the upper bits read 1
+            0xFF0F => self.flag | 0xE0,
             0xFFFF => self.enable,
-            _ => panic!("invalid interrupt address"),
+            _ => unreachable!("interrupt registers are 0xFF0F and 0xFFFF"),
         }
     }
 
     fn write(&mut self, address: u16, byte: u8) {
         match address {
-            0xFF0F => self.flag = byte,
+            0xFF0F => self.flag = byte & 0x1F,
             0xFFFF => self.enable = byte,
-            _ => panic!("invalid interrupt address"),
+            _ => unreachable!("interrupt registers are 0xFF0F and 0xFFFF"),
         }
     }
 }
 
+impl Savestate for Interrupts {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.enable);
+        state.write_u8(self.flag);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SavestateError> {
+        self.enable = state.read_u8()?;
+        self.flag = state.read_u8()?;
+        Ok(())
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum InterruptType {
     VBLANK = 0,
@@ -44,7 +59,7 @@ impl Interrupts {
     pub fn new() -> Self {
         Interrupts {
             enable: 0x00,
-            flag: 0xE1,
+            flag: 0x01,
         }
     }
 
@@ -70,28 +85,29 @@ impl Interrupts {
         }
     }
 
-    pub fn interrupt_type(enable: u8, flag: u8) -> InterruptType {
+    /// Highest priority interrupt that is both requested and enabled
+    pub fn interrupt_type(enable: u8, flag: u8) -> Option<InterruptType> {
         if Self::check_flag(enable, flag, 0) {
-            return InterruptType::VBLANK;
+            return Some(InterruptType::VBLANK);
         }
 
         if Self::check_flag(enable, flag, 1) {
-            return InterruptType::LCDSTAT;
+            return Some(InterruptType::LCDSTAT);
         }
 
         if Self::check_flag(enable, flag, 2) {
-            return InterruptType::TIMER;
+            return Some(InterruptType::TIMER);
         }
 
         if Self::check_flag(enable, flag, 3) {
-            return InterruptType::SERIAL;
+            return Some(InterruptType::SERIAL);
         }
 
         if Self::check_flag(enable, flag, 4) {
-            return InterruptType::JOYPAD;
+            return Some(InterruptType::JOYPAD);
         }
 
-        panic!("invalid interrupt registers");
+        None
     }
 
     fn check_flag(enable: u8, flag: u8, pos: usize) -> bool {
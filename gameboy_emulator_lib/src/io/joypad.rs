@@ -1,19 +1,28 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
 
 use crate::{
     bus::Memory,
     interrupt::{InterruptType, Interrupts},
+    savestate::{Savestate, SavestateError, StateReader, StateWriter},
+    utils::BitPosCheck,
 };
 
+/// Bit positions inside the pressed button mask.
+/// Directions take the low nibble, actions the high nibble,
+/// both in the order the joypad register reports them.
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum JoypadInput {
-    Start,
-    Select,
-    A,
-    B,
-    Up,
-    Down,
-    Left,
-    Right,
+    Right = 0,
+    Left = 1,
+    Up = 2,
+    Down = 3,
+    A = 4,
+    B = 5,
+    Select = 6,
+    Start = 7,
 }
 
 /// Joypad
@@ -23,19 +32,17 @@ pub enum JoypadInput {
 /// - 1 -> not pressed
 /// - 0 -> pressed
 ///
+/// Button state is kept as a mask independent of the selected group,
+/// the register value is computed from it on every read.
 pub struct Joypad {
     /// Bit 5
     select_action: bool,
     /// Bit 4
     select_direction: bool,
-    /// Down | Start
-    bit_3: bool,
-    /// Up | Select
-    bit_2: bool,
-    /// Left | B
-    bit_1: bool,
-    /// Right | A
-    bit_0: bool,
+    /// One bit per `JoypadInput`, 1 -> pressed
+    pressed: u8,
+    /// Set whenever the cpu reads the register, tool reads leave it alone
+    polled: Cell<bool>,
     interrupts: Rc<RefCell<Interrupts>>,
 }
 
@@ -44,43 +51,44 @@ impl Joypad {
         Joypad {
             select_action: false,
             select_direction: false,
-            bit_3: true,
-            bit_2: true,
-            bit_1: true,
-            bit_0: true,
+            pressed: 0,
+            polled: Cell::new(false),
             interrupts,
         }
     }
 
     pub fn key_down(&mut self, key: JoypadInput) {
-        self.set_joypad(false, key);
-        self.create_interrupt();
+        self.set_buttons(self.pressed | (1 << key as u8));
     }
 
     pub fn key_up(&mut self, key: JoypadInput) {
-        self.set_joypad(true, key);
+        self.set_buttons(self.pressed & !(1 << key as u8));
     }
 
-    fn set_joypad(&mut self, pressed: bool, key: JoypadInput) {
-        if self.is_action_mode() {
-            match key {
-                JoypadInput::Start => self.bit_3 = pressed,
-                JoypadInput::Select => self.bit_2 = pressed,
-                JoypadInput::A => self.bit_0 = pressed,
-                JoypadInput::B => self.bit_1 = pressed,
-                _ => (),
-            }
-        }
+    /// Replaces the state of all buttons at once
+    /// Newly pressed buttons of a selected group request a joypad interrupt
+    pub fn set_buttons(&mut self, pressed: u8) {
+        let lines = self.get_input();
+        self.pressed = pressed;
+        self.check_interrupt(lines);
+    }
 
-        if self.is_direction_mode() {
-            match key {
-                JoypadInput::Up => self.bit_2 = pressed,
-                JoypadInput::Down => self.bit_3 = pressed,
-                JoypadInput::Left => self.bit_1 = pressed,
-                JoypadInput::Right => self.bit_0 = pressed,
-                _ => (),
-            }
-        }
+    pub fn buttons(&self) -> u8 {
+        self.pressed
+    }
+
+    pub fn is_pressed(&self, key: JoypadInput) -> bool {
+        self.pressed.is_bit_set(key as usize)
+    }
+
+    /// The cpu read the register
+    pub fn mark_polled(&self) {
+        self.polled.set(true);
+    }
+
+    /// Whether the register was read since the last call
+    pub fn take_polled(&self) -> bool {
+        self.polled.replace(false)
     }
 
     fn enable_action(&mut self) {
@@ -101,29 +109,28 @@ impl Joypad {
 
     /// Basically returns reverse of their actual bit
     fn get_state(&self) -> u8 {
-        if self.is_action_mode() {
-            return 0b0001_0000;
-        }
-
-        if self.is_direction_mode() {
-            return 0b0010_0000;
-        }
-
-        0b0011_0000
+        u8::from(self.select_action) << 5 | u8::from(self.select_direction) << 4
     }
 
     fn get_input(&self) -> u8 {
         // if button pressed -> false
         // not pressed -> true
+        let mut pressed = 0;
 
-        u8::from(self.bit_3) << 3
-            | u8::from(self.bit_2) << 2
-            | u8::from(self.bit_1) << 1
-            | u8::from(self.bit_0) << 0
+        if self.is_action_mode() {
+            pressed |= self.pressed >> 4;
+        }
+
+        if self.is_direction_mode() {
+            pressed |= self.pressed & 0x0F;
+        }
+
+        !pressed & 0x0F
     }
 
     fn get_joypad_input(&self) -> u8 {
-        self.get_state() | self.get_input()
+        // bits 6 and 7 are unused and read 1
+        0xC0 | self.get_state() | self.get_input()
     }
 
     fn is_action_mode(&self) -> bool {
@@ -134,10 +141,29 @@ impl Joypad {
         !self.select_direction
     }
 
-    fn create_interrupt(&mut self) {
-        self.interrupts
-            .borrow_mut()
-            .create_interrupt(InterruptType::JOYPAD);
+    /// The interrupt fires when one of P10 - P13 goes from high to low,
+    /// `lines` is the low nibble before the change
+    fn check_interrupt(&mut self, lines: u8) {
+        if lines & !self.get_input() != 0 {
+            self.interrupts
+                .borrow_mut()
+                .create_interrupt(InterruptType::JOYPAD);
+        }
+    }
+}
+
+impl Savestate for Joypad {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.select_action);
+        state.write_bool(self.select_direction);
+        state.write_u8(self.pressed);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SavestateError> {
+        self.select_action = state.read_bool()?;
+        self.select_direction = state.read_bool()?;
+        self.pressed = state.read_u8()?;
+        Ok(())
     }
 }
 
@@ -145,11 +171,14 @@ impl Memory for Joypad {
     fn read(&self, address: u16) -> u8 {
         match address {
             0xFF00 => self.get_joypad_input(),
-            _ => panic!("Accessing invalid address"),
+            _ => unreachable!("joypad register is 0xFF00"),
         }
     }
 
     fn write(&mut self, _address: u16, byte: u8) {
+        // selecting a group with a button held pulls its line low too
+        let lines = self.get_input();
+
         match byte & 0b0011_0000 {
             0b0001_0000 => {
                 self.enable_action();
@@ -161,10 +190,56 @@ impl Memory for Joypad {
                 self.enable_direction();
                 self.disable_action();
             }
+            0b0000_0000 => {
+                self.enable_direction();
+                self.enable_action();
+            }
             _ => {
                 self.disable_direction();
                 self.disable_action();
             }
         }
+
+        self.check_interrupt(lines);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::{Joypad, JoypadInput};
+    use crate::{bus::Memory, interrupt::Interrupts};
+
+    fn requested(interrupts: &Rc<RefCell<Interrupts>>) -> bool {
+        let requested = interrupts.borrow().flag & 0x10 != 0;
+        interrupts.borrow_mut().flag = 0;
+        requested
+    }
+
+    #[test]
+    fn test_interrupt_only_for_selected_group() {
+        let interrupts = Rc::new(RefCell::new(Interrupts::new()));
+        let mut joypad = Joypad::new(interrupts.clone());
+        interrupts.borrow_mut().flag = 0;
+
+        // directions selected
+        joypad.write(0xFF00, 0x20);
+        joypad.key_down(JoypadInput::A);
+        assert!(!requested(&interrupts));
+        joypad.key_down(JoypadInput::Up);
+        assert!(requested(&interrupts));
+
+        // selecting actions with A held pulls P10 low
+        joypad.write(0xFF00, 0x10);
+        assert!(requested(&interrupts));
+        joypad.key_down(JoypadInput::Down);
+        assert!(!requested(&interrupts));
+
+        // a line that's already low doesn't fire again
+        joypad.write(0xFF00, 0x00);
+        assert!(requested(&interrupts));
+        joypad.key_down(JoypadInput::Right);
+        assert!(!requested(&interrupts));
     }
 }
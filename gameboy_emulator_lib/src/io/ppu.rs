@@ -1,23 +1,23 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
 
 pub mod fetcher;
 pub mod oam;
 pub mod registers;
 
 use crate::interrupt::InterruptType;
-use crate::utils::BitPosCheck;
+use crate::savestate::{Savestate, SavestateError, StateReader, StateWriter};
 use crate::{
     bus::{
-        ranges::{OAM_COUNT, OAM_END, OAM_START, VRAM_END, VRAM_SIZE, VRAM_START},
+        ranges::{OAM_COUNT, OAM_END, OAM_START, PROHIBITED_END, VRAM_END, VRAM_SIZE, VRAM_START},
         Memory,
     },
     interrupt::Interrupts,
 };
 
-use self::fetcher::Pixel;
+use self::fetcher::{tile_row, FetchRegisters, Fetcher, FifoPixel, Pixel};
 use self::registers::Color;
 use self::{
-    oam::OamEntry,
+    oam::{corrupt_oam, OamBug, OamEntry},
     registers::{Lcdc, Mode, Palette, Stat},
 };
 
@@ -25,6 +25,7 @@ pub const VBLANK_LINE_LIMIT: u8 = 144;
 pub const MAX_LINE_LIMIT: u8 = 154;
 
 pub const OAM_TICK_LIMIT: u64 = 80;
+/// Mode 3 without fine scroll, window or objects. Each of them makes it longer
 pub const LCD_TRANSFER_TICK_LIMIT: u64 = 172;
 pub const HBLANK_TICK_LIMIT: u64 = 456;
 pub const SCREEN_WIDTH: usize = 160;
@@ -61,12 +62,27 @@ pub const SCREEN_HEIGHT: usize = 144;
 /// - Palette
 /// - Write color to buffer
 ///
+/// ### Pixel FIFO
+/// Mode 3 draws one pixel per dot. The `Fetcher` reads background or window
+/// tiles into the background FIFO, objects are fetched into a FIFO of their
+/// own and mixed in as pixels are shifted out. The mode takes longer
+/// - by SCX % 8 dots, the pixels scrolled off the left edge are dropped
+/// - by 6 dots when the window starts, the fetcher begins again
+/// - by 6 to 11 dots per object, the fetcher stops while it is read
+///
+/// Registers are read as the pixels go through, so writes during mode 3 show
+/// up from the next tile ( SCX, LCDC ) or the next pixel ( palettes ) on.
+///
+/// ### Window
+/// The window shows once LY matched WY at the start of a line this frame and
+/// the lcd reached WX - 7. It draws its rows from a counter of its own, lines
+/// with the window hidden don't advance it. Two WX values misbehave
+/// - WX = 0 starts the window while the SCX % 8 pixels are still dropped, it
+///   moves with SCX % 8 instead of sitting 7 pixels left of the screen
+/// - WX = 166 starts the window on the last pixel, it then covers the whole
+///   next line
 pub struct PPU {
-    cycles: u64,
     ticks: u64,
-    machine_cycles: u64,
-    pub dma_mode: bool,
-    pub dma_cycles: u64,
     /// Tile data stored inside vram.
     /// Tile represents 8x8 pixels
     /// 2 bytes makes up a single line of data
@@ -96,6 +112,8 @@ pub struct PPU {
     /// Triggers interrupt
     lyc: u8,
     stat: Stat,
+    /// The STAT interrupt sources ORed together, interrupts on a rising edge
+    stat_line: bool,
     /// Background positions
     /// Used to scroll the background. Specifices the origin of the 160x144 (width x height) area
     /// Visible area of the background wraps around the background map
@@ -105,15 +123,36 @@ pub struct PPU {
     /// Used to change the window positions. It is otherwise non scrollable.
     wy: u8,
     wx: u8,
-    pub dma: u8,
+    /// Window row to draw next
+    window_line: u8,
+    /// LY matched WY at the start of a line this frame
+    wy_triggered: bool,
+    /// The window started on the last pixel of the line before
+    window_full_line: bool,
     /// Background Palette
     bg_palette: Palette,
     /// Object Palette
     obj_palette_0: Palette,
     obj_palette_1: Palette,
     interrupts: Rc<RefCell<Interrupts>>,
-    background_priority: [bool; SCREEN_WIDTH],
+    fetcher: Fetcher,
+    /// Object pixels waiting to be mixed with the background, at most 8
+    obj_fifo: VecDeque<FifoPixel>,
+    /// Object being read and the dots left until it is in the FIFO
+    obj_fetch: Option<(OamEntry, u8)>,
+    /// Next pixel of the line to be drawn
+    lcd_x: u8,
+    /// Pixels left to drop at the start of the line, SCX % 8
+    discard: u8,
+    /// Dots at the start of mode 3 before the first tile is fetched
+    startup: u8,
     pub buffer: [Pixel; SCREEN_WIDTH * SCREEN_HEIGHT],
+    /// Set on entering VBlank with the lcd on, `buffer` holds a full frame
+    frame_ready: bool,
+    /// Line 0 right after the lcd was turned on, without mode 2
+    first_line: bool,
+    /// The frame after the lcd was turned on isn't drawn
+    skip_frame: bool,
 }
 
 #[inline(always)]
@@ -126,30 +165,26 @@ impl Memory for PPU {
     fn read(&self, address: u16) -> u8 {
         match address {
             0xFF40 => self.lcdc.into(),
-            0xFF41 => self.stat.into(),
+            // bit 7 is unused and reads 1
+            0xFF41 => u8::from(self.stat) | 0x80,
             0xFF42 => self.scy,
             0xFF43 => self.scx,
             0xFF44 => self.ly,
             0xFF45 => self.lyc,
-            0xFF46 => self.dma,
             0xFF47 => self.bg_palette.into(),
             0xFF48 => self.obj_palette_0.into(),
             0xFF49 => self.obj_palette_1.into(),
             0xFF4A => self.wy,
             0xFF4B => self.wx,
             OAM_START..=OAM_END => {
-                if self.dma_mode {
+                if self.oam_blocked() {
                     return 0xFF;
                 }
                 let (idx, field_idx) = get_oam_idx(address);
                 self.oam[idx].get_field(field_idx)
             }
             VRAM_START..=VRAM_END => {
-                if !self.lcdc.is_lcd_enabled() {
-                    return 0xFF;
-                }
-
-                if self.stat.get_mode() == Mode::LcdTransfer {
+                if self.vram_blocked() {
                     return 0xFF;
                 }
 
@@ -161,37 +196,180 @@ impl Memory for PPU {
 
     fn write(&mut self, address: u16, byte: u8) {
         match address {
-            0xFF40 => self.lcdc = Lcdc::new(byte),
-            0xFF41 => self.stat = Stat::new(byte),
+            0xFF40 => self.write_lcdc(byte),
+            0xFF41 => self.write_stat(byte),
             0xFF42 => self.scy = byte,
             0xFF43 => self.scx = byte,
             0xFF44 => self.ly = byte,
-            0xFF45 => self.lyc = byte,
-            0xFF46 => {
-                self.start_dma_transfer(byte);
+            0xFF45 => {
+                self.lyc = byte;
+                self.compare_ly();
+                self.update_stat_line();
             }
             0xFF47 => self.bg_palette = byte.into(),
             0xFF48 => self.obj_palette_0 = byte.into(),
             0xFF49 => self.obj_palette_1 = byte.into(),
             0xFF4A => self.wy = byte,
             0xFF4B => self.wx = byte,
-            OAM_START..=OAM_END => {
-                let (idx, field_idx) = get_oam_idx(address);
-                self.oam[idx].set_field(byte, field_idx);
+            OAM_START..=OAM_END if !self.oam_blocked() => self.write_oam(address, byte),
+            VRAM_START..=VRAM_END if !self.vram_blocked() => {
+                self.vram[(address - VRAM_START) as usize] = byte
             }
-            VRAM_START..=VRAM_END => self.vram[(address - VRAM_START) as usize] = byte,
+            OAM_START..=OAM_END | VRAM_START..=VRAM_END => {}
             _ => unreachable!(),
         }
     }
 }
 
+impl Savestate for PPU {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u64(self.ticks);
+        state.write_bytes(&self.vram);
+        self.oam
+            .iter()
+            .for_each(|entry| save_oam_entry(entry, state));
+
+        state.write_u8(self.active_sprites.len() as u8);
+        self.active_sprites
+            .iter()
+            .for_each(|entry| save_oam_entry(entry, state));
+
+        state.write_u8(self.lcdc.into());
+        state.write_u8(self.stat.into());
+        state.write_bool(self.stat_line);
+        state.write_u8(self.ly);
+        state.write_u8(self.lyc);
+        state.write_u8(self.scy);
+        state.write_u8(self.scx);
+        state.write_u8(self.wy);
+        state.write_u8(self.wx);
+        state.write_u8(self.window_line);
+        state.write_bool(self.wy_triggered);
+        state.write_bool(self.window_full_line);
+        state.write_u8(self.bg_palette.into());
+        state.write_u8(self.obj_palette_0.into());
+        state.write_u8(self.obj_palette_1.into());
+
+        self.fetcher.save_state(state);
+        state.write_u8(self.obj_fifo.len() as u8);
+        self.obj_fifo
+            .iter()
+            .for_each(|pixel| state.write_u8(pixel.to_byte()));
+        match &self.obj_fetch {
+            Some((entry, dots)) => {
+                state.write_u8(*dots);
+                save_oam_entry(entry, state);
+            }
+            None => state.write_u8(0),
+        }
+        state.write_u8(self.lcd_x);
+        state.write_u8(self.discard);
+        state.write_u8(self.startup);
+        state.write_bool(self.first_line);
+        state.write_bool(self.skip_frame);
+
+        self.buffer
+            .iter()
+            .for_each(|pixel| state.write_u8(pixel.get_color() as u8));
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SavestateError> {
+        self.ticks = state.read_u64()?;
+        state.read_into(&mut self.vram)?;
+
+        for entry in self.oam.iter_mut() {
+            load_oam_entry(entry, state)?;
+        }
+
+        let active_count = state.read_u8()? as usize;
+        if active_count > 10 {
+            return Err(SavestateError::InvalidValue("active sprite count"));
+        }
+
+        self.active_sprites.clear();
+        for _ in 0..active_count {
+            let mut entry = OamEntry::new();
+            load_oam_entry(&mut entry, state)?;
+            self.active_sprites.push(entry);
+        }
+
+        self.lcdc = state.read_u8()?.into();
+        self.stat = state.read_u8()?.into();
+        self.stat_line = state.read_bool()?;
+        self.ly = state.read_u8()?;
+        self.lyc = state.read_u8()?;
+        self.scy = state.read_u8()?;
+        self.scx = state.read_u8()?;
+        self.wy = state.read_u8()?;
+        self.wx = state.read_u8()?;
+        self.window_line = state.read_u8()?;
+        self.wy_triggered = state.read_bool()?;
+        self.window_full_line = state.read_bool()?;
+        self.bg_palette = state.read_u8()?.into();
+        self.obj_palette_0 = state.read_u8()?.into();
+        self.obj_palette_1 = state.read_u8()?.into();
+
+        self.fetcher.load_state(state)?;
+
+        let obj_count = state.read_u8()?;
+        if obj_count > 8 {
+            return Err(SavestateError::InvalidValue("object fifo length"));
+        }
+
+        self.obj_fifo.clear();
+        for _ in 0..obj_count {
+            self.obj_fifo
+                .push_back(FifoPixel::from_byte(state.read_u8()?));
+        }
+
+        self.obj_fetch = match state.read_u8()? {
+            0 => None,
+            dots => {
+                let mut entry = OamEntry::new();
+                load_oam_entry(&mut entry, state)?;
+                Some((entry, dots))
+            }
+        };
+        self.lcd_x = state.read_u8()?;
+        self.discard = state.read_u8()?;
+        self.startup = state.read_u8()?;
+        self.first_line = state.read_bool()?;
+        self.skip_frame = state.read_bool()?;
+
+        for pixel in self.buffer.iter_mut() {
+            let color = match state.read_u8()? {
+                0 => Color::C0,
+                1 => Color::C1,
+                2 => Color::C2,
+                3 => Color::C3,
+                4 => Color::Off,
+                _ => return Err(SavestateError::InvalidValue("pixel color")),
+            };
+            *pixel = Pixel::new(color);
+        }
+
+        self.frame_ready = false;
+        Ok(())
+    }
+}
+
+fn save_oam_entry(entry: &OamEntry, state: &mut StateWriter) {
+    (0..4).for_each(|field| state.write_u8(entry.get_field(field)));
+}
+
+fn load_oam_entry(entry: &mut OamEntry, state: &mut StateReader) -> Result<(), SavestateError> {
+    for field in 0..4 {
+        entry.set_field(state.read_u8()?, field);
+    }
+    Ok(())
+}
+
 impl PPU {
     pub fn new(interrupts: Rc<RefCell<Interrupts>>) -> Self {
         // blarrgs' test -> 0x94
         let ly: u8 = 0x00;
 
         PPU {
-            cycles: 0,
             ticks: 0,
             interrupts,
             oam: [OamEntry::new(); OAM_COUNT],
@@ -200,46 +378,101 @@ impl PPU {
             ly,
             lyc: 0x00,
             stat: 0x85.into(),
+            stat_line: false,
             scy: 0x00,
             scx: 0x00,
             wy: 0x00,
             wx: 0x00,
-            dma: 0xFF,
+            window_line: 0,
+            wy_triggered: false,
+            window_full_line: false,
             bg_palette: 0xFC.into(),
             obj_palette_0: 0x00.into(),
             obj_palette_1: 0x00.into(),
-            machine_cycles: 0,
-            dma_mode: false,
-            dma_cycles: 0,
             buffer: [Pixel::default(); SCREEN_WIDTH * SCREEN_HEIGHT],
             active_sprites: vec![],
-            background_priority: [false; SCREEN_WIDTH],
+            fetcher: Fetcher::new(),
+            obj_fifo: VecDeque::new(),
+            obj_fetch: None,
+            lcd_x: 0,
+            discard: 0,
+            startup: 0,
+            frame_ready: false,
+            first_line: false,
+            skip_frame: false,
         }
     }
 
-    fn machine_cycle(&mut self) {
-        if self.dma_mode {
-            self.dma_cycles += 1;
-            self.machine_cycles += 1;
+    pub fn mode(&self) -> Mode {
+        self.stat.get_mode()
+    }
 
-            if self.dma_cycles >= 160 {
-                self.dma_mode = false;
-            }
+    pub fn ly(&self) -> u8 {
+        self.ly
+    }
+
+    pub fn is_lcd_enabled(&self) -> bool {
+        self.lcdc.is_lcd_enabled()
+    }
+
+    /// # Access by the cpu
+    /// The PPU reads OAM in modes 2 and 3 and VRAM in mode 3, the cpu reads
+    /// 0xFF from them and its writes are dropped. The mode is the one at the
+    /// start of the M-cycle of the access, so a read on the dot mode 3 ends
+    /// still fails. DMA transfers are handled by the bus
+    pub fn oam_blocked(&self) -> bool {
+        self.lcdc.is_lcd_enabled() && matches!(self.mode(), Mode::OamSearch | Mode::LcdTransfer)
+    }
+
+    pub fn vram_blocked(&self) -> bool {
+        self.lcdc.is_lcd_enabled() && self.mode() == Mode::LcdTransfer
+    }
+
+    /// Writes OAM regardless of the mode, for DMA transfers
+    pub(crate) fn write_oam(&mut self, address: u16, byte: u8) {
+        let (idx, field_idx) = get_oam_idx(address);
+        self.oam[idx].set_field(byte, field_idx);
+    }
 
+    /// The cpu put `address` on the bus, in 0xFE00 - 0xFEFF during mode 2
+    /// that corrupts the OAM row the PPU is reading
+    pub fn oam_bug(&mut self, address: u16, access: OamBug) {
+        if !(OAM_START..=PROHIBITED_END).contains(&address)
+            || !self.lcdc.is_lcd_enabled()
+            || self.mode() != Mode::OamSearch
+        {
             return;
         }
 
-        self.machine_cycles += 1;
+        // one row of two objects per M-cycle
+        corrupt_oam(&mut self.oam, (self.ticks / 4) as usize, access);
     }
 
-    pub fn tick(&mut self) {
-        self.cycles += 1;
-        self.ticks += 1;
+    /// Whether a frame was completed since the last call
+    pub fn take_frame_ready(&mut self) -> bool {
+        std::mem::take(&mut self.frame_ready)
+    }
 
-        if self.cycles == 4 {
-            self.machine_cycle();
-            self.cycles = 0;
+    /// Restarts the timing of the current mode, used after registers were
+    /// restored from a save state that doesn't carry the PPU's dot counter
+    pub fn sync_ticks_to_mode(&mut self) {
+        self.ticks = match self.stat.get_mode() {
+            Mode::OamSearch | Mode::VBlank => 0,
+            Mode::LcdTransfer => OAM_TICK_LIMIT,
+            Mode::HBlank => OAM_TICK_LIMIT + LCD_TRANSFER_TICK_LIMIT,
+        };
+
+        if self.stat.get_mode() == Mode::LcdTransfer {
+            self.start_lcd_transfer();
         }
+    }
+
+    pub fn tick(&mut self) {
+        if !self.lcdc.is_lcd_enabled() {
+            return;
+        }
+
+        self.ticks += 1;
 
         let mode = self.stat.get_mode();
 
@@ -249,29 +482,137 @@ impl PPU {
             Mode::OamSearch => self.oam_search_mode(),
             Mode::LcdTransfer => self.lcd_transfer_mode(),
         }
+
+        self.update_stat_line();
     }
 
-    fn inc_ly(&mut self) {
-        self.ly += 1;
+    /// # STAT interrupt
+    /// The sources enabled in STAT are ORed into a single line, the interrupt
+    /// is requested when it goes from low to high. While one source holds it
+    /// high the others can't interrupt, e.g. the LY=LYC interrupt of a line
+    /// blocks its mode 2 interrupt.
+    ///
+    /// Entering VBlank raises the mode 2 source as well for an M-cycle
+    fn stat_line(&self) -> bool {
+        if !self.lcdc.is_lcd_enabled() {
+            return false;
+        }
 
-        if self.ly == self.lyc {
-            self.stat.set_lyc_ly_eq_flag(true);
+        let stat = &self.stat;
+        let vblank_start = self.ly == VBLANK_LINE_LIMIT && self.ticks < 4;
 
-            if self.stat.lyc_ly_eq_interrupt {
-                self.interrupts
-                    .borrow_mut()
-                    .create_interrupt(InterruptType::LCDSTAT);
+        (stat.lyc_ly_eq_interrupt && stat.lyc_ly_eq_flag)
+            || match stat.get_mode() {
+                Mode::HBlank => stat.hblank_interrupt && !self.first_line,
+                Mode::VBlank => stat.vblank_interrupt || (stat.oam_interrupt && vblank_start),
+                Mode::OamSearch => stat.oam_interrupt,
+                Mode::LcdTransfer => false,
             }
-        } else {
-            self.stat.set_lyc_ly_eq_flag(false);
+    }
+
+    fn update_stat_line(&mut self) {
+        let line = self.stat_line();
+
+        if line && !self.stat_line {
+            self.interrupts
+                .borrow_mut()
+                .create_interrupt(InterruptType::LCDSTAT);
+        }
+        self.stat_line = line;
+    }
+
+    /// Writing STAT on a DMG enables every source for a cycle before the
+    /// written value takes effect. In HBlank, VBlank or with LY=LYC that
+    /// requests an interrupt, which some games rely on
+    fn write_stat(&mut self, byte: u8) {
+        let spurious = self.lcdc.is_lcd_enabled()
+            && (matches!(self.mode(), Mode::HBlank | Mode::VBlank) || self.stat.lyc_ly_eq_flag);
+
+        if spurious && !self.stat_line {
+            self.interrupts
+                .borrow_mut()
+                .create_interrupt(InterruptType::LCDSTAT);
+            self.stat_line = true;
         }
+
+        self.stat.write(byte);
+        self.update_stat_line();
+    }
+
+    /// # LCD on and off
+    /// With the lcd off the PPU stands still on line 0 in mode 0, VRAM and OAM
+    /// are free to access and the screen is blank, lighter than color 0.
+    ///
+    /// Turning it back on starts line 0 without a mode 2, mode 0 stands in
+    /// for it and the line is 4 dots short. The first frame is not shown, the
+    /// screen stays blank until the next VBlank
+    fn write_lcdc(&mut self, byte: u8) {
+        let was_enabled = self.lcdc.is_lcd_enabled();
+        self.lcdc = Lcdc::new(byte);
+
+        match (was_enabled, self.lcdc.is_lcd_enabled()) {
+            (true, false) => self.turn_lcd_off(),
+            (false, true) => self.turn_lcd_on(),
+            _ => {}
+        }
+    }
+
+    fn turn_lcd_off(&mut self) {
+        self.ticks = 0;
+        self.stat.set_mode(Mode::HBlank);
+        self.stat_line = false;
+        self.reset_ly();
+        self.reset_window();
+        self.clear_active_sprites();
+        // drops the line being drawn
+        self.start_lcd_transfer();
+        self.first_line = false;
+        self.buffer.fill(Pixel::new(Color::Off));
+    }
+
+    fn turn_lcd_on(&mut self) {
+        self.ticks = 4;
+        self.first_line = true;
+        self.skip_frame = true;
+        self.compare_ly();
+        self.check_wy();
+        self.update_stat_line();
+    }
+
+    /// Sets LCDC without turning the lcd on or off
+    pub fn restore_lcdc(&mut self, byte: u8) {
+        self.lcdc = Lcdc::new(byte);
+    }
+
+    /// Sets STAT including the mode and LY=LYC flag, without interrupting
+    pub fn restore_stat(&mut self, byte: u8) {
+        self.stat = Stat::new(byte);
+        self.stat_line = self.stat_line();
+    }
+
+    fn compare_ly(&mut self) {
+        self.stat.set_lyc_ly_eq_flag(self.ly == self.lyc);
+    }
+
+    fn inc_ly(&mut self) {
+        self.ly += 1;
+        self.compare_ly();
     }
 
     fn reset_ly(&mut self) {
         self.ly = 0;
+        self.compare_ly();
     }
 
     fn hblank_mode(&mut self) {
+        if self.first_line {
+            if self.ticks >= OAM_TICK_LIMIT {
+                self.first_line = false;
+                self.enter_lcd_transfer();
+            }
+            return;
+        }
+
         if self.ticks >= HBLANK_TICK_LIMIT {
             // finished one line
             self.inc_ly();
@@ -279,45 +620,53 @@ impl PPU {
             if self.ly >= VBLANK_LINE_LIMIT {
                 // means 1 frame has finished processing
                 self.stat.set_mode(Mode::VBlank);
+                self.frame_ready = true;
+                self.skip_frame = false;
 
                 self.interrupts
                     .borrow_mut()
                     .create_interrupt(InterruptType::VBLANK);
-
-                if self.stat.vblank_interrupt {
-                    self.interrupts
-                        .borrow_mut()
-                        .create_interrupt(InterruptType::LCDSTAT);
-                }
             } else {
                 self.stat.set_mode(Mode::OamSearch);
+                self.check_wy();
             }
 
             self.ticks -= HBLANK_TICK_LIMIT;
         }
     }
 
-    fn inc_wy(&mut self) {}
+    /// Latches the window's vertical start, checked as a line starts
+    fn check_wy(&mut self) {
+        if self.ly == self.wy {
+            self.wy_triggered = true;
+        }
+    }
+
+    fn inc_window_line(&mut self) {
+        self.window_line = self.window_line.wrapping_add(1);
+    }
 
-    fn reset_wy(&mut self) {
-        self.wy = 0;
+    fn reset_window(&mut self) {
+        self.window_line = 0;
+        self.wy_triggered = false;
+        self.window_full_line = false;
     }
 
     fn vblank_mode(&mut self) {
-        if self.ticks >= HBLANK_TICK_LIMIT {
-            self.inc_ly();
+        // line 153 reads LY = 0 after its first M-cycle
+        if self.ly == MAX_LINE_LIMIT - 1 && self.ticks >= 4 {
+            self.reset_ly();
+        }
 
-            if self.ly >= MAX_LINE_LIMIT {
-                // all 153 lines have finished
+        if self.ticks >= HBLANK_TICK_LIMIT {
+            if self.ly == 0 {
+                // all 154 lines have finished
                 // move to next frame
                 self.stat.set_mode(Mode::OamSearch);
-
-                if self.stat.oam_interrupt {
-                    self.interrupts
-                        .borrow_mut()
-                        .create_interrupt(InterruptType::LCDSTAT);
-                }
-                self.reset_ly();
+                self.reset_window();
+                self.check_wy();
+            } else {
+                self.inc_ly();
             }
 
             self.ticks -= HBLANK_TICK_LIMIT;
@@ -328,315 +677,614 @@ impl PPU {
         self.active_sprites.clear();
     }
 
+    /// The first 10 objects in OAM on this line. X doesn't matter, objects
+    /// left or right of the screen take up a slot all the same
     fn load_active_sprites(&mut self) {
-        let height = match self.lcdc.obj_size {
-            true => 16,
-            false => 8,
-        };
-        let cur_line = self.ly;
+        let height = self.lcdc.obj_height();
+        // Y is the line + 16, objects partly above the screen have Y < 16
+        let line = self.ly.wrapping_add(16);
 
         self.active_sprites = self
             .oam
             .into_iter()
-            .filter(|sprite| {
-                let y_pos = sprite.y_pos.wrapping_sub(16);
-                cur_line >= y_pos && cur_line < (y_pos + height)
-            })
+            .filter(|sprite| sprite.y_pos <= line && line < sprite.y_pos.saturating_add(height))
             .take(10)
             .collect();
     }
 
     fn oam_search_mode(&mut self) {
         if self.ticks >= OAM_TICK_LIMIT {
-            self.clear_active_sprites();
-            self.load_active_sprites();
-            self.stat.set_mode(Mode::LcdTransfer);
+            self.enter_lcd_transfer();
         }
     }
 
-    fn render_line_to_buffer(&mut self) {
-        if !self.lcdc.is_lcd_enabled() {
-            return;
-        }
+    fn enter_lcd_transfer(&mut self) {
+        self.clear_active_sprites();
+        self.load_active_sprites();
+        self.start_lcd_transfer();
+        self.stat.set_mode(Mode::LcdTransfer);
+    }
 
-        self.render_line();
-        self.render_sprite_line();
+    fn start_lcd_transfer(&mut self) {
+        self.fetcher.reset();
+        self.obj_fifo.clear();
+        self.obj_fetch = None;
+        self.lcd_x = 0;
+        self.discard = self.scx % 8;
+        // the first tile is fetched twice, the first fetch is thrown away
+        self.startup = 6;
     }
 
+    /// One dot of mode 3
     fn lcd_transfer_mode(&mut self) {
-        if self.ticks >= (LCD_TRANSFER_TICK_LIMIT + OAM_TICK_LIMIT) {
-            self.render_line_to_buffer();
+        if self.startup > 0 {
+            self.startup -= 1;
+            return;
+        }
 
-            self.stat.set_mode(Mode::HBlank);
-            if self.stat.hblank_interrupt {
-                self.interrupts
-                    .borrow_mut()
-                    .create_interrupt(InterruptType::LCDSTAT);
-            }
+        // the background fetcher waits while an object is read
+        if self.fetch_object() {
+            return;
         }
-    }
 
-    fn start_dma_transfer(&mut self, byte: u8) {
-        self.dma_mode = true;
-        self.dma = byte;
-        self.dma_cycles = 0;
-    }
+        if !self.fetcher.window && self.is_window_start() {
+            self.fetcher.start_window();
+            self.discard = match self.wx {
+                _ if self.window_full_line => 0,
+                // the fine scroll pixels being dropped go on with the window
+                0 if self.discard > 0 => self.discard,
+                // the window starts left of the screen for WX < 7
+                wx => 7u8.saturating_sub(wx),
+            };
+        }
 
-    #[inline(always)]
-    fn is_window(&mut self, x: u8) -> bool {
-        self.lcdc.window_enable && x >= self.wx.wrapping_sub(7) && self.ly >= self.wy
-    }
+        let regs = FetchRegisters {
+            lcdc: self.lcdc,
+            scx: self.scx,
+            bg_y: self.ly.wrapping_add(self.scy),
+            window_y: self.window_line,
+        };
+        self.fetcher.process(&self.vram, regs);
+
+        if self.discard == 0 && self.lcdc.obj_enable {
+            if let Some(idx) = self.next_object() {
+                if self.fetcher.ready_for_object() {
+                    let sprite = self.active_sprites.remove(idx);
+                    // this dot is the first of the 6 it takes
+                    self.obj_fetch = Some((sprite, 5));
+                }
+                return;
+            }
+        }
 
-    fn render_sprite_line(&mut self) {
-        if !self.lcdc.obj_enable {
+        let Some(pixel) = self.fetcher.pop() else {
             return;
+        };
+
+        if self.discard > 0 {
+            self.discard -= 1;
+            return;
+        }
+
+        let obj = self.obj_fifo.pop_front();
+        if !self.skip_frame {
+            let pixel = self.mix_pixel(pixel, obj);
+            self.write_pixel(self.lcd_x, self.ly, pixel);
         }
 
-        // active sprites sorted by their x coordinate
-        // otherwise by their index
-        // stable sort, hence just sorting by x pos is enough
-        self.active_sprites.sort_by_key(|sprite| sprite.x_pos);
+        self.lcd_x += 1;
+        if self.lcd_x as usize == SCREEN_WIDTH {
+            if self.fetcher.window {
+                self.inc_window_line();
+            }
+            // a window started by WX = 166 covers the next line
+            self.window_full_line = self.fetcher.window && self.wx == 166;
 
-        let current_line = self.ly;
-        let height: u8 = match self.lcdc.obj_size {
-            true => 16,
-            false => 8,
+            self.stat.set_mode(Mode::HBlank);
+        }
+    }
+
+    /// Runs a dot of an object fetch, false when there is none
+    fn fetch_object(&mut self) -> bool {
+        let Some((sprite, dots)) = self.obj_fetch.as_mut() else {
+            return false;
         };
 
-        let mut pixel_data = Vec::new();
+        if *dots > 1 {
+            *dots -= 1;
+            return true;
+        }
+
+        let sprite = *sprite;
+        self.obj_fetch = None;
+        self.push_object(sprite);
+        true
+    }
 
-        for sprite in self.active_sprites.iter().rev() {
-            let y_flip = sprite.y_flipped();
-            let x_flip = sprite.x_flipped();
-            let palette = match sprite.get_palette_number() {
-                false => self.obj_palette_0,
-                true => self.obj_palette_1,
-            };
+    /// Of the objects that start at or left of the next pixel the one
+    /// furthest left, then the first in OAM. That's the DMG's priority, the
+    /// object fetched first wins
+    fn next_object(&self) -> Option<usize> {
+        self.active_sprites
+            .iter()
+            .enumerate()
+            .filter(|(_, sprite)| sprite.x_pos <= self.lcd_x + 8)
+            .min_by_key(|(idx, sprite)| (sprite.x_pos, *idx))
+            .map(|(idx, _)| idx)
+    }
 
-            // sprite's coordinates
-            let x_pos = sprite.x_pos.wrapping_sub(8);
-            let y_pos = sprite.y_pos.wrapping_sub(16);
+    #[inline(always)]
+    fn is_window_start(&self) -> bool {
+        self.lcdc.window_enable
+            && self.wy_triggered
+            && (self.window_full_line || self.lcd_x + 7 >= self.wx)
+    }
 
-            // find exact line based on current line number and y flip
-            let line_no = if y_flip {
-                height
-                    .wrapping_sub(current_line)
-                    .wrapping_add(y_pos)
-                    .wrapping_sub(1)
-            } else {
-                current_line.wrapping_sub(y_pos)
-            };
+    /// Merges an object's row into the object FIFO. Pixels of objects fetched
+    /// earlier win, those are the ones further left or first in OAM
+    fn push_object(&mut self, sprite: OamEntry) {
+        let height = self.lcdc.obj_height();
 
-            let tile_idx = sprite.tile_idx;
+        // find exact line based on current line number and y flip,
+        // masked in case the object size changed since the OAM search
+        let mut line_no = self.ly.wrapping_add(16).wrapping_sub(sprite.y_pos) & (height - 1);
+        if sprite.y_flipped() {
+            line_no = height - 1 - line_no;
+        }
 
-            // points to the base tile data being used
-            let base_tile_address = tile_idx as usize * 16;
+        // 8x16 objects are an even tile and the one after it
+        let tile = match self.lcdc.obj_size {
+            true => sprite.tile_idx & 0xFE,
+            false => sprite.tile_idx,
+        };
 
-            // use the correct byte for tile data based on the line number
-            // each line takes up 2 bytes
-            let tile_address = base_tile_address + (line_no as usize * 2);
-            let tile_low = self.vram[tile_address];
-            let tile_high = self.vram[tile_address + 1];
+        // each line takes up 2 bytes
+        let tile_address = tile as usize * 16 + (line_no as usize * 2);
+        let row = tile_row(
+            self.vram[tile_address],
+            self.vram[tile_address + 1],
+            sprite.x_flipped(),
+        );
 
-            // render the pixel line
-            for px in 0..8 {
-                let target = x_pos + px;
+        // objects with X < 8 are partly left of the screen
+        let hidden = (self.lcd_x + 8 - sprite.x_pos) as usize;
 
-                if !self.in_inside_viewport(target) {
-                    continue;
-                }
+        for (idx, color) in row.into_iter().skip(hidden).enumerate() {
+            let pixel = FifoPixel {
+                color,
+                palette: sprite.get_palette_number(),
+                bg_priority: sprite.bg_priority(),
+            };
 
-                // bit 7 of tile -> points to first pixel
-                // thus if is flipped, take 7 - px value
-                let pixel_x = if !x_flip { 7 - px } else { px };
-
-                let (low, high) = (
-                    tile_low.is_bit_set(pixel_x.into()),
-                    tile_high.is_bit_set(pixel_x.into()),
-                );
-
-                let palette_index = Palette::palette_index(high, low);
-                if palette_index != 0 {
-                    let color = palette.get_color(palette_index);
-                    let pixel = Pixel::new(color);
-
-                    if sprite.bg_priority() {
-                        if self.background_priority[target as usize] {
-                            pixel_data.push((target, current_line, pixel));
-                        }
-                    } else {
-                        pixel_data.push((target, current_line, pixel));
-                    }
-                }
+            match self.obj_fifo.get_mut(idx) {
+                Some(slot) if slot.color == 0 => *slot = pixel,
+                Some(_) => {}
+                None => self.obj_fifo.push_back(pixel),
             }
         }
+    }
 
-        pixel_data.iter().for_each(|px| {
-            self.write_pixel(px.0, px.1, px.2);
-        });
+    fn mix_pixel(&self, bg: FifoPixel, obj: Option<FifoPixel>) -> Pixel {
+        // with LCDC bit 0 off background and window are blank
+        let bg_color = if self.lcdc.bg_priority { bg.color } else { 0 };
+
+        let color = match obj {
+            Some(obj)
+                if obj.color != 0
+                    && self.lcdc.obj_enable
+                    && !(obj.bg_priority && bg_color != 0) =>
+            {
+                let palette = match obj.palette {
+                    false => self.obj_palette_0,
+                    true => self.obj_palette_1,
+                };
+                palette.get_color(obj.color)
+            }
+            _ if self.lcdc.bg_priority => self.bg_palette.get_color(bg_color),
+            _ => Color::C0,
+        };
+
+        Pixel::new(color)
     }
 
-    fn get_pixel(&self, x: u8, y: u8) -> Pixel {
+    fn write_pixel(&mut self, x: u8, y: u8, pixel: Pixel) {
         let index = x as usize + (y as usize * SCREEN_WIDTH);
-        self.buffer[index]
+        self.buffer[index] = pixel;
     }
+}
 
-    fn in_inside_viewport(&self, x_pos: u8) -> bool {
-        (x_pos as usize) < SCREEN_WIDTH
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::{
+        oam::{OamBug, OamEntry},
+        registers::{Color, Mode},
+        PPU, SCREEN_WIDTH,
+    };
+    use crate::{bus::Memory, interrupt::Interrupts};
+
+    /// A PPU at the start of line 0
+    fn test_ppu() -> PPU {
+        let mut ppu = PPU::new(Rc::new(RefCell::new(Interrupts::new())));
+        while ppu.mode() != Mode::OamSearch {
+            ppu.tick();
+        }
+        ppu
     }
 
-    fn render_window_pixel(&mut self, x_coor: u8) {
-        let (tile_offset, x, y) = self.get_window_map_offset(x_coor);
-
-        // base address of the tile to be used
-        let base_tile_address = self.get_tile_address(tile_offset);
+    fn run_until_mode(ppu: &mut PPU, mode: Mode) {
+        while ppu.mode() != mode {
+            ppu.tick();
+        }
+    }
 
-        // which line to render
-        let line_no = (y % 8) * 2;
+    fn mode3_length(setup: impl FnOnce(&mut PPU)) -> u64 {
+        let mut ppu = test_ppu();
+        setup(&mut ppu);
+        run_until_mode(&mut ppu, Mode::LcdTransfer);
 
-        // each pixel line takes up 2 bytes
-        let tile_address = base_tile_address as usize + line_no as usize;
+        let mut dots = 0;
+        while ppu.mode() == Mode::LcdTransfer {
+            ppu.tick();
+            dots += 1;
+        }
+        dots
+    }
 
-        let tile_low = self.vram[tile_address];
-        let tile_high = self.vram[tile_address + 1];
-        let x_bit_pos = 7 - (x % 8);
+    fn add_sprite(ppu: &mut PPU, idx: usize, x_pos: u8) {
+        // lcd, objects and background on
+        ppu.write(0xFF40, 0x93);
+        // OAM is blocked in mode 2
+        ppu.oam[idx] = OamEntry {
+            y_pos: 16,
+            x_pos,
+            tile_idx: 1,
+            flags: 0,
+        };
+    }
 
-        let (low, high) = (
-            tile_low.is_bit_set(x_bit_pos as usize),
-            tile_high.is_bit_set(x_bit_pos as usize),
+    #[test]
+    fn test_mode3_length() {
+        assert_eq!(mode3_length(|_| {}), 172);
+        assert_eq!(mode3_length(|ppu| ppu.write(0xFF43, 0x13)), 175);
+        assert_eq!(
+            mode3_length(|ppu| {
+                ppu.write(0xFF40, 0xB1);
+                ppu.write(0xFF4B, 7 + 80);
+            }),
+            178
         );
 
-        let palette_index = Palette::palette_index(high, low);
-        self.set_background_priority(x_coor as usize, palette_index);
-        let color = self.bg_palette.get_color(palette_index);
-        let pixel = Pixel::new(color);
-        self.write_pixel(x_coor, self.ly, pixel);
+        // an object at the start of a tile waits for the fetcher
+        assert_eq!(mode3_length(|ppu| add_sprite(ppu, 0, 8)), 183);
+        assert_eq!(mode3_length(|ppu| add_sprite(ppu, 0, 8 + 5)), 178);
+        assert_eq!(
+            mode3_length(|ppu| {
+                add_sprite(ppu, 0, 8);
+                add_sprite(ppu, 1, 8);
+            }),
+            189
+        );
     }
 
-    fn set_background_priority(&mut self, x: usize, palette_index: usize) {
-        if palette_index == 0 {
-            self.background_priority[x] = true;
-        }
+    fn run_line(ppu: &mut PPU) {
+        run_until_mode(ppu, Mode::LcdTransfer);
+        run_until_mode(ppu, Mode::HBlank);
     }
 
-    fn render_background_pixel(&mut self, x_coor: u8) {
-        let (tile_offset, x, y) = self.get_background_map_offset(x_coor);
-
-        let base_tile_address = self.get_tile_address(tile_offset);
-        let line_no = (y % 8) * 2;
-
-        // each pixel line takes up 2 bytes
-        let tile_address = base_tile_address as usize + (line_no as usize);
-
-        let tile_low = self.vram[tile_address];
-        let tile_high = self.vram[tile_address + 1];
-        let x_bit_pos = 7 - (x % 8);
-
-        let (low, high) = (
-            tile_low.is_bit_set(x_bit_pos as usize),
-            tile_high.is_bit_set(x_bit_pos as usize),
-        );
+    #[test]
+    fn test_window() {
+        let mut ppu = test_ppu();
+        // window on, from the 0x9C00 map which is all tile 1 ( color 3 )
+        ppu.write(0xFF40, 0xF1);
+        ppu.vram[0x1C00..0x2000].fill(1);
+        ppu.vram[16..32].fill(0xFF);
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF4B, 7);
+
+        for _ in 0..5 {
+            run_line(&mut ppu);
+        }
+        assert_eq!(ppu.window_line, 5);
 
-        let palette_index = Palette::palette_index(high, low);
-        self.set_background_priority(x_coor as usize, palette_index);
-        let color = self.bg_palette.get_color(palette_index);
-        let pixel = Pixel::new(color);
-        self.write_pixel(x_coor, self.ly, pixel);
+        // hidden lines don't count
+        ppu.write(0xFF40, 0xD1);
+        for _ in 0..5 {
+            run_line(&mut ppu);
+        }
+        assert_eq!(ppu.window_line, 5);
+
+        // WY matched on line 0, moving it doesn't hide the window
+        ppu.write(0xFF40, 0xF1);
+        ppu.write(0xFF4A, 100);
+        run_line(&mut ppu);
+        assert_eq!(ppu.window_line, 6);
+        assert_eq!(ppu.buffer[10 * SCREEN_WIDTH].get_color(), Color::C3);
+
+        // WX = 166 draws the last pixel and then the whole next line
+        ppu.write(0xFF4B, 166);
+        run_line(&mut ppu);
+        let line = 11 * SCREEN_WIDTH;
+        assert_eq!(ppu.buffer[line + 158].get_color(), Color::C0);
+        assert_eq!(ppu.buffer[line + 159].get_color(), Color::C3);
+        ppu.write(0xFF4B, 200);
+        run_line(&mut ppu);
+        assert_eq!(ppu.buffer[line + SCREEN_WIDTH].get_color(), Color::C3);
+        assert_eq!(ppu.window_line, 8);
+
+        // the counter starts over with the next frame, WY = 100 isn't reached yet
+        run_until_mode(&mut ppu, Mode::VBlank);
+        run_line(&mut ppu);
+        assert_eq!(ppu.window_line, 0);
+        assert_eq!(ppu.buffer[0].get_color(), Color::C0);
     }
 
-    fn reset_line(&mut self) {
-        for x in 0..(SCREEN_WIDTH as u8) {
-            let pixel = Pixel::new(Color::C0);
-            self.background_priority[x as usize] = false;
-            self.write_pixel(x, self.ly, pixel);
+    #[test]
+    fn test_mid_scanline_writes() {
+        let mut ppu = test_ppu();
+        add_sprite(&mut ppu, 0, 8 + 100);
+        // tile 1, first row color 3
+        ppu.vram[16] = 0xFF;
+        ppu.vram[17] = 0xFF;
+        // color 3 of the object is 1
+        ppu.write(0xFF48, 0x54);
+
+        run_until_mode(&mut ppu, Mode::LcdTransfer);
+        while ppu.lcd_x < 80 {
+            ppu.tick();
         }
+        ppu.write(0xFF47, 0xFF);
+        run_until_mode(&mut ppu, Mode::HBlank);
+
+        let line = &ppu.buffer[..SCREEN_WIDTH];
+        assert_eq!(line[79].get_color(), Color::C0);
+        assert_eq!(line[80].get_color(), Color::C3);
+        assert_eq!(line[99].get_color(), Color::C3);
+        assert_eq!(line[100].get_color(), Color::C1);
+        assert_eq!(line[107].get_color(), Color::C1);
+        assert_eq!(line[108].get_color(), Color::C3);
     }
 
-    fn render_line(&mut self) {
-        self.reset_line();
+    #[test]
+    fn test_access_blocking() {
+        let mut ppu = test_ppu();
+        ppu.write(0xFF40, 0x91);
+
+        // mode 2, OAM only
+        ppu.write(0xFE00, 0x42);
+        ppu.write(0x8000, 0x42);
+        assert_eq!(ppu.read(0xFE00), 0xFF);
+        assert_eq!(ppu.read(0x8000), 0x42);
+        assert_eq!(ppu.oam[0].y_pos, 0);
+
+        // mode 3, both
+        run_until_mode(&mut ppu, Mode::LcdTransfer);
+        ppu.write(0x8000, 0x24);
+        assert_eq!(ppu.read(0x8000), 0xFF);
+        assert_eq!(ppu.vram[0], 0x42);
+        assert_eq!(ppu.read(0xFE00), 0xFF);
+
+        // free again on the dot HBlank starts
+        run_until_mode(&mut ppu, Mode::HBlank);
+        ppu.write(0xFE00, 0x42);
+        assert_eq!(ppu.read(0xFE00), 0x42);
+        assert_eq!(ppu.read(0x8000), 0x42);
+
+        // and blocked on the dot the next line starts
+        run_until_mode(&mut ppu, Mode::OamSearch);
+        assert_eq!(ppu.read(0xFE00), 0xFF);
+
+        // DMA transfers write regardless
+        ppu.write_oam(0xFE01, 0x24);
+        assert_eq!(ppu.oam[0].x_pos, 0x24);
+    }
 
-        for x_coor in 0..(SCREEN_WIDTH as u8) {
-            if !self.lcdc.bg_priority {
-                let pixel = Pixel::new(Color::C0);
-                self.write_pixel(x_coor, self.ly, pixel);
-                continue;
+    #[test]
+    fn test_oam_bug() {
+        let fill = |ppu: &mut PPU| {
+            for (idx, entry) in ppu.oam.iter_mut().enumerate() {
+                let byte = idx as u8 * 4;
+                *entry = OamEntry {
+                    y_pos: byte,
+                    x_pos: byte + 1,
+                    tile_idx: byte + 2,
+                    flags: byte + 3,
+                };
             }
+        };
 
-            if self.is_window(x_coor) {
-                self.render_window_pixel(x_coor);
-            } else {
-                self.render_background_pixel(x_coor);
-            };
+        let mut ppu = test_ppu();
+        ppu.write(0xFF40, 0x91);
+        fill(&mut ppu);
+        let oam = ppu.oam;
+
+        // row 0 is safe, so are addresses outside 0xFE00 - 0xFEFF
+        ppu.oam_bug(0xFE00, OamBug::Write);
+        ppu.oam_bug(0xC000, OamBug::Write);
+        for _ in 0..8 {
+            ppu.tick();
+        }
+        ppu.oam_bug(0xFF80, OamBug::Read);
+        assert_eq!(ppu.oam, oam);
+
+        // row 2, objects 4 and 5, words 0x1110 and 0x1312 after 0x0908 and 0x0D0C
+        ppu.oam_bug(0xFEFF, OamBug::Write);
+        assert_eq!(ppu.oam[4].y_pos, ((0x10 ^ 0x0C) & (0x08 ^ 0x0C)) ^ 0x0C);
+        assert_eq!(ppu.oam[4].x_pos, ((0x11 ^ 0x0D) & (0x09 ^ 0x0D)) ^ 0x0D);
+        assert_eq!(ppu.oam[4].tile_idx, oam[2].tile_idx);
+        assert_eq!(ppu.oam[4].flags, oam[2].flags);
+        assert_eq!(ppu.oam[5], oam[3]);
+
+        // row 6 read by POP, rows 4 and 5 are mixed and copied first
+        fill(&mut ppu);
+        for _ in 0..16 {
+            ppu.tick();
         }
+        ppu.oam_bug(0xFE00, OamBug::ReadIncDec);
+        let (a, b, c, d) = (0x20, 0x28, 0x30, 0x2C);
+        let first = (b & (a | c | d)) | (a & c & d);
+        assert_eq!(ppu.oam[8].y_pos, first);
+        assert_eq!(ppu.oam[10].y_pos, first);
+        // then the read mixes row 6 with the copy, b | (a & c) is b again
+        assert_eq!(ppu.oam[12].y_pos, first);
+        assert_eq!(ppu.oam[13], oam[11]);
+        assert_eq!(ppu.oam[14], oam[14]);
     }
 
-    fn get_window_map_offset(&mut self, x_coor: u8) -> (u8, u8, u8) {
-        let tilemap = match self.lcdc.window_tile_map_area {
-            true => 0x9C00 - VRAM_START,
-            false => 0x9800 - VRAM_START,
+    #[test]
+    fn test_stat_interrupt() {
+        let mut ppu = test_ppu();
+        let interrupts = ppu.interrupts.clone();
+        let take_stat = || {
+            let requested = interrupts.borrow().read(0xFF0F) & 0x02 != 0;
+            interrupts.borrow_mut().write(0xFF0F, 0x00);
+            requested
         };
 
-        // gets the real position of the window's x coordinate 
-        // wx = 7 places the window at left most of the screen
-        let window_x = self.wx.wrapping_sub(7);
-        
-        let mut x_offset = x_coor.wrapping_add(self.scx);
-        let y_offset = self.ly.wrapping_sub(self.wy);
-
-        if x_offset >= window_x {
-            x_offset = x_coor - window_x;
+        // no LY=LYC and mode 2, the write doesn't interrupt
+        ppu.write(0xFF45, 10);
+        ppu.write(0xFF41, 0x08);
+        assert!(!take_stat());
+        assert_eq!(ppu.mode(), Mode::OamSearch);
+
+        run_until_mode(&mut ppu, Mode::HBlank);
+        assert!(take_stat());
+
+        // the mode 2 source takes over the high line, no second interrupt
+        ppu.write(0xFF41, 0x28);
+        run_until_mode(&mut ppu, Mode::OamSearch);
+        assert!(!take_stat());
+
+        // in HBlank with the line low, the write does interrupt
+        ppu.write(0xFF41, 0x00);
+        run_until_mode(&mut ppu, Mode::HBlank);
+        assert!(!take_stat());
+        ppu.write(0xFF41, 0x00);
+        assert!(take_stat());
+
+        // LY=LYC on line 10
+        ppu.write(0xFF41, 0x40);
+        take_stat();
+        while ppu.ly() != 10 {
+            ppu.tick();
         }
-
-        (
-            self.get_tile_offset_from_map(x_offset, y_offset, tilemap),
-            x_offset,
-            y_offset,
-        )
+        assert!(take_stat());
+        assert_eq!(ppu.read(0xFF41) & 0x04, 0x04);
+
+        // writing LYC compares right away
+        ppu.write(0xFF45, 11);
+        assert_eq!(ppu.read(0xFF41) & 0x04, 0x00);
+        ppu.write(0xFF45, 10);
+        assert!(take_stat());
+
+        // line 153 reads LY = 0 after 4 dots, LYC = 0 matches there
+        ppu.write(0xFF45, 0);
+        while ppu.ly() != 153 {
+            ppu.tick();
+        }
+        take_stat();
+        for _ in 0..4 {
+            ppu.tick();
+        }
+        assert_eq!(ppu.ly(), 0);
+        assert_eq!(ppu.mode(), Mode::VBlank);
+        assert!(take_stat());
+
+        // and stays high into line 0
+        run_until_mode(&mut ppu, Mode::OamSearch);
+        assert_eq!(ppu.ly(), 0);
+        assert!(!take_stat());
     }
 
-    fn get_background_map_offset(&mut self, x_coor: u8) -> (u8, u8, u8) {
-        let tilemap = match self.lcdc.bg_tile_map_area {
-            true => 0x9C00 - VRAM_START,
-            false => 0x9800 - VRAM_START,
-        };
+    #[test]
+    fn test_lcd_off() {
+        let mut ppu = test_ppu();
+        while ppu.ly() != 5 {
+            ppu.tick();
+        }
+        run_until_mode(&mut ppu, Mode::LcdTransfer);
 
-        let x_offset = x_coor.wrapping_add(self.scx);
-        let y_offset = self.ly.wrapping_add(self.scy);
-
-        (
-            self.get_tile_offset_from_map(x_offset, y_offset, tilemap),
-            x_offset,
-            y_offset,
-        )
-    }
-
-    /// tilemaps are 32 x 32 bytes array
-    /// tilemaps contain one byte index of teh tile to be displayed
-    /// one tile has data for 8x8 pixels
-    /// given parameters are pixel offsets
-    /// thus must be divided by 8 to first get tile offsets
-    /// and then can be used to index into vram
-    fn get_tile_offset_from_map(&self, x_offset: u8, y_offset: u8, tilemap: u16) -> u8 {
-        let tile_x = (x_offset as usize) / 8;
-        let tile_y = (y_offset as usize / 8) * 32;
-        let index = tilemap as usize + tile_x + tile_y;
-        self.vram[index]
-    }
-
-    fn get_tile_address(&self, offset: u8) -> u16 {
-        match self.lcdc.bg_tile_data_area {
-            true => offset as u16 * 16,
-            false => {
-                // interpret it a possible negative number first
-                // then multiply by 16
-                let signed_offset = (offset as i8 as i16).wrapping_mul(16);
-                (0x9000 - VRAM_START).wrapping_add(signed_offset as u16)
-            }
+        ppu.write(0xFF40, 0x11);
+        for _ in 0..1000 {
+            ppu.tick();
         }
+        assert_eq!(ppu.ly(), 0);
+        assert_eq!(ppu.mode(), Mode::HBlank);
+        assert!(ppu.buffer.iter().all(|p| p.get_color() == Color::Off));
+        ppu.write(0xFE00, 0x42);
+        ppu.write(0x8000, 0x42);
+        assert_eq!(ppu.read(0xFE00), 0x42);
+        assert_eq!(ppu.read(0x8000), 0x42);
+
+        // line 0 reports mode 0 instead of 2 and is 4 dots short
+        ppu.write(0xFF40, 0x91);
+        let mut dots = 0;
+        while ppu.mode() == Mode::HBlank {
+            assert_eq!(ppu.read(0xFE00), 0x42);
+            ppu.tick();
+            dots += 1;
+        }
+        assert_eq!(dots, 76);
+        while ppu.ly() == 0 {
+            ppu.tick();
+            dots += 1;
+        }
+        assert_eq!(dots, 452);
+
+        // the first frame stays blank, the next one is drawn
+        run_until_mode(&mut ppu, Mode::VBlank);
+        assert!(ppu.take_frame_ready());
+        assert_eq!(ppu.buffer[0].get_color(), Color::Off);
+        run_until_mode(&mut ppu, Mode::OamSearch);
+        run_until_mode(&mut ppu, Mode::VBlank);
+        assert_eq!(ppu.buffer[0].get_color(), Color::C0);
     }
 
-    fn write_pixel(&mut self, x: u8, y: u8, pixel: Pixel) {
-        let index = x as usize + (y as usize * SCREEN_WIDTH);
-        self.buffer[index] = pixel;
+    #[test]
+    fn test_sprite_selection() {
+        let mut ppu = test_ppu();
+        // objects 8x16
+        ppu.write(0xFF40, 0x97);
+        ppu.write(0xFF48, 0xE4);
+        for row in 0..8 {
+            // tile 2 color 1, tile 3 color 2
+            ppu.vram[32 + row * 2] = 0xFF;
+            ppu.vram[49 + row * 2] = 0xFF;
+        }
+        // tiles 4 and 5 color 3
+        ppu.vram[64..96].fill(0xFF);
+
+        let mut object = |idx: usize, y_pos: u8, x_pos: u8, tile_idx: u8| {
+            ppu.oam[idx] = OamEntry {
+                y_pos,
+                x_pos,
+                tile_idx,
+                flags: 0,
+            };
+        };
+        // bit 0 of the tile is ignored, the top half is tile 2
+        object(0, 16, 8, 3);
+        // 8 lines above the screen, line 0 shows the bottom half
+        object(1, 8, 8 + 20, 2);
+        // further left than object 0, it wins over the lower OAM index
+        object(4, 16, 4, 4);
+        // off screen, still counts toward the 10 per line
+        for idx in 5..12 {
+            object(idx, 16, 0, 4);
+        }
+        object(12, 16, 8 + 100, 4);
+
+        run_until_mode(&mut ppu, Mode::LcdTransfer);
+        run_until_mode(&mut ppu, Mode::HBlank);
+
+        let line = &ppu.buffer[..SCREEN_WIDTH];
+        assert_eq!(line[0].get_color(), Color::C3);
+        assert_eq!(line[3].get_color(), Color::C3);
+        assert_eq!(line[4].get_color(), Color::C1);
+        assert_eq!(line[7].get_color(), Color::C1);
+        assert_eq!(line[20].get_color(), Color::C2);
+        assert_eq!(line[27].get_color(), Color::C2);
+        assert_eq!(line[100].get_color(), Color::C0);
     }
 }
@@ -1,6 +1,12 @@
 use std::collections::VecDeque;
 
-use super::registers::Color;
+use crate::{
+    bus::ranges::{VRAM_SIZE, VRAM_START},
+    savestate::{Savestate, SavestateError, StateReader, StateWriter},
+    utils::BitPosCheck,
+};
+
+use super::registers::{Color, Lcdc, Palette};
 
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub struct Pixel {
@@ -27,21 +33,133 @@ impl Pixel {
     }
 }
 
+/// A pixel waiting in one of the FIFOs, the palette is applied once it is
+/// shifted out to the lcd
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct FifoPixel {
+    /// Index into the palette, 0 - 3
+    pub color: usize,
+    /// Objects only, OBP1 instead of OBP0
+    pub palette: bool,
+    /// Objects only, background and window colors 1 - 3 are drawn over it
+    pub bg_priority: bool,
+}
+
+impl FifoPixel {
+    pub(crate) fn to_byte(self) -> u8 {
+        self.color as u8 | u8::from(self.palette) << 2 | u8::from(self.bg_priority) << 3
+    }
+
+    pub(crate) fn from_byte(byte: u8) -> Self {
+        FifoPixel {
+            color: (byte & 0x03) as usize,
+            palette: byte.is_bit_set(2),
+            bg_priority: byte.is_bit_set(3),
+        }
+    }
+}
+
+/// The 8 pixels of a tile row, leftmost first
+pub fn tile_row(low: u8, high: u8, x_flip: bool) -> [usize; 8] {
+    let mut row = [0; 8];
+
+    for (px, color) in row.iter_mut().enumerate() {
+        // bit 7 holds the leftmost pixel
+        let bit = if x_flip { px } else { 7 - px };
+        *color = Palette::palette_index(high.is_bit_set(bit), low.is_bit_set(bit));
+    }
+
+    row
+}
+
+/// Each step takes 2 dots, `Push` waits until the FIFO ran empty
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum FetcherState {
     GetTile,
     GetDataLow,
     GetDataHigh,
-    Sleep,
     Push,
 }
 
+/// Registers as the fetcher sees them on a dot, writes during mode 3 take
+/// effect on the next tile fetched
+#[derive(Copy, Clone, Debug)]
+pub struct FetchRegisters {
+    pub lcdc: Lcdc,
+    pub scx: u8,
+    /// Background row, LY + SCY
+    pub bg_y: u8,
+    /// Window row
+    pub window_y: u8,
+}
+
+/// # Fetcher
+/// Reads background or window tiles one row of 8 pixels at a time and fills
+/// the background FIFO with them.
+///
+/// The FIFO is refilled only once it ran empty, so the lcd gets one pixel
+/// every dot while the fetcher works on the next tile.
 pub struct Fetcher {
-    queue: VecDeque<Pixel>,
+    queue: VecDeque<FifoPixel>,
     pub state: FetcherState,
-    pub pushed: usize,
+    /// Dots spent on the current tile
     pub ticks: u64,
+    /// Tiles fetched on this line, since the window started for the window
     x_coor: usize,
-    y_coor: usize,
+    /// Fetching window tiles instead of the background
+    pub window: bool,
+    tile: u8,
+    data_low: u8,
+    data_high: u8,
+}
+
+impl Default for Fetcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for Fetcher {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.queue.len() as u8);
+        self.queue
+            .iter()
+            .for_each(|pixel| state.write_u8(pixel.to_byte()));
+        state.write_u8(self.state as u8);
+        state.write_u64(self.ticks);
+        state.write_u8(self.x_coor as u8);
+        state.write_bool(self.window);
+        state.write_u8(self.tile);
+        state.write_u8(self.data_low);
+        state.write_u8(self.data_high);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SavestateError> {
+        let len = state.read_u8()?;
+        if len > 16 {
+            return Err(SavestateError::InvalidValue("background fifo length"));
+        }
+
+        self.queue.clear();
+        for _ in 0..len {
+            self.queue.push_back(FifoPixel::from_byte(state.read_u8()?));
+        }
+
+        self.state = match state.read_u8()? {
+            0 => FetcherState::GetTile,
+            1 => FetcherState::GetDataLow,
+            2 => FetcherState::GetDataHigh,
+            3 => FetcherState::Push,
+            _ => return Err(SavestateError::InvalidValue("fetcher state")),
+        };
+        self.ticks = state.read_u64()?;
+        self.x_coor = state.read_u8()? as usize;
+        self.window = state.read_bool()?;
+        self.tile = state.read_u8()?;
+        self.data_low = state.read_u8()?;
+        self.data_high = state.read_u8()?;
+        Ok(())
+    }
 }
 
 impl Fetcher {
@@ -50,50 +168,132 @@ impl Fetcher {
             queue: VecDeque::new(),
             state: FetcherState::GetTile,
             ticks: 0,
-            pushed: 0,
             x_coor: 0,
-            y_coor: 0,
+            window: false,
+            tile: 0,
+            data_low: 0,
+            data_high: 0,
         }
     }
 
-    pub fn process(&mut self) {
+    /// Runs one dot
+    pub fn process(&mut self, vram: &[u8; VRAM_SIZE], regs: FetchRegisters) {
+        self.tick();
+
         match self.state {
-            FetcherState::GetTile => todo!(),
-            FetcherState::GetDataLow => todo!(),
-            FetcherState::GetDataHigh => todo!(),
-            FetcherState::Sleep => todo!(),
-            FetcherState::Push => todo!(),
+            FetcherState::GetTile if self.ticks >= 2 => {
+                self.tile = vram[self.tile_map_index(regs)];
+                self.set_state(FetcherState::GetDataLow);
+            }
+            FetcherState::GetDataLow if self.ticks >= 4 => {
+                self.data_low = vram[self.tile_data_index(regs)];
+                self.set_state(FetcherState::GetDataHigh);
+            }
+            FetcherState::GetDataHigh if self.ticks >= 6 => {
+                self.data_high = vram[self.tile_data_index(regs) + 1];
+                self.set_state(FetcherState::Push);
+            }
+            FetcherState::Push => self.push_pixel(),
+            _ => {}
         }
     }
 
-    pub fn push_pixel(&mut self) {}
+    /// Fills the FIFO with the fetched row once it ran empty
+    pub fn push_pixel(&mut self) {
+        if !self.queue.is_empty() {
+            return;
+        }
+
+        for color in tile_row(self.data_low, self.data_high, false) {
+            self.push(FifoPixel {
+                color,
+                ..Default::default()
+            });
+        }
 
+        self.x_coor += 1;
+        self.ticks = 0;
+        self.set_state(FetcherState::GetTile);
+    }
+
+    /// Starts a new line with the background
     pub fn reset(&mut self) {
         self.queue.clear();
         self.state = FetcherState::GetTile;
         self.ticks = 0;
-        self.pushed = 0;
+        self.x_coor = 0;
+        self.window = false;
+    }
+
+    /// Drops the background pixels and fetches window tiles from now on
+    pub fn start_window(&mut self) {
+        self.queue.clear();
+        self.state = FetcherState::GetTile;
+        self.ticks = 0;
+        self.x_coor = 0;
+        self.window = true;
+    }
+
+    /// An object fetch waits for the fetcher to get far into its tile,
+    /// at most 5 dots after it started on it
+    pub fn ready_for_object(&self) -> bool {
+        !self.queue.is_empty() && self.ticks >= 5
     }
 
     pub fn set_state(&mut self, state: FetcherState) {
         self.state = state;
     }
 
-    pub fn push(&mut self, pixel: Pixel) {
+    pub fn push(&mut self, pixel: FifoPixel) {
         self.queue.push_back(pixel);
     }
 
-    pub fn pop(&mut self) -> Pixel {
-        match self.queue.pop_front() {
-            Some(pixel) => {
-                self.pushed += 1;
-                pixel
-            }
-            None => panic!("Popping from empty fifo"),
-        }
+    /// None once the fifo ran empty
+    pub fn pop(&mut self) -> Option<FifoPixel> {
+        self.queue.pop_front()
     }
 
     pub fn tick(&mut self) {
         self.ticks += 1;
     }
+
+    fn tile_map_index(&self, regs: FetchRegisters) -> usize {
+        let (map_area, column, y) = if self.window {
+            (regs.lcdc.window_tile_map_area, self.x_coor, regs.window_y)
+        } else {
+            let column = (regs.scx / 8) as usize + self.x_coor;
+            (regs.lcdc.bg_tile_map_area, column, regs.bg_y)
+        };
+
+        let tilemap = match map_area {
+            true => 0x9C00 - VRAM_START,
+            false => 0x9800 - VRAM_START,
+        };
+
+        // tilemaps are 32 x 32 tiles and wrap around
+        tilemap as usize + (y as usize / 8) * 32 + column % 32
+    }
+
+    fn tile_data_index(&self, regs: FetchRegisters) -> usize {
+        let y = match self.window {
+            true => regs.window_y,
+            false => regs.bg_y,
+        };
+
+        // each row of pixels takes up 2 bytes
+        tile_address(self.tile, regs.lcdc.bg_tile_data_area) as usize + (y as usize % 8) * 2
+    }
+}
+
+/// Start of a background or window tile inside vram
+fn tile_address(tile: u8, unsigned: bool) -> u16 {
+    match unsigned {
+        true => tile as u16 * 16,
+        false => {
+            // interpret it a possible negative number first
+            // then multiply by 16
+            let signed_offset = (tile as i8 as i16).wrapping_mul(16);
+            (0x9000 - VRAM_START).wrapping_add(signed_offset as u16)
+        }
+    }
 }
@@ -1,5 +1,8 @@
 use crate::utils::BitPosCheck;
 
+/// OAM as the PPU reads it in mode 2, one row of two objects per M-cycle
+const OAM_ROWS: usize = 20;
+
 /// ### OamEntry
 /// Data for each individual sprite
 #[derive(Copy, PartialEq, Debug, Clone)]
@@ -59,7 +62,7 @@ impl OamEntry {
             1 => self.x_pos,
             2 => self.tile_idx,
             3 => self.flags,
-            _ => panic!("Shouldn't happen"),
+            _ => unreachable!("Shouldn't happen"),
         }
     }
 
@@ -69,7 +72,65 @@ impl OamEntry {
             1 => self.x_pos = byte,
             2 => self.tile_idx = byte,
             3 => self.flags = byte,
-            _ => panic!("Shouldn't happen"),
+            _ => unreachable!("Shouldn't happen"),
         }
     }
 }
+
+/// How the cpu put an address in 0xFE00 - 0xFEFF on the bus while the PPU
+/// searched OAM, see `corrupt_oam`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OamBug {
+    /// A write, or INC rr / DEC rr / PUSH changing the register
+    Write,
+    Read,
+    /// A read that also increments or decrements the register, POP and
+    /// LD A, (HL+) / LD A, (HL-)
+    ReadIncDec,
+}
+
+/// # OAM corruption bug
+/// https://gbdev.io/pandocs/OAM_Corruption_Bug.html
+///
+/// A DMG corrupts OAM when the cpu puts an OAM address on the bus during
+/// mode 2. The row of 8 bytes the PPU is reading, `row`, gets its first word
+/// mixed with the row before it and the other three words copied from it.
+/// Row 0 is never corrupted.
+pub fn corrupt_oam(oam: &mut [OamEntry], row: usize, access: OamBug) {
+    if row == 0 || row >= OAM_ROWS {
+        return;
+    }
+
+    // each row holds two objects as four little endian words
+    let mut rows = [[0u16; 4]; OAM_ROWS];
+    for (idx, entry) in oam.iter().enumerate() {
+        let words = &mut rows[idx / 2][(idx % 2) * 2..];
+        words[0] = u16::from_le_bytes([entry.y_pos, entry.x_pos]);
+        words[1] = u16::from_le_bytes([entry.tile_idx, entry.flags]);
+    }
+
+    if access == OamBug::ReadIncDec && (4..OAM_ROWS - 1).contains(&row) {
+        let a = rows[row - 2][0];
+        let b = rows[row - 1][0];
+        let c = rows[row][0];
+        let d = rows[row - 1][2];
+        rows[row - 1][0] = (b & (a | c | d)) | (a & c & d);
+        rows[row] = rows[row - 1];
+        rows[row - 2] = rows[row - 1];
+    }
+
+    let a = rows[row][0];
+    let b = rows[row - 1][0];
+    let c = rows[row - 1][2];
+    rows[row] = rows[row - 1];
+    rows[row][0] = match access {
+        OamBug::Write => ((a ^ c) & (b ^ c)) ^ c,
+        OamBug::Read | OamBug::ReadIncDec => b | (a & c),
+    };
+
+    for (idx, entry) in oam.iter_mut().enumerate() {
+        let words = &rows[idx / 2][(idx % 2) * 2..];
+        [entry.y_pos, entry.x_pos] = words[0].to_le_bytes();
+        [entry.tile_idx, entry.flags] = words[1].to_le_bytes();
+    }
+}
@@ -74,6 +74,14 @@ impl Lcdc {
         byte.into()
     }
 
+    /// Objects are 8x8 or 8x16
+    pub fn obj_height(&self) -> u8 {
+        match self.obj_size {
+            true => 16,
+            false => 8,
+        }
+    }
+
     pub fn is_lcd_enabled(&self) -> bool {
         self.enable_lcd
     }
@@ -144,6 +152,15 @@ impl Stat {
         byte.into()
     }
 
+    /// A write by the cpu, which can only change the interrupt sources. The
+    /// LY=LYC flag and the mode are read only
+    pub fn write(&mut self, byte: u8) {
+        self.lyc_ly_eq_interrupt = is_bit_set(byte, 6);
+        self.oam_interrupt = is_bit_set(byte, 5);
+        self.vblank_interrupt = is_bit_set(byte, 4);
+        self.hblank_interrupt = is_bit_set(byte, 3);
+    }
+
     pub fn set_lyc_ly_eq_flag(&mut self, flag: bool) {
         self.lyc_ly_eq_flag = flag;
     }
@@ -227,6 +244,8 @@ pub enum Color {
     C1 = 1,
     C2 = 2,
     C3 = 3,
+    /// The lcd is off, the screen is a shade lighter than color 0
+    Off = 4,
 }
 
 impl Palette {
@@ -3,6 +3,7 @@ use std::{cell::RefCell, rc::Rc};
 use crate::{
     bus::Memory,
     interrupt::Interrupts,
+    savestate::{Savestate, SavestateError, StateReader, StateWriter},
 };
 
 pub struct Serial {
@@ -16,8 +17,9 @@ impl Memory for Serial {
     fn read(&self, address: u16) -> u8 {
         match address {
             0xFF01 => self.data,
-            0xFF02 => self.control,
-            _ => panic!("invalid address for serial"),
+            // bits 1 - 6 are unused on a DMG
+            0xFF02 => self.control | 0x7E,
+            _ => unreachable!("serial registers are 0xFF01 - 0xFF02"),
         }
     }
 
@@ -25,11 +27,24 @@ impl Memory for Serial {
         match address {
             0xFF01 => self.data = byte,
             0xFF02 => self.control = byte,
-            _ => panic!("invalid address for serial"),
+            _ => unreachable!("serial registers are 0xFF01 - 0xFF02"),
         }
     }
 }
 
+impl Savestate for Serial {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.data);
+        state.write_u8(self.control);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SavestateError> {
+        self.data = state.read_u8()?;
+        self.control = state.read_u8()?;
+        Ok(())
+    }
+}
+
 impl Serial {
     pub fn new(interrupts: Rc<RefCell<Interrupts>>) -> Self {
         Serial {
@@ -40,11 +55,25 @@ impl Serial {
         }
     }
 
-    pub fn print_serial_data(&mut self) {
-        if self.control == 0x81 {
-            self.output.push(self.data as char);
-            self.control = 0;
-            println!("{}", self.output);
+    /// Completes a transfer started with the internal clock.
+    /// There is no link partner, the sent byte is collected into `output`.
+    /// Returns the byte once it was sent
+    pub fn update(&mut self) -> Option<u8> {
+        if self.control != 0x81 {
+            return None;
         }
+
+        self.output.push(self.data as char);
+        self.control = 0;
+        Some(self.data)
+    }
+
+    /// Everything sent over the link port so far
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    pub fn print_serial_data(&self) {
+        println!("{}", self.output);
     }
 }
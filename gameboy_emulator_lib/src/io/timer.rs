@@ -3,6 +3,7 @@ use std::{cell::RefCell, rc::Rc};
 use crate::{
     bus::Memory,
     interrupt::{InterruptType, Interrupts},
+    savestate::{Savestate, SavestateError, StateReader, StateWriter},
     utils::BitPosCheck,
 };
 
@@ -30,8 +31,9 @@ impl Memory for Timer {
             0xFF04 => (self.div >> 8) as u8,
             0xFF05 => self.tima,
             0xFF06 => self.tma,
-            0xFF07 => self.tac,
-            _ => panic!("invalid address passed"),
+            // only the lower 3 bits are used
+            0xFF07 => self.tac | 0xF8,
+            _ => unreachable!("timer registers are 0xFF04 - 0xFF07"),
         }
     }
 
@@ -47,11 +49,38 @@ impl Memory for Timer {
                 self.tma = byte;
             }
             0xFF07 => self.tac = byte,
-            _ => panic!("invalid address passed"),
+            _ => unreachable!("timer registers are 0xFF04 - 0xFF07"),
         }
     }
 }
 
+impl Savestate for Timer {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.div);
+        state.write_u8(self.tima);
+        state.write_u16(self.tima_cycles);
+        state.write_u16(self.tima_period as u16);
+        state.write_u8(self.tma);
+        state.write_u8(self.tac);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SavestateError> {
+        self.div = state.read_u16()?;
+        self.tima = state.read_u8()?;
+        self.tima_cycles = state.read_u16()?;
+        self.tima_period = match state.read_u16()? {
+            1024 => ClockFreq::C1024,
+            16 => ClockFreq::C16,
+            64 => ClockFreq::C64,
+            256 => ClockFreq::C256,
+            _ => return Err(SavestateError::InvalidValue("timer period")),
+        };
+        self.tma = state.read_u8()?;
+        self.tac = state.read_u8()?;
+        Ok(())
+    }
+}
+
 impl Timer {
     pub fn new(interrupts: Rc<RefCell<Interrupts>>) -> Self {
         Timer {
@@ -65,6 +94,11 @@ impl Timer {
         }
     }
 
+    /// Sets the internal divider counter without the reset a DIV write causes
+    pub fn set_div_counter(&mut self, div: u16) {
+        self.div = div;
+    }
+
     pub fn tick(&mut self) {
         self.div = self.div.wrapping_add(1);
 
@@ -102,7 +136,7 @@ impl Timer {
             1 => ClockFreq::C16,
             2 => ClockFreq::C64,
             3 => ClockFreq::C256,
-            _ => panic!("invalid bit pattern"),
+            _ => unreachable!("masked to 2 bits"),
         }
     }
 }
@@ -1,8 +1,18 @@
 pub mod bus;
 pub mod cartridge;
+pub mod cheats;
 pub mod cpu;
+pub mod debug;
 pub mod emu;
+pub mod error;
+pub mod gdb;
+pub mod hooks;
 pub mod interrupt;
 pub mod io;
+pub mod movie;
+pub mod rewind;
 pub mod rom;
+pub mod savestate;
+#[cfg(feature = "scripting")]
+pub mod script;
 pub mod utils;
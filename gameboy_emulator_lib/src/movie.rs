@@ -0,0 +1,546 @@
+use std::fmt;
+
+use crate::{
+    bus::ram_init::RamInit,
+    cheats::Cheat,
+    emu::EmuContext,
+    savestate::{bess::DMG_MODEL, SavestateError, StateReader, StateWriter},
+    utils::crc32,
+};
+
+const MOVIE_MAGIC: &[u8; 4] = b"GBMV";
+const MOVIE_VERSION: u16 = 2;
+pub const EMULATOR_VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Frames between two state hashes, one second of emulation
+pub const DEFAULT_HASH_INTERVAL: u32 = 60;
+
+/// Where playback starts from
+#[derive(Clone, Debug, PartialEq)]
+pub enum MovieAnchor {
+    PowerOn,
+    /// Native save state
+    Savestate(Vec<u8>),
+}
+
+/// # Movie
+/// Joypad input for every frame, recorded from a fixed starting point.
+///
+/// Replaying the same input from the same anchor on the same rom
+/// reproduces the run bit for bit. A hash of the machine state is stored
+/// every `hash_interval` frames to detect when playback diverges.
+///
+/// Each input byte is the joypad's pressed mask, see `JoypadInput`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Movie {
+    /// CRC-32 of the whole rom
+    pub rom_hash: u32,
+    /// BESS style model id
+    pub model: [u8; 4],
+    pub emulator_version: String,
+    pub anchor: MovieAnchor,
+    /// Power-on RAM pattern, playback uses it instead of the player's options
+    pub ram_init: RamInit,
+    /// Cheats enabled when recording started, playback enables exactly these
+    pub cheats: Vec<Cheat>,
+    pub hash_interval: u32,
+    pub inputs: Vec<u8>,
+    /// ( frame, CRC-32 of the native save state at the end of that frame )
+    pub state_hashes: Vec<(u32, u32)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MovieError {
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    RomMismatch { expected: u32, found: u32 },
+    State(SavestateError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::InvalidMagic => write!(f, "not a movie file"),
+            MovieError::UnsupportedVersion(v) => write!(f, "unsupported movie version {}", v),
+            MovieError::RomMismatch { expected, found } => write!(
+                f,
+                "movie was recorded on rom {:08X}, loaded rom is {:08X}",
+                expected, found
+            ),
+            MovieError::State(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+impl From<SavestateError> for MovieError {
+    fn from(e: SavestateError) -> Self {
+        MovieError::State(e)
+    }
+}
+
+/// Playback no longer matches the recording
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Desync {
+    pub frame: u32,
+    pub expected: u32,
+    pub found: u32,
+}
+
+impl fmt::Display for Desync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "desync at frame {}: state hash {:08X}, recorded {:08X}",
+            self.frame, self.found, self.expected
+        )
+    }
+}
+
+fn rom_hash(ctx: &EmuContext) -> u32 {
+    crc32(&ctx.bus.borrow().cartridge.data)
+}
+
+fn state_hash(ctx: &EmuContext) -> u32 {
+    crc32(&ctx.save_state())
+}
+
+fn write_ram_init(data: &mut StateWriter, init: RamInit) {
+    let (kind, seed) = match init {
+        RamInit::Zero => (0, 0),
+        RamInit::Ones => (1, 0),
+        RamInit::Random(seed) => (2, seed),
+        RamInit::DmgLike(seed) => (3, seed),
+    };
+
+    data.write_u8(kind);
+    data.write_u64(seed);
+}
+
+fn write_string(data: &mut StateWriter, s: &str) {
+    data.write_u16(s.len() as u16);
+    data.write_bytes(s.as_bytes());
+}
+
+fn read_string(data: &mut StateReader) -> Result<String, MovieError> {
+    let len = data.read_u16()? as usize;
+    Ok(String::from_utf8_lossy(data.read_bytes(len)?).into_owned())
+}
+
+fn read_ram_init(data: &mut StateReader) -> Result<RamInit, MovieError> {
+    let kind = data.read_u8()?;
+    let seed = data.read_u64()?;
+
+    match kind {
+        0 => Ok(RamInit::Zero),
+        1 => Ok(RamInit::Ones),
+        2 => Ok(RamInit::Random(seed)),
+        3 => Ok(RamInit::DmgLike(seed)),
+        _ => Err(SavestateError::InvalidValue("movie ram init").into()),
+    }
+}
+
+impl Movie {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data = StateWriter::new();
+
+        data.write_bytes(MOVIE_MAGIC);
+        data.write_u16(MOVIE_VERSION);
+        data.write_u32(self.rom_hash);
+        data.write_bytes(&self.model);
+        data.write_u8(self.emulator_version.len() as u8);
+        data.write_bytes(self.emulator_version.as_bytes());
+        write_ram_init(&mut data, self.ram_init);
+        data.write_u32(self.cheats.len() as u32);
+        for cheat in self.cheats.iter() {
+            write_string(&mut data, &cheat.name);
+            write_string(&mut data, &cheat.code);
+        }
+        data.write_u32(self.hash_interval);
+
+        match &self.anchor {
+            MovieAnchor::PowerOn => data.write_u8(0),
+            MovieAnchor::Savestate(state) => {
+                data.write_u8(1);
+                data.write_u32(state.len() as u32);
+                data.write_bytes(state);
+            }
+        }
+
+        data.write_u32(self.inputs.len() as u32);
+        data.write_bytes(&self.inputs);
+
+        data.write_u32(self.state_hashes.len() as u32);
+        for (frame, hash) in self.state_hashes.iter() {
+            data.write_u32(*frame);
+            data.write_u32(*hash);
+        }
+
+        data.into_bytes()
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, MovieError> {
+        let mut data = StateReader::new(bytes);
+
+        if data.read_bytes(MOVIE_MAGIC.len())? != MOVIE_MAGIC {
+            return Err(MovieError::InvalidMagic);
+        }
+
+        let version = data.read_u16()?;
+        if version != MOVIE_VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+
+        let rom_hash = data.read_u32()?;
+        let mut model = [0; 4];
+        data.read_into(&mut model)?;
+        let version_len = data.read_u8()? as usize;
+        let emulator_version = String::from_utf8_lossy(data.read_bytes(version_len)?).into_owned();
+        let ram_init = read_ram_init(&mut data)?;
+        let cheat_count = data.read_u32()?;
+        let mut cheats = Vec::new();
+        for _ in 0..cheat_count {
+            let name = read_string(&mut data)?;
+            let code = read_string(&mut data)?;
+            let cheat = Cheat::new(&name, &code)
+                .map_err(|_| SavestateError::InvalidValue("movie cheat"))?;
+            cheats.push(cheat);
+        }
+        let hash_interval = data.read_u32()?;
+
+        let anchor = match data.read_u8()? {
+            0 => MovieAnchor::PowerOn,
+            1 => {
+                let len = data.read_u32()? as usize;
+                MovieAnchor::Savestate(data.read_bytes(len)?.to_vec())
+            }
+            _ => return Err(SavestateError::InvalidValue("movie anchor").into()),
+        };
+
+        let frame_count = data.read_u32()? as usize;
+        let inputs = data.read_bytes(frame_count)?.to_vec();
+
+        let hash_count = data.read_u32()?;
+        let mut state_hashes = Vec::new();
+        for _ in 0..hash_count {
+            state_hashes.push((data.read_u32()?, data.read_u32()?));
+        }
+
+        Ok(Movie {
+            rom_hash,
+            model,
+            emulator_version,
+            anchor,
+            ram_init,
+            cheats,
+            hash_interval,
+            inputs,
+            state_hashes,
+        })
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.inputs.len()
+    }
+}
+
+/// Counts frames in which the game never read the joypad register
+#[derive(Default)]
+struct LagCounter {
+    lag_frames: u32,
+}
+
+impl LagCounter {
+    fn end_frame(&mut self, ctx: &EmuContext) -> bool {
+        let lagged = !ctx.bus.borrow().joypad.take_polled();
+
+        if lagged {
+            self.lag_frames += 1;
+        }
+
+        lagged
+    }
+}
+
+/// Records a movie
+///
+/// Per frame call `begin_frame` with the pressed buttons before emulating
+/// and `end_frame` once the frame is done.
+pub struct MovieRecorder {
+    movie: Movie,
+    lag: LagCounter,
+}
+
+impl MovieRecorder {
+    /// Power cycles the machine and records from there
+    pub fn power_on(ctx: &mut EmuContext, hash_interval: u32) -> Self {
+        ctx.reset();
+        Self::with_anchor(ctx, MovieAnchor::PowerOn, hash_interval)
+    }
+
+    /// Records from the machine's current state
+    pub fn from_current_state(ctx: &EmuContext, hash_interval: u32) -> Self {
+        Self::with_anchor(ctx, MovieAnchor::Savestate(ctx.save_state()), hash_interval)
+    }
+
+    fn with_anchor(ctx: &EmuContext, anchor: MovieAnchor, hash_interval: u32) -> Self {
+        // reads before the anchor don't belong to the first frame
+        ctx.bus.borrow().joypad.take_polled();
+
+        MovieRecorder {
+            movie: Movie {
+                rom_hash: rom_hash(ctx),
+                model: DMG_MODEL,
+                emulator_version: EMULATOR_VERSION.to_string(),
+                anchor,
+                ram_init: ctx.opts.ram_init,
+                cheats: ctx
+                    .cheats()
+                    .iter()
+                    .filter(|(_, cheat)| cheat.enabled)
+                    .map(|(_, cheat)| cheat.clone())
+                    .collect(),
+                hash_interval: hash_interval.max(1),
+                inputs: vec![],
+                state_hashes: vec![],
+            },
+            lag: LagCounter::default(),
+        }
+    }
+
+    pub fn begin_frame(&mut self, ctx: &mut EmuContext, buttons: u8) {
+        ctx.bus.borrow_mut().joypad.set_buttons(buttons);
+        self.movie.inputs.push(buttons);
+    }
+
+    /// Returns whether the frame was a lag frame
+    pub fn end_frame(&mut self, ctx: &EmuContext) -> bool {
+        let frame = self.movie.inputs.len() as u32;
+
+        if frame.is_multiple_of(self.movie.hash_interval) {
+            self.movie.state_hashes.push((frame, state_hash(ctx)));
+        }
+
+        self.lag.end_frame(ctx)
+    }
+
+    pub fn frames(&self) -> u32 {
+        self.movie.inputs.len() as u32
+    }
+
+    pub fn lag_frames(&self) -> u32 {
+        self.lag.lag_frames
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+/// Plays a movie back
+///
+/// Per frame call `begin_frame` before emulating, which feeds the recorded
+/// input, and `end_frame` afterwards to check for desyncs.
+pub struct MoviePlayer {
+    movie: Movie,
+    frame: u32,
+    next_hash: usize,
+    lag: LagCounter,
+    desync: Option<Desync>,
+}
+
+impl MoviePlayer {
+    /// Checks the rom and brings the machine to the movie's anchor
+    ///
+    /// The recorded power-on RAM pattern replaces the one in `ctx.opts`,
+    /// the recorded cheats replace the ones in `ctx`
+    pub fn new(movie: Movie, ctx: &mut EmuContext) -> Result<Self, MovieError> {
+        let found = rom_hash(ctx);
+        if found != movie.rom_hash {
+            return Err(MovieError::RomMismatch {
+                expected: movie.rom_hash,
+                found,
+            });
+        }
+
+        ctx.opts.ram_init = movie.ram_init;
+
+        let ids = ctx.cheats().iter().map(|(id, _)| id).collect::<Vec<_>>();
+        for id in ids {
+            ctx.remove_cheat(id);
+        }
+        for cheat in movie.cheats.iter() {
+            ctx.add_cheat(cheat.clone());
+        }
+
+        match &movie.anchor {
+            MovieAnchor::PowerOn => ctx.reset(),
+            MovieAnchor::Savestate(state) => ctx.load_state(state)?,
+        }
+        ctx.bus.borrow().joypad.take_polled();
+
+        Ok(MoviePlayer {
+            movie,
+            frame: 0,
+            next_hash: 0,
+            lag: LagCounter::default(),
+            desync: None,
+        })
+    }
+
+    /// Feeds the next frame's input
+    /// Returns false once all recorded frames have been played
+    pub fn begin_frame(&mut self, ctx: &mut EmuContext) -> bool {
+        match self.movie.inputs.get(self.frame as usize) {
+            Some(buttons) => {
+                ctx.bus.borrow_mut().joypad.set_buttons(*buttons);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the first desync, once it has been detected
+    pub fn end_frame(&mut self, ctx: &EmuContext) -> Option<Desync> {
+        self.frame += 1;
+        self.lag.end_frame(ctx);
+
+        if self.desync.is_some() {
+            return self.desync;
+        }
+
+        if let Some((frame, expected)) = self.movie.state_hashes.get(self.next_hash) {
+            if *frame == self.frame {
+                self.next_hash += 1;
+                let found = state_hash(ctx);
+
+                if found != *expected {
+                    self.desync = Some(Desync {
+                        frame: self.frame,
+                        expected: *expected,
+                        found,
+                    });
+                }
+            }
+        }
+
+        self.desync
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame as usize >= self.movie.inputs.len()
+    }
+
+    pub fn frame(&self) -> u32 {
+        self.frame
+    }
+
+    pub fn lag_frames(&self) -> u32 {
+        self.lag.lag_frames
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Movie, MoviePlayer, MovieRecorder};
+    use crate::{
+        bus::ram_init::RamInit, cartridge::Cartridge, cheats::Cheat, emu::EmuContext, utils::Opts,
+    };
+
+    fn test_context() -> EmuContext {
+        // poll the joypad forever
+        // loop: ld a, (0xFF00) ; jr loop
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0xF0, 0x00, 0x18, 0xFC]);
+        EmuContext::new(Cartridge::new(rom).unwrap(), Opts::new(false, false))
+    }
+
+    #[test]
+    fn test_record_and_play_back() {
+        let mut ctx = test_context();
+        let mut recorder = MovieRecorder::power_on(&mut ctx, 2);
+
+        for frame in 0..6u8 {
+            recorder.begin_frame(&mut ctx, frame);
+            ctx.run_frame().unwrap();
+            assert!(!recorder.end_frame(&ctx));
+        }
+
+        let movie = Movie::deserialize(&recorder.finish().serialize()).unwrap();
+        assert_eq!(movie.frame_count(), 6);
+        assert_eq!(movie.state_hashes.len(), 3);
+
+        let end_state = ctx.save_state();
+        let mut ctx = test_context();
+        let mut player = MoviePlayer::new(movie, &mut ctx).unwrap();
+
+        while player.begin_frame(&mut ctx) {
+            ctx.run_frame().unwrap();
+            assert_eq!(player.end_frame(&ctx), None);
+        }
+
+        assert!(player.is_finished());
+        assert_eq!(player.lag_frames(), 0);
+        assert_eq!(ctx.save_state(), end_state);
+    }
+
+    #[test]
+    fn test_desync_detected() {
+        let mut ctx = test_context();
+        let mut recorder = MovieRecorder::power_on(&mut ctx, 1);
+        recorder.begin_frame(&mut ctx, 0);
+        ctx.run_frame().unwrap();
+        recorder.end_frame(&ctx);
+
+        let mut movie = recorder.finish();
+        movie.inputs[0] = 0x80;
+
+        let mut player = MoviePlayer::new(movie, &mut ctx).unwrap();
+        player.begin_frame(&mut ctx);
+        ctx.run_frame().unwrap();
+        assert!(player.end_frame(&ctx).is_some());
+    }
+
+    #[test]
+    fn test_play_back_uses_recorded_ram_init() {
+        let mut ctx = test_context();
+        ctx.opts.ram_init = RamInit::Random(42);
+        let mut recorder = MovieRecorder::power_on(&mut ctx, 1);
+        recorder.begin_frame(&mut ctx, 0);
+        ctx.run_frame().unwrap();
+        recorder.end_frame(&ctx);
+
+        let movie = Movie::deserialize(&recorder.finish().serialize()).unwrap();
+        assert_eq!(movie.ram_init, RamInit::Random(42));
+
+        let mut ctx = test_context();
+        let mut player = MoviePlayer::new(movie, &mut ctx).unwrap();
+        player.begin_frame(&mut ctx);
+        ctx.run_frame().unwrap();
+        assert_eq!(player.end_frame(&ctx), None);
+    }
+
+    #[test]
+    fn test_play_back_uses_recorded_cheats() {
+        let mut ctx = test_context();
+        ctx.add_cheat(Cheat::new("test", "014200C0").unwrap());
+        let mut recorder = MovieRecorder::power_on(&mut ctx, 1);
+        recorder.begin_frame(&mut ctx, 0);
+        ctx.run_frame().unwrap();
+        recorder.end_frame(&ctx);
+
+        let movie = Movie::deserialize(&recorder.finish().serialize()).unwrap();
+        assert_eq!(movie.cheats.len(), 1);
+
+        let mut ctx = test_context();
+        let mut player = MoviePlayer::new(movie, &mut ctx).unwrap();
+        player.begin_frame(&mut ctx);
+        ctx.run_frame().unwrap();
+        assert_eq!(player.end_frame(&ctx), None);
+        assert_eq!(ctx.cheats().iter().count(), 1);
+    }
+}
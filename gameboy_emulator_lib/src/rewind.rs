@@ -0,0 +1,260 @@
+use std::collections::VecDeque;
+
+use crate::emu::EmuContext;
+
+/// # Rewind
+/// Ring buffer of save states that can be played back in reverse.
+///
+/// A snapshot is captured every `interval` frames. Only the newest snapshot
+/// is kept as a full save state, every older one is stored as the XOR against
+/// its successor, run length encoded. Between two snapshots only a few bytes
+/// of WRAM / VRAM change, so most of the XOR is zero and compresses well.
+///
+/// Rewinding loads the newest snapshot and rebuilds the one before it from the
+/// stored delta. Once `max_bytes` is exceeded the oldest deltas get dropped.
+pub struct Rewind {
+    interval: u32,
+    max_bytes: usize,
+    frames_since_capture: u32,
+    /// Newest snapshot, uncompressed
+    head: Option<Vec<u8>>,
+    /// Deltas to rebuild older snapshots, oldest first
+    deltas: VecDeque<Vec<u8>>,
+    delta_bytes: usize,
+}
+
+impl Rewind {
+    pub fn new(interval: u32, max_bytes: usize) -> Self {
+        Rewind {
+            interval: interval.max(1),
+            max_bytes,
+            frames_since_capture: 0,
+            head: None,
+            deltas: VecDeque::new(),
+            delta_bytes: 0,
+        }
+    }
+
+    /// Call once after every emulated frame
+    pub fn on_frame(&mut self, ctx: &EmuContext) {
+        self.frames_since_capture += 1;
+
+        if self.frames_since_capture >= self.interval {
+            self.capture(ctx);
+        }
+    }
+
+    pub fn capture(&mut self, ctx: &EmuContext) {
+        self.frames_since_capture = 0;
+        let state = ctx.save_state();
+
+        if let Some(prev) = self.head.take() {
+            let delta = encode_delta(&prev, &state);
+            self.delta_bytes += delta.len();
+            self.deltas.push_back(delta);
+        }
+
+        self.head = Some(state);
+        self.enforce_limit();
+    }
+
+    /// Loads the newest snapshot and makes the one before it the next target.
+    /// Returns false once the buffer is exhausted.
+    pub fn rewind(&mut self, ctx: &mut EmuContext) -> bool {
+        let state = match self.head.take() {
+            Some(state) => state,
+            None => return false,
+        };
+
+        if let Some(delta) = self.deltas.pop_back() {
+            self.delta_bytes -= delta.len();
+            self.head = Some(decode_delta(&state, &delta));
+        }
+
+        self.frames_since_capture = 0;
+        ctx.load_state(&state).is_ok()
+    }
+
+    pub fn clear(&mut self) {
+        self.head = None;
+        self.deltas.clear();
+        self.delta_bytes = 0;
+        self.frames_since_capture = 0;
+    }
+
+    /// Number of snapshots that can be rewound to
+    pub fn len(&self) -> usize {
+        match self.head {
+            Some(_) => self.deltas.len() + 1,
+            None => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.head.as_ref().map_or(0, |head| head.len()) + self.delta_bytes
+    }
+
+    fn enforce_limit(&mut self) {
+        while self.memory_used() > self.max_bytes {
+            match self.deltas.pop_front() {
+                Some(delta) => self.delta_bytes -= delta.len(),
+                None => break,
+            }
+        }
+    }
+}
+
+/// Delta layout
+/// - 4 bytes little endian length of `prev`
+/// - `prev XOR next` run length encoded, zero runs are written
+///   as a 0x00 byte followed by the run length as LEB128, other bytes as is
+fn encode_delta(prev: &[u8], next: &[u8]) -> Vec<u8> {
+    let len = prev.len().max(next.len());
+    let mut out = Vec::with_capacity(64);
+    out.extend_from_slice(&(prev.len() as u32).to_le_bytes());
+
+    let mut zero_run = 0usize;
+
+    for idx in 0..len {
+        let byte = prev.get(idx).unwrap_or(&0) ^ next.get(idx).unwrap_or(&0);
+
+        if byte == 0 {
+            zero_run += 1;
+            continue;
+        }
+
+        if zero_run > 0 {
+            write_zero_run(&mut out, zero_run);
+            zero_run = 0;
+        }
+        out.push(byte);
+    }
+
+    if zero_run > 0 {
+        write_zero_run(&mut out, zero_run);
+    }
+
+    out
+}
+
+fn write_zero_run(out: &mut Vec<u8>, mut run: usize) {
+    out.push(0x00);
+
+    loop {
+        let byte = (run & 0x7F) as u8;
+        run >>= 7;
+
+        if run == 0 {
+            out.push(byte);
+            break;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Rebuilds the older snapshot from the newer one
+fn decode_delta(next: &[u8], delta: &[u8]) -> Vec<u8> {
+    let prev_len = u32::from_le_bytes([delta[0], delta[1], delta[2], delta[3]]) as usize;
+    let mut prev = Vec::with_capacity(prev_len);
+    let mut bytes = delta[4..].iter();
+
+    while let Some(&byte) = bytes.next() {
+        if byte != 0 {
+            let idx = prev.len();
+            prev.push(next.get(idx).unwrap_or(&0) ^ byte);
+            continue;
+        }
+
+        let mut run = 0usize;
+        let mut shift = 0;
+        for &len_byte in bytes.by_ref() {
+            run |= ((len_byte & 0x7F) as usize) << shift;
+            shift += 7;
+
+            if len_byte & 0x80 == 0 {
+                break;
+            }
+        }
+
+        for _ in 0..run {
+            let idx = prev.len();
+            prev.push(*next.get(idx).unwrap_or(&0));
+        }
+    }
+
+    prev.truncate(prev_len);
+    prev
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_delta, encode_delta, Rewind};
+    use crate::{cartridge::Cartridge, emu::EmuContext, utils::Opts};
+
+    #[test]
+    fn test_delta_round_trip() {
+        let prev = vec![1, 0, 0, 0, 5, 6, 0, 0, 0, 0, 9];
+        let mut next = prev.clone();
+        next[4] = 0xFF;
+        next.extend_from_slice(&[7, 7]);
+
+        let delta = encode_delta(&prev, &next);
+        assert_eq!(decode_delta(&next, &delta), prev);
+
+        let delta = encode_delta(&next, &prev);
+        assert_eq!(decode_delta(&prev, &delta), next);
+    }
+
+    #[test]
+    fn test_long_zero_run_compresses() {
+        let prev = vec![0xAA; 0x10000];
+        let mut next = prev.clone();
+        next[0x8000] = 0x55;
+
+        let delta = encode_delta(&prev, &next);
+        assert!(delta.len() < 16);
+        assert_eq!(decode_delta(&next, &delta), prev);
+    }
+
+    #[test]
+    fn test_rewind_restores_snapshots_in_reverse() {
+        let mut ctx = EmuContext::new(
+            Cartridge::new(vec![0; 0x8000]).unwrap(),
+            Opts::new(false, false),
+        );
+        let mut rewind = Rewind::new(1, usize::MAX);
+
+        for pc in 0x200..0x205 {
+            ctx.cpu.registers.pc = pc;
+            rewind.on_frame(&ctx);
+        }
+        assert_eq!(rewind.len(), 5);
+
+        for pc in (0x200..0x205).rev() {
+            assert!(rewind.rewind(&mut ctx));
+            assert_eq!(ctx.cpu.registers.pc, pc);
+        }
+        assert!(!rewind.rewind(&mut ctx));
+    }
+
+    #[test]
+    fn test_memory_limit_drops_oldest() {
+        let ctx = EmuContext::new(
+            Cartridge::new(vec![0; 0x8000]).unwrap(),
+            Opts::new(false, false),
+        );
+        let state_len = ctx.save_state().len();
+        let mut rewind = Rewind::new(1, state_len + 64);
+
+        for _ in 0..100 {
+            rewind.capture(&ctx);
+        }
+
+        assert!(rewind.memory_used() <= state_len + 64);
+        assert!(rewind.len() < 100);
+    }
+}
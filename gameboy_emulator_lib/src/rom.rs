@@ -1,17 +1,17 @@
 use std::{fs, path::Path};
 
+use crate::error::EmuError;
+
 pub struct Rom {
     pub data: Vec<u8>,
 }
 
 impl Rom {
-    pub fn new(path: String) -> Self {
+    pub fn new(path: String) -> Result<Self, EmuError> {
         let path = Path::new(&path);
+        let data = fs::read(path)?;
 
-        match fs::read(path) {
-            Ok(data) => Rom { data },
-            Err(e) => panic!("Error in reading ROM {:?}", e),
-        }
+        Ok(Rom { data })
     }
 
     pub fn stat(&self) {
@@ -0,0 +1,175 @@
+use std::fmt;
+
+pub mod bess;
+
+/// Magic bytes at the start of every native save state
+pub const STATE_MAGIC: &[u8; 4] = b"GBST";
+pub const STATE_VERSION: u16 = 8;
+
+/// # Savestate
+/// Implemented by every component that holds machine state.
+///
+/// Components write their fields in a fixed order and read them back in
+/// the same order. The native format has no field tags, so any change to
+/// the order or to a field's size needs a bump of `STATE_VERSION`.
+pub trait Savestate {
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SavestateError>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SavestateError {
+    UnexpectedEof,
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    RomMismatch,
+    InvalidValue(&'static str),
+    Bess(String),
+}
+
+impl fmt::Display for SavestateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SavestateError::UnexpectedEof => write!(f, "save state ended unexpectedly"),
+            SavestateError::InvalidMagic => write!(f, "not a save state"),
+            SavestateError::UnsupportedVersion(v) => {
+                write!(f, "unsupported save state version {}", v)
+            }
+            SavestateError::RomMismatch => write!(f, "save state belongs to a different rom"),
+            SavestateError::InvalidValue(field) => write!(f, "invalid value for {}", field),
+            SavestateError::Bess(msg) => write!(f, "invalid BESS data: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for SavestateError {}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn write_u8(&mut self, byte: u8) {
+        self.data.push(byte);
+    }
+
+    pub fn write_bool(&mut self, flag: bool) {
+        self.data.push(u8::from(flag));
+    }
+
+    pub fn write_u16(&mut self, word: u16) {
+        self.data.extend_from_slice(&word.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data, pos: 0 }
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, SavestateError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, SavestateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, SavestateError> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, SavestateError> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.read_bytes(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, SavestateError> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.read_bytes(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], SavestateError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .ok_or(SavestateError::UnexpectedEof)?;
+        let bytes = self
+            .data
+            .get(self.pos..end)
+            .ok_or(SavestateError::UnexpectedEof)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub fn read_into(&mut self, buf: &mut [u8]) -> Result<(), SavestateError> {
+        buf.copy_from_slice(self.read_bytes(buf.len())?);
+        Ok(())
+    }
+
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SavestateError, StateReader, StateWriter};
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.write_u8(0xAB);
+        writer.write_bool(true);
+        writer.write_u16(0x1234);
+        writer.write_u64(0xDEAD_BEEF_0102_0304);
+        writer.write_bytes(&[1, 2, 3]);
+
+        let data = writer.into_bytes();
+        let mut reader = StateReader::new(&data);
+
+        assert_eq!(reader.read_u8(), Ok(0xAB));
+        assert_eq!(reader.read_bool(), Ok(true));
+        assert_eq!(reader.read_u16(), Ok(0x1234));
+        assert_eq!(reader.read_u64(), Ok(0xDEAD_BEEF_0102_0304));
+        assert_eq!(reader.read_bytes(3), Ok(&[1, 2, 3][..]));
+        assert_eq!(reader.read_u8(), Err(SavestateError::UnexpectedEof));
+    }
+}
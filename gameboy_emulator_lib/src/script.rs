@@ -0,0 +1,344 @@
+use std::{cell::RefCell, fmt, path::Path, rc::Rc};
+
+use rhai::{CallFnOptions, Engine, EvalAltResult, Scope, AST};
+
+use crate::{
+    bus::{Bus, Memory},
+    emu::EmuContext,
+    io::joypad::JoypadInput,
+    savestate::SavestateError,
+};
+
+/// # Scripts
+/// Runs a [Rhai](https://rhai.rs) script next to the emulator.
+///
+/// Top level statements run once when the script is loaded. After every
+/// frame the script's `on_frame(frame)` function is called, if it has one.
+///
+/// Functions available to scripts
+/// - `peek(address)`, `peek16(address)` read memory, `poke(address, byte)` writes it
+/// - `press(button)`, `release(button)`, `release_all()` hold buttons for the
+///   following frames. Buttons are `"up"`, `"down"`, `"left"`, `"right"`,
+///   `"a"`, `"b"`, `"select"` and `"start"`
+/// - `save_state(path)`, `load_state(path)`
+/// - `screenshot(path)`
+/// - `text(x, y, message)` shows a line of text until the next frame
+///
+/// Savestates and screenshots are taken once the callback returns, so a state
+/// loaded from a script is only visible to `peek` in the next callback.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    shared: Rc<RefCell<Shared>>,
+    has_on_frame: bool,
+    frame: i64,
+    screenshots: Vec<String>,
+}
+
+/// A line of text drawn over the screen, in screen pixels
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextLine {
+    pub x: i64,
+    pub y: i64,
+    pub text: String,
+}
+
+#[derive(Debug)]
+pub enum ScriptError {
+    Compile(String),
+    Runtime(String),
+    Io(std::io::Error),
+    State(SavestateError),
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptError::Compile(e) => write!(f, "script does not compile: {}", e),
+            ScriptError::Runtime(e) => write!(f, "script failed: {}", e),
+            ScriptError::Io(e) => write!(f, "{}", e),
+            ScriptError::State(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+impl From<Box<EvalAltResult>> for ScriptError {
+    fn from(e: Box<EvalAltResult>) -> Self {
+        ScriptError::Runtime(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for ScriptError {
+    fn from(e: std::io::Error) -> Self {
+        ScriptError::Io(e)
+    }
+}
+
+impl From<SavestateError> for ScriptError {
+    fn from(e: SavestateError) -> Self {
+        ScriptError::State(e)
+    }
+}
+
+enum Request {
+    SaveState(String),
+    LoadState(String),
+    Screenshot(String),
+}
+
+/// State the registered functions share with `Script`
+#[derive(Default)]
+struct Shared {
+    /// Only set while script code runs
+    bus: Option<Rc<RefCell<Bus>>>,
+    buttons: u8,
+    text: Vec<TextLine>,
+    requests: Vec<Request>,
+}
+
+impl Shared {
+    fn bus(&self) -> &Rc<RefCell<Bus>> {
+        self.bus.as_ref().expect("bus is set while a script runs")
+    }
+}
+
+impl Script {
+    /// Compiles the script and runs its top level statements
+    pub fn load(path: &Path, ctx: &mut EmuContext) -> Result<Self, ScriptError> {
+        let shared = Rc::new(RefCell::new(Shared::default()));
+        let engine = create_engine(&shared);
+
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|e| ScriptError::Compile(e.to_string()))?;
+
+        let has_on_frame = ast
+            .iter_functions()
+            .any(|f| f.name == "on_frame" && f.params.len() == 1);
+
+        let mut script = Script {
+            engine,
+            ast,
+            scope: Scope::new(),
+            shared,
+            has_on_frame,
+            frame: 0,
+            screenshots: vec![],
+        };
+
+        script.run(ctx, |script| {
+            script
+                .engine
+                .run_ast_with_scope(&mut script.scope, &script.ast)
+        })?;
+
+        Ok(script)
+    }
+
+    /// Calls `on_frame`, meant to run after every emulated frame
+    pub fn on_frame(&mut self, ctx: &mut EmuContext) -> Result<(), ScriptError> {
+        self.frame += 1;
+        self.shared.borrow_mut().text.clear();
+
+        if !self.has_on_frame {
+            return Ok(());
+        }
+
+        let frame = self.frame;
+        self.run(ctx, |script| {
+            // the top level statements only run once, on load
+            let options = CallFnOptions::new().eval_ast(false);
+            script.engine.call_fn_with_options::<()>(
+                options,
+                &mut script.scope,
+                &script.ast,
+                "on_frame",
+                (frame,),
+            )
+        })
+    }
+
+    /// Buttons held down by the script, as a joypad button mask
+    pub fn buttons(&self) -> u8 {
+        self.shared.borrow().buttons
+    }
+
+    /// Text the script drew during the last frame
+    pub fn text(&self) -> Vec<TextLine> {
+        self.shared.borrow().text.clone()
+    }
+
+    /// Paths the script asked to save screenshots to.
+    /// Writing images is up to the frontend.
+    pub fn take_screenshots(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.screenshots)
+    }
+
+    fn run<F>(&mut self, ctx: &mut EmuContext, f: F) -> Result<(), ScriptError>
+    where
+        F: FnOnce(&mut Script) -> Result<(), Box<EvalAltResult>>,
+    {
+        self.shared.borrow_mut().bus = Some(ctx.bus.clone());
+        let result = f(self);
+        self.shared.borrow_mut().bus = None;
+
+        // requests made before a failure still go through
+        let requests = std::mem::take(&mut self.shared.borrow_mut().requests);
+        for request in requests {
+            match request {
+                Request::SaveState(path) => std::fs::write(path, ctx.save_state())?,
+                Request::LoadState(path) => ctx.load_state(&std::fs::read(path)?)?,
+                Request::Screenshot(path) => self.screenshots.push(path),
+            }
+        }
+
+        Ok(result?)
+    }
+}
+
+fn create_engine(shared: &Rc<RefCell<Shared>>) -> Engine {
+    let mut engine = Engine::new();
+
+    let state = shared.clone();
+    engine.register_fn("peek", move |address: i64| -> i64 {
+        state.borrow().bus().borrow().read(address as u16) as i64
+    });
+
+    let state = shared.clone();
+    engine.register_fn("peek16", move |address: i64| -> i64 {
+        let state = state.borrow();
+        let bus = state.bus().borrow();
+        let low = bus.read(address as u16) as i64;
+        let high = bus.read((address as u16).wrapping_add(1)) as i64;
+        (high << 8) | low
+    });
+
+    let state = shared.clone();
+    engine.register_fn("poke", move |address: i64, byte: i64| {
+        state
+            .borrow()
+            .bus()
+            .borrow_mut()
+            .write(address as u16, byte as u8);
+    });
+
+    let state = shared.clone();
+    engine.register_fn(
+        "press",
+        move |button: &str| -> Result<(), Box<EvalAltResult>> {
+            state.borrow_mut().buttons |= 1 << parse_button(button)? as u8;
+            Ok(())
+        },
+    );
+
+    let state = shared.clone();
+    engine.register_fn(
+        "release",
+        move |button: &str| -> Result<(), Box<EvalAltResult>> {
+            state.borrow_mut().buttons &= !(1 << parse_button(button)? as u8);
+            Ok(())
+        },
+    );
+
+    let state = shared.clone();
+    engine.register_fn("release_all", move || state.borrow_mut().buttons = 0);
+
+    let state = shared.clone();
+    engine.register_fn("save_state", move |path: &str| {
+        let request = Request::SaveState(path.to_string());
+        state.borrow_mut().requests.push(request);
+    });
+
+    let state = shared.clone();
+    engine.register_fn("load_state", move |path: &str| {
+        let request = Request::LoadState(path.to_string());
+        state.borrow_mut().requests.push(request);
+    });
+
+    let state = shared.clone();
+    engine.register_fn("screenshot", move |path: &str| {
+        let request = Request::Screenshot(path.to_string());
+        state.borrow_mut().requests.push(request);
+    });
+
+    let state = shared.clone();
+    engine.register_fn("text", move |x: i64, y: i64, text: &str| {
+        state.borrow_mut().text.push(TextLine {
+            x,
+            y,
+            text: text.to_string(),
+        });
+    });
+
+    engine
+}
+
+fn parse_button(name: &str) -> Result<JoypadInput, Box<EvalAltResult>> {
+    match name.to_lowercase().as_str() {
+        "right" => Ok(JoypadInput::Right),
+        "left" => Ok(JoypadInput::Left),
+        "up" => Ok(JoypadInput::Up),
+        "down" => Ok(JoypadInput::Down),
+        "a" => Ok(JoypadInput::A),
+        "b" => Ok(JoypadInput::B),
+        "select" => Ok(JoypadInput::Select),
+        "start" => Ok(JoypadInput::Start),
+        _ => Err(format!("unknown button {:?}", name).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Script, TextLine};
+    use crate::{
+        bus::Memory, cartridge::Cartridge, emu::EmuContext, io::joypad::JoypadInput, utils::Opts,
+    };
+
+    #[test]
+    fn test_script_callbacks() {
+        let path = std::env::temp_dir().join("gbemu_test_script_callbacks.rhai");
+        std::fs::write(
+            &path,
+            r#"
+            poke(0xC000, 0x41);
+            press("start");
+
+            fn on_frame(frame) {
+                poke(0xC001, peek(0xC000) + frame);
+                if frame == 2 {
+                    release("start");
+                    press("a");
+                }
+                text(1, 2, `frame ${frame}`);
+            }
+            "#,
+        )
+        .unwrap();
+
+        let mut ctx = EmuContext::new(
+            Cartridge::new(vec![0; 0x8000]).unwrap(),
+            Opts::new(false, false),
+        );
+        let mut script = Script::load(&path, &mut ctx).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(script.buttons(), 1 << JoypadInput::Start as u8);
+
+        script.on_frame(&mut ctx).unwrap();
+        script.on_frame(&mut ctx).unwrap();
+
+        assert_eq!(ctx.bus.borrow().read(0xC001), 0x43);
+        assert_eq!(script.buttons(), 1 << JoypadInput::A as u8);
+        assert_eq!(
+            script.text(),
+            vec![TextLine {
+                x: 1,
+                y: 2,
+                text: "frame 2".to_string()
+            }]
+        );
+    }
+}
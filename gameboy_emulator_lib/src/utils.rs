@@ -1,3 +1,5 @@
+use crate::bus::ram_init::RamInit;
+
 /// CPU Freq / 60
 /// This many CPU cycles need to occur before a frame gets sent for rendering
 pub const CYCLES_1_FRAME: u64 = 70224;
@@ -5,6 +7,8 @@ pub const CYCLES_1_FRAME: u64 = 70224;
 pub struct Opts {
     pub show_debug_info: bool,
     pub show_serial_output: bool,
+    /// Power-on contents of WRAM, HRAM, VRAM and OAM
+    pub ram_init: RamInit,
 }
 
 impl Opts {
@@ -12,6 +16,7 @@ impl Opts {
         Opts {
             show_debug_info: debug,
             show_serial_output: serial,
+            ram_init: RamInit::Zero,
         }
     }
 }
@@ -80,6 +85,21 @@ pub fn rotate_right_helper(byte: u8, prev_carry: bool, through_carry: bool) -> (
     }
 }
 
+/// CRC-32 ( IEEE ), as used by zip and png
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+
+    !crc
+}
+
 pub trait HalfCarryCheck {
     type Item;
 
@@ -145,7 +165,7 @@ impl BitPosSet for u8 {
 mod tests {
     use crate::utils::BitPosCheck;
 
-    use super::{bytes_to_word, word_to_bytes};
+    use super::{bytes_to_word, crc32, word_to_bytes};
 
     #[test]
     fn test_bytes_to_word() {
@@ -174,4 +194,10 @@ mod tests {
         assert!(n.is_bit_set(6));
         assert!(n.is_bit_set(7));
     }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
 }